@@ -1,44 +1,67 @@
-use crate::config::HeicSettings;
+use crate::config::{CacheSettings, HeicSettings};
 use aes_gcm::{
     aead::{Aead, KeyInit, OsRng},
     Aes256Gcm, Key, Nonce,
 };
-use anyhow::Result;
-use log::{debug, info};
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use parking_lot::Mutex;
 use rand::RngCore;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use std::io::Write;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
 use std::{fs, thread, time::Duration};
+use zeroize::Zeroizing;
 
 /// Cache file header to track encryption status and integrity
 #[derive(Debug)]
 struct CacheFileHeader {
     magic: [u8; 4],     // "FHIC" magic bytes
     version: u8,        // Header version (1)
-    encrypted: u8,      // 1 if encrypted, 0 if not
+    flags: u8,          // FLAG_ENCRYPTED | FLAG_PINNED, entries before pinning used 0/1
     quality: u8,        // HEIC quality setting when cached
     speed: u8,          // HEIC speed setting when cached
     chroma: u16,        // HEIC chroma setting when cached (big-endian)
-    reserved: [u8; 16], // Reserved for future use
+    source: u64,        // Source file fingerprint when converted, 0 if unknown (big-endian)
+    payload_len: u64,   // Plaintext payload length, 0 if unknown (big-endian)
     checksum: [u8; 32], // SHA256 checksum of payload
     nonce: [u8; 12],    // AES-GCM nonce (only used if encrypted)
 }
 
 const CACHE_FILE_MAGIC: [u8; 4] = *b"FHIC"; // FUSE HEIC Cache
+/// Still 1 since `flags`, `source` and `payload_len` took over bytes that were written
+/// as the encrypted bool and zeroed reserved space: older entries read as unpinned
+/// with an unknown source and length, which is handled like any unknown value
 const CACHE_FILE_VERSION: u8 = 1;
-const HEADER_SIZE: usize = 70; // 4+1+1+1+1+2+16+32+12
+const HEADER_SIZE: usize = 70; // 4+1+1+1+1+2+8+8+32+12
+/// Offset of `flags` in the header, rewritten in place when the pinned status changes
+const FLAGS_OFFSET: u64 = 5;
+const FLAG_ENCRYPTED: u8 = 1;
+/// Source matched `cache.pinned_patterns` when last written or read
+const FLAG_PINNED: u8 = 2;
+
+/// Sidecar file in the cache root holding the persisted savings counters
+const STATS_FILE_NAME: &str = "stats.yaml";
+
+/// Marker file in the cache root recording the `shard_depth` entries are stored with;
+/// caches created before it existed use a depth of 1
+const SHARD_DEPTH_FILE_NAME: &str = "shard_depth";
 
 impl CacheFileHeader {
     fn new_unencrypted(payload_checksum: [u8; 32], quality: u8, speed: u8, chroma: u16) -> Self {
         Self {
             magic: CACHE_FILE_MAGIC,
             version: CACHE_FILE_VERSION,
-            encrypted: 0,
+            flags: 0,
             quality,
             speed,
             chroma,
-            reserved: [0; 16],
+            source: 0,
+            payload_len: 0,
             checksum: payload_checksum,
             nonce: [0; 12],
         }
@@ -54,11 +77,12 @@ impl CacheFileHeader {
         Self {
             magic: CACHE_FILE_MAGIC,
             version: CACHE_FILE_VERSION,
-            encrypted: 1,
+            flags: FLAG_ENCRYPTED,
             quality,
             speed,
             chroma,
-            reserved: [0; 16],
+            source: 0,
+            payload_len: 0,
             checksum: payload_checksum,
             nonce,
         }
@@ -68,11 +92,12 @@ impl CacheFileHeader {
         let mut bytes = Vec::with_capacity(HEADER_SIZE);
         bytes.extend_from_slice(&self.magic);
         bytes.push(self.version);
-        bytes.push(self.encrypted);
+        bytes.push(self.flags);
         bytes.push(self.quality);
         bytes.push(self.speed);
         bytes.extend_from_slice(&self.chroma.to_be_bytes());
-        bytes.extend_from_slice(&self.reserved);
+        bytes.extend_from_slice(&self.source.to_be_bytes());
+        bytes.extend_from_slice(&self.payload_len.to_be_bytes());
         bytes.extend_from_slice(&self.checksum);
         bytes.extend_from_slice(&self.nonce);
         bytes
@@ -93,12 +118,16 @@ impl CacheFileHeader {
             return Err(anyhow::anyhow!("Unsupported version: {}", version));
         }
 
-        let encrypted = bytes[5];
+        let flags = bytes[5];
         let quality = bytes[6];
         let speed = bytes[7];
         let chroma = u16::from_be_bytes([bytes[8], bytes[9]]);
-        let mut reserved = [0u8; 16];
-        reserved.copy_from_slice(&bytes[10..26]);
+        let mut source = [0u8; 8];
+        source.copy_from_slice(&bytes[10..18]);
+        let source = u64::from_be_bytes(source);
+        let mut payload_len = [0u8; 8];
+        payload_len.copy_from_slice(&bytes[18..26]);
+        let payload_len = u64::from_be_bytes(payload_len);
         let mut checksum = [0u8; 32];
         checksum.copy_from_slice(&bytes[26..58]);
         let mut nonce = [0u8; 12];
@@ -107,73 +136,284 @@ impl CacheFileHeader {
         Ok(Self {
             magic,
             version,
-            encrypted,
+            flags,
             quality,
             speed,
             chroma,
-            reserved,
+            source,
+            payload_len,
             checksum,
             nonce,
         })
     }
 
     fn is_encrypted(&self) -> bool {
-        self.encrypted == 1
+        self.flags & FLAG_ENCRYPTED != 0
+    }
+
+    fn is_pinned(&self) -> bool {
+        self.flags & FLAG_PINNED != 0
     }
 
     fn matches_heic_settings(&self, quality: u8, speed: u8, chroma: u16) -> bool {
         self.quality == quality && self.speed == speed && self.chroma == chroma
     }
+
+    /// Entries written before fingerprints were recorded, or for sources that could not
+    /// be stat'ed, are accepted as they are
+    fn matches_source(&self, source_fingerprint: Option<u64>) -> bool {
+        source_matches(
+            (self.source != 0).then_some(self.source),
+            source_fingerprint,
+        )
+    }
+}
+
+/// Point-in-time copy of the cumulative savings counters
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CacheStatsSnapshot {
+    /// Total size of the source files whose results were cached
+    pub original_bytes: u64,
+    /// Total size of the cached results produced from those sources
+    pub converted_bytes: u64,
+}
+
+impl CacheStatsSnapshot {
+    /// Fraction of the original bytes saved by conversion (0.0 when nothing was cached yet)
+    pub fn savings_ratio(&self) -> f64 {
+        if self.original_bytes == 0 {
+            return 0.0;
+        }
+        1.0 - self.converted_bytes as f64 / self.original_bytes as f64
+    }
+
+    pub fn saved_bytes(&self) -> i64 {
+        self.original_bytes as i64 - self.converted_bytes as i64
+    }
+}
+
+/// Header of a disk cache entry, read without decrypting or loading the payload
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheEntryInfo {
+    pub path: PathBuf,
+    pub encrypted: bool,
+    /// Spared by the eviction sweep, see `cache.pinned_patterns`
+    pub pinned: bool,
+    pub quality: u8,
+    pub speed: u8,
+    pub chroma: u16,
+    /// Stored payload size; includes the 16 byte AES-GCM tag for encrypted entries
+    pub payload_size: u64,
+    /// SHA256 of the unencrypted payload
+    pub checksum: [u8; 32],
+}
+
+/// Cumulative bytes-saved counters, incremented on every cache `put`
+#[derive(Debug, Default)]
+struct CacheStats {
+    original_bytes: AtomicU64,
+    converted_bytes: AtomicU64,
+}
+
+impl CacheStats {
+    fn from_snapshot(snapshot: CacheStatsSnapshot) -> Self {
+        Self {
+            original_bytes: AtomicU64::new(snapshot.original_bytes),
+            converted_bytes: AtomicU64::new(snapshot.converted_bytes),
+        }
+    }
+
+    fn record(&self, original_size: u64, converted_size: u64) {
+        self.original_bytes
+            .fetch_add(original_size, Ordering::Relaxed);
+        self.converted_bytes
+            .fetch_add(converted_size, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> CacheStatsSnapshot {
+        CacheStatsSnapshot {
+            original_bytes: self.original_bytes.load(Ordering::Relaxed),
+            converted_bytes: self.converted_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Cached payload shared with readers without copying, its whole allocation is
+/// overwritten with zeros once the last reference is dropped
+pub type CachedData = Arc<Zeroizing<Vec<u8>>>;
+
+/// Cache entry held in RAM by `secure_memory_only` mode
+struct MemoryEntry {
+    data: CachedData,
+    last_access: u64,
+    source_fingerprint: Option<u64>,
+    /// Source matches `pinned_patterns`, never evicted
+    pinned: bool,
+}
+
+/// In-memory LRU store used instead of the disk cache in `secure_memory_only` mode
+#[derive(Default)]
+struct MemoryStore {
+    entries: std::collections::HashMap<String, MemoryEntry>,
+    used_bytes: u64,
+    access_clock: u64,
+}
+
+impl MemoryStore {
+    /// Evict least recently used entries until `needed` more bytes fit in `max_size`.
+    /// Evicted buffers are scrubbed once no reader holds them anymore.
+    fn ensure_space(&mut self, needed: u64, max_size: u64) -> bool {
+        if needed > max_size {
+            return false;
+        }
+
+        while self.used_bytes + needed > max_size {
+            let Some(oldest) = self
+                .entries
+                .iter()
+                .filter(|(_, entry)| !entry.pinned)
+                .min_by_key(|(_, entry)| entry.last_access)
+                .map(|(key, _)| key.clone())
+            else {
+                if !self.entries.is_empty() {
+                    warn!(
+                        "Pinned entries alone use {} of the {max_size} bytes of the memory cache, \
+                         raise cache.max_size_mb or pin fewer files",
+                        self.used_bytes
+                    );
+                }
+                break;
+            };
+            self.remove(&oldest);
+            debug!("Evicted from memory: {oldest}");
+        }
+        true
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.used_bytes -= entry.data.len() as u64;
+        }
+    }
 }
 
 pub struct ImageCache {
     max_size: u64,
     cache_dir: PathBuf,
+    /// Directory levels between the cache root and the entry files
+    shard_depth: usize,
     encryption_enabled: bool,
+    /// Random key used instead of the filepath-derived one, never written anywhere
+    session_key: Option<[u8; 32]>,
+    fsync: bool,
+    stats: CacheStats,
+    /// Counters changed since the stats sidecar file was last written
+    stats_dirty: AtomicBool,
+    /// Serializes writes of the stats sidecar file
+    stats_lock: Mutex<()>,
+    /// RAM-only storage replacing the disk cache in `secure_memory_only` mode
+    memory: Option<Mutex<MemoryStore>>,
+    pinned_patterns: Vec<Regex>,
+    /// Check the SHA-256 of every unencrypted payload read from disk against its header
+    verify_checksum: bool,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct CacheContext {
     pub filepath: String,
+    pub original_size: u64,
     pub heic_settings: HeicSettings,
+    /// Identity of the source file the entry is converted from, see `source_fingerprint`
+    pub source_fingerprint: Option<u64>,
 }
 
 impl CacheContext {
-    pub fn new(filepath: String, heic_settings: HeicSettings) -> Self {
+    pub fn new(filepath: String, original_size: u64, heic_settings: HeicSettings) -> Self {
         Self {
             filepath,
+            original_size,
             heic_settings,
+            source_fingerprint: None,
         }
     }
 }
 
 impl ImageCache {
-    pub fn new(
-        max_size_mb: u64,
-        cache_dir: PathBuf,
-        encryption_enabled: bool,
-    ) -> Result<Arc<Self>> {
-        info!("Initializing disk cache: max size {max_size_mb} MB, dir: {cache_dir:?}, encryption: {encryption_enabled}");
+    pub fn new(cache_dir: PathBuf, settings: &CacheSettings) -> Result<Arc<Self>> {
+        let max_size_mb = settings.max_size_mb;
+        let encryption_enabled = settings.enable_encryption;
+        let pinned_patterns = settings
+            .pinned_patterns
+            .iter()
+            .map(|pattern| {
+                Regex::new(pattern)
+                    .with_context(|| format!("Invalid cache.pinned_patterns entry {pattern:?}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        if settings.secure_memory_only {
+            info!("Initializing memory-only cache: max size {max_size_mb} MB, nothing is written to disk");
+            return Ok(Arc::new(Self {
+                max_size: max_size_mb * 1024 * 1024,
+                cache_dir,
+                shard_depth: settings.shard_depth,
+                encryption_enabled: false,
+                session_key: None,
+                fsync: false,
+                stats: CacheStats::default(),
+                stats_dirty: AtomicBool::new(false),
+                stats_lock: Mutex::new(()),
+                memory: Some(Mutex::new(MemoryStore::default())),
+                pinned_patterns,
+                verify_checksum: false,
+            }));
+        }
+
+        info!("Initializing disk cache: max size {max_size_mb} MB, dir: {cache_dir:?}, encryption: {encryption_enabled}, fsync: {}", settings.fsync);
 
         fs::create_dir_all(&cache_dir)?;
+        Self::prepare_shard_layout(&cache_dir, settings.shard_depth)?;
+
+        let stats = Self::load_stats(&cache_dir).unwrap_or_else(|e| {
+            log::warn!("Failed to load cache stats, starting from zero: {e}");
+            CacheStatsSnapshot::default()
+        });
 
         let cache = Arc::new(Self {
             max_size: max_size_mb * 1024 * 1024,
             cache_dir,
+            shard_depth: settings.shard_depth,
             encryption_enabled,
+            session_key: settings.session_key.then(|| {
+                let mut key = [0u8; 32];
+                OsRng.fill_bytes(&mut key);
+                key
+            }),
+            fsync: settings.fsync,
+            stats: CacheStats::from_snapshot(stats),
+            stats_dirty: AtomicBool::new(false),
+            stats_lock: Mutex::new(()),
+            memory: None,
+            pinned_patterns,
+            verify_checksum: settings.verify_checksum_on_read,
         });
 
-        // Start background cleanup thread
-        let cache_clone = Arc::clone(&cache);
+        // Start background cleanup thread, it must not keep the cache alive so that
+        // dropping the cache persists the stats
+        let cache_weak = Arc::downgrade(&cache);
         thread::spawn(move || {
-            cache_clone.cleanup_worker();
+            Self::cleanup_worker(cache_weak);
         });
 
         Ok(cache)
     }
 
-    /// Generate encryption key from filepath using SHA256
+    /// Generate encryption key from filepath using SHA256, or use the session key
     fn generate_encryption_key(&self, filepath: &str) -> [u8; 32] {
+        if let Some(session_key) = self.session_key {
+            return session_key;
+        }
+
         let mut hasher = Sha256::new();
         hasher.update(filepath.as_bytes());
         hasher.update(b"fuse-img2heic-encryption-key");
@@ -218,16 +458,63 @@ impl ImageCache {
         Ok(plaintext)
     }
 
-    pub fn get_with_context(&self, key: &str, context: &CacheContext) -> Option<Vec<u8>> {
-        self.get(key, &context.filepath, &context.heic_settings)
+    /// Whether a source path matches `cache.pinned_patterns`
+    pub fn is_pinned_path(&self, filepath: &str) -> bool {
+        self.pinned_patterns
+            .iter()
+            .any(|pattern| pattern.is_match(filepath))
     }
 
-    pub fn get(&self, key: &str, filepath: &str, heic_settings: &HeicSettings) -> Option<Vec<u8>> {
+    /// Bring the pinned flag of a disk entry in line with the current `pinned_patterns`,
+    /// so the sweep spares it across restarts and stops sparing it once unpinned
+    ///
+    /// Unlike every other write this skips the temporary file and rename: it rewrites
+    /// one byte that no checksum covers, which either lands or does not, and a stale
+    /// flag only means one more or one fewer entry spared until the next read.
+    fn refresh_pinned_flag(&self, header: &CacheFileHeader, file_path: &Path, filepath: &str) {
+        use std::os::unix::fs::FileExt;
+
+        let pinned = self.is_pinned_path(filepath);
+        if header.is_pinned() == pinned {
+            return;
+        }
+        let flags = if pinned {
+            header.flags | FLAG_PINNED
+        } else {
+            header.flags & !FLAG_PINNED
+        };
+        let result = fs::File::options()
+            .write(true)
+            .open(file_path)
+            .and_then(|file| file.write_all_at(&[flags], FLAGS_OFFSET));
+        if let Err(e) = result {
+            debug!("Failed to update pinned flag of {file_path:?}: {e}");
+        }
+    }
+
+    pub fn get_with_context(&self, key: &str, context: &CacheContext) -> Option<CachedData> {
+        if let Some(memory) = &self.memory {
+            let mut store = memory.lock();
+            store.access_clock += 1;
+            let access = store.access_clock;
+            let entry = store.entries.get_mut(key)?;
+            if !source_matches(entry.source_fingerprint, context.source_fingerprint) {
+                debug!(
+                    "Source of {} changed, dropping cached entry",
+                    context.filepath
+                );
+                store.remove(key);
+                return None;
+            }
+            entry.last_access = access;
+            return Some(Arc::clone(&entry.data));
+        }
+
         // Read from disk cache (Linux page cache handles hot data)
-        match self.load_from_disk_key(key, filepath, heic_settings) {
+        match self.load_from_disk_key(key, context) {
             Ok(data) => {
                 log::trace!("Cache hit: {key}");
-                Some(data)
+                Some(Arc::new(Zeroizing::new(data)))
             }
             Err(_) => {
                 log::trace!("Cache miss: {key}");
@@ -236,30 +523,248 @@ impl ImageCache {
         }
     }
 
+    /// SHA-256 of a cached payload, taken from the entry header without loading the payload
+    pub fn get_checksum_with_context(&self, key: &str, context: &CacheContext) -> Option<[u8; 32]> {
+        if let Some(memory) = &self.memory {
+            let store = memory.lock();
+            return store
+                .entries
+                .get(key)
+                .filter(|entry| {
+                    source_matches(entry.source_fingerprint, context.source_fingerprint)
+                })
+                .map(|entry| Sha256::digest(entry.data.as_slice()).into());
+        }
+
+        // Entries of an earlier session still have a valid header but will be converted
+        // again, so only trust entries that decrypt with this session's key
+        if self.session_key.is_some() {
+            return self
+                .get_with_context(key, context)
+                .map(|data| Sha256::digest(data.as_slice()).into());
+        }
+
+        self.load_header_from_disk_key(key, context)
+            .ok()
+            .map(|header| header.checksum)
+    }
+
+    /// Size of a cached payload, taken from the entry header when it records it
+    pub fn get_size_with_context(&self, key: &str, context: &CacheContext) -> Option<u64> {
+        if let Some(memory) = &self.memory {
+            let store = memory.lock();
+            return store
+                .entries
+                .get(key)
+                .filter(|entry| {
+                    source_matches(entry.source_fingerprint, context.source_fingerprint)
+                })
+                .map(|entry| entry.data.len() as u64);
+        }
+
+        // Same as for the checksum, only trust entries this session can decrypt
+        if self.session_key.is_none() {
+            match self.load_header_from_disk_key(key, context) {
+                Ok(header) if header.payload_len != 0 => return Some(header.payload_len),
+                Ok(_) => {} // Written before the length was recorded
+                Err(_) => return None,
+            }
+        }
+        self.get_with_context(key, context)
+            .map(|data| data.len() as u64)
+    }
+
     pub fn put_with_context(
         &self,
         key: String,
         data: Vec<u8>,
         context: &CacheContext,
     ) -> Result<()> {
-        self.put(key, data, &context.filepath, &context.heic_settings)
+        log::trace!("Caching entry: {key} ({} bytes)", data.len());
+        if let Some(memory) = &self.memory {
+            let pinned = self.is_pinned_path(&context.filepath);
+            // Stats stay in memory too, persisting them would leave a trace on disk
+            self.stats.record(context.original_size, data.len() as u64);
+            self.put_in_memory(memory, key, data, context.source_fingerprint, pinned);
+            return Ok(());
+        }
+
+        self.save_to_disk_key(&key, &data, context)?;
+
+        // Persisted in batches by the cleanup worker and on drop, not on every put
+        self.stats.record(context.original_size, data.len() as u64);
+        self.stats_dirty.store(true, Ordering::Relaxed);
+        Ok(())
     }
 
-    pub fn put(
+    fn put_in_memory(
         &self,
+        memory: &Mutex<MemoryStore>,
         key: String,
         data: Vec<u8>,
-        filepath: &str,
-        heic_settings: &HeicSettings,
-    ) -> Result<()> {
-        log::trace!("Caching entry: {key} ({} bytes)", data.len());
-        self.save_to_disk_key(&key, &data, filepath, heic_settings)
+        source_fingerprint: Option<u64>,
+        pinned: bool,
+    ) {
+        // Wrap first so the data is scrubbed even if it does not fit
+        let data = Arc::new(Zeroizing::new(data));
+        let size = data.len() as u64;
+
+        let mut store = memory.lock();
+        store.remove(&key);
+        if !store.ensure_space(size, self.max_size) {
+            debug!("Entry {key} ({size} bytes) exceeds the memory cache size, not caching");
+            return;
+        }
+
+        store.access_clock += 1;
+        let last_access = store.access_clock;
+        store.used_bytes += size;
+        store.entries.insert(
+            key,
+            MemoryEntry {
+                data,
+                last_access,
+                source_fingerprint,
+                pinned,
+            },
+        );
+    }
+
+    /// Current cumulative savings counters
+    pub fn stats(&self) -> CacheStatsSnapshot {
+        self.stats.snapshot()
     }
 
-    fn cleanup_worker(&self) {
+    /// Read the persisted savings counters from a cache directory without starting a cache
+    pub fn load_stats(cache_dir: &Path) -> Result<CacheStatsSnapshot> {
+        let stats_path = cache_dir.join(STATS_FILE_NAME);
+        if !stats_path.exists() {
+            return Ok(CacheStatsSnapshot::default());
+        }
+
+        let content = fs::read_to_string(&stats_path)
+            .with_context(|| format!("Failed to read cache stats: {stats_path:?}"))?;
+        serde_yaml::from_str(&content)
+            .with_context(|| format!("Failed to parse cache stats: {stats_path:?}"))
+    }
+
+    /// Read the header of the disk entry for `key`, None if there is no such entry
+    pub fn entry_info(cache_dir: &Path, key: &str) -> Result<Option<CacheEntryInfo>> {
+        use std::io::Read;
+
+        if key.len() != 64 || !key.bytes().all(|b| b.is_ascii_hexdigit()) {
+            anyhow::bail!("Invalid cache key {key:?}: expected 64 hex characters");
+        }
+
+        let shard_depth = read_shard_depth(cache_dir)?.unwrap_or(1);
+        let path = get_cache_file_path(cache_dir, key, shard_depth);
+        let mut file = match fs::File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Failed to open {path:?}")),
+        };
+        let file_size = file.metadata()?.len();
+
+        let mut header_bytes = [0u8; HEADER_SIZE];
+        file.read_exact(&mut header_bytes)
+            .with_context(|| format!("Cache entry {path:?} is too small"))?;
+        let header = CacheFileHeader::from_bytes(&header_bytes)
+            .with_context(|| format!("Invalid cache entry header in {path:?}"))?;
+
+        Ok(Some(CacheEntryInfo {
+            path,
+            encrypted: header.is_encrypted(),
+            pinned: header.is_pinned(),
+            quality: header.quality,
+            speed: header.speed,
+            chroma: header.chroma,
+            payload_size: file_size - HEADER_SIZE as u64,
+            checksum: header.checksum,
+        }))
+    }
+
+    /// Move existing entries over if the cache was written with another shard depth,
+    /// then record the configured depth
+    fn prepare_shard_layout(cache_dir: &Path, shard_depth: usize) -> Result<()> {
+        let marker_path = cache_dir.join(SHARD_DEPTH_FILE_NAME);
+        let current_depth = read_shard_depth(cache_dir)?;
+        if current_depth == Some(shard_depth) {
+            return Ok(());
+        }
+
+        let current_depth = current_depth.unwrap_or(1);
+        if current_depth != shard_depth {
+            info!(
+                "Cache shard depth changed from {current_depth} to {shard_depth}, moving entries"
+            );
+            let mut moved = 0;
+            for entry in cache_entry_files(cache_dir, current_depth) {
+                let key: String = entry
+                    .strip_prefix(cache_dir)?
+                    .iter()
+                    .map(|part| part.to_string_lossy())
+                    .collect();
+                if key.len() != 64 || !key.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    continue; // Leftover temporary file of an interrupted write
+                }
+                let new_path = get_cache_file_path(cache_dir, &key, shard_depth);
+                if let Some(parent) = new_path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::rename(&entry, &new_path)
+                    .with_context(|| format!("Failed to move cache entry {entry:?}"))?;
+                moved += 1;
+            }
+
+            // Drop the shard directories left empty, deepest first
+            for dir in walkdir::WalkDir::new(cache_dir)
+                .min_depth(1)
+                .contents_first(true)
+                .into_iter()
+                .flatten()
+                .filter(|entry| entry.file_type().is_dir())
+            {
+                let _ = fs::remove_dir(dir.path());
+            }
+            info!("Moved {moved} cache entries");
+        }
+
+        // Marker last, so an interrupted move is resumed from the old layout
+        write_file_atomically(&marker_path, format!("{shard_depth}\n").as_bytes(), true)
+    }
+
+    /// Write the stats sidecar file if the counters changed since it was last written
+    pub fn flush_stats(&self) {
+        if !self.stats_dirty.swap(false, Ordering::Relaxed) {
+            return;
+        }
+        if let Err(e) = self.save_stats() {
+            self.stats_dirty.store(true, Ordering::Relaxed);
+            debug!("Failed to persist cache stats: {e}");
+        }
+    }
+
+    fn save_stats(&self) -> Result<()> {
+        let _guard = self.stats_lock.lock();
+
+        let content = serde_yaml::to_string(&self.stats.snapshot())?;
+        let stats_path = self.cache_dir.join(STATS_FILE_NAME);
+        let tmp_path = self.cache_dir.join(format!("{STATS_FILE_NAME}.tmp"));
+
+        // Write then rename so a crash never leaves a truncated stats file behind
+        fs::write(&tmp_path, content)?;
+        fs::rename(tmp_path, stats_path)?;
+        Ok(())
+    }
+
+    fn cleanup_worker(cache: Weak<Self>) {
         loop {
             thread::sleep(Duration::from_secs(300)); // Run every 5 minutes
-            self.enforce_disk_limit();
+            let Some(cache) = cache.upgrade() else {
+                break;
+            };
+            cache.enforce_disk_limit();
+            cache.flush_stats();
         }
     }
 
@@ -268,24 +773,12 @@ impl ImageCache {
         let mut files: Vec<(PathBuf, u64, std::time::SystemTime)> = Vec::new();
         let mut total_size: u64 = 0;
 
-        if let Ok(subdirs) = fs::read_dir(&self.cache_dir) {
-            for subdir in subdirs.flatten() {
-                if !subdir.path().is_dir() {
-                    continue;
-                }
-                if let Ok(entries) = fs::read_dir(subdir.path()) {
-                    for entry in entries.flatten() {
-                        let path = entry.path();
-                        if let Ok(meta) = path.metadata() {
-                            if meta.is_file() {
-                                let size = meta.len();
-                                let atime = meta.accessed().unwrap_or(std::time::UNIX_EPOCH);
-                                files.push((path, size, atime));
-                                total_size += size;
-                            }
-                        }
-                    }
-                }
+        for path in cache_entry_files(&self.cache_dir, self.shard_depth) {
+            if let Ok(meta) = path.metadata() {
+                let size = meta.len();
+                let atime = meta.accessed().unwrap_or(std::time::UNIX_EPOCH);
+                files.push((path, size, atime));
+                total_size += size;
             }
         }
 
@@ -293,7 +786,21 @@ impl ImageCache {
             return;
         }
 
-        debug!("Cache cleanup: {} bytes used, {} max", total_size, self.max_size);
+        debug!(
+            "Cache cleanup: {} bytes used, {} max",
+            total_size, self.max_size
+        );
+
+        // Pinned entries count towards the size but are never picked for eviction
+        files.retain(|(path, _, _)| !entry_is_pinned(path));
+        let pinned_size = total_size - files.iter().map(|(_, size, _)| size).sum::<u64>();
+        if pinned_size > self.max_size {
+            warn!(
+                "Pinned entries alone use {pinned_size} of the {} bytes of the disk cache, \
+                 raise cache.max_size_mb or pin fewer files",
+                self.max_size
+            );
+        }
 
         // Sort by atime (oldest first)
         files.sort_by_key(|(_, _, atime)| *atime);
@@ -310,14 +817,9 @@ impl ImageCache {
         }
     }
 
-    fn save_to_disk_key(
-        &self,
-        key: &str,
-        data: &[u8],
-        filepath: &str,
-        heic_settings: &HeicSettings,
-    ) -> Result<()> {
-        let file_path = get_cache_file_path(&self.cache_dir, key);
+    fn save_to_disk_key(&self, key: &str, data: &[u8], context: &CacheContext) -> Result<()> {
+        let heic_settings = &context.heic_settings;
+        let file_path = get_cache_file_path(&self.cache_dir, key, self.shard_depth);
 
         // Create subdirectory if it doesn't exist
         if let Some(parent) = file_path.parent() {
@@ -329,9 +831,9 @@ impl ImageCache {
         hasher.update(data);
         let payload_checksum: [u8; 32] = hasher.finalize().into();
 
-        let (final_data, header) = if self.encryption_enabled {
+        let (final_data, mut header) = if self.encryption_enabled {
             // Encrypt the data
-            let (encrypted_data, nonce) = self.encrypt_data(data, filepath)?;
+            let (encrypted_data, nonce) = self.encrypt_data(data, &context.filepath)?;
             let header = CacheFileHeader::new_encrypted(
                 payload_checksum,
                 nonce,
@@ -349,23 +851,58 @@ impl ImageCache {
             );
             (data.to_vec(), header)
         };
+        header.source = context.source_fingerprint.unwrap_or(0);
+        header.payload_len = data.len() as u64;
+        if self.is_pinned_path(&context.filepath) {
+            header.flags |= FLAG_PINNED;
+        }
 
         // Write header + data to file
         let mut file_content = header.to_bytes();
         file_content.extend_from_slice(&final_data);
 
-        fs::write(file_path, file_content)?;
-        Ok(())
+        // Always go through a temporary file so concurrent writers of the same key (e.g.
+        // prefetch racing an on-demand read) never expose a truncated entry to readers
+        write_file_atomically(&file_path, &file_content, self.fsync)
     }
 
-    fn load_from_disk_key(
+    fn load_header_from_disk_key(
         &self,
         key: &str,
-        filepath: &str,
-        heic_settings: &HeicSettings,
-    ) -> Result<Vec<u8>> {
-        let file_path = get_cache_file_path(&self.cache_dir, key);
-        let file_content = fs::read(file_path)?;
+        context: &CacheContext,
+    ) -> Result<CacheFileHeader> {
+        use std::io::Read;
+
+        let heic_settings = &context.heic_settings;
+        let file_path = get_cache_file_path(&self.cache_dir, key, self.shard_depth);
+        let mut header_bytes = [0u8; HEADER_SIZE];
+        fs::File::open(&file_path)?.read_exact(&mut header_bytes)?;
+        let header = CacheFileHeader::from_bytes(&header_bytes)?;
+
+        // Same checks as a full load, so the checksum is only reported for usable entries
+        self.check_source(&header, &file_path, context)?;
+        if !header.matches_heic_settings(
+            heic_settings.quality,
+            heic_settings.speed,
+            heic_settings.chroma,
+        ) {
+            return Err(anyhow::anyhow!(
+                "HEIC settings mismatch, cache entry invalid"
+            ));
+        }
+        if header.is_encrypted() && !self.encryption_enabled {
+            return Err(anyhow::anyhow!(
+                "Cache file is encrypted but encryption is disabled"
+            ));
+        }
+        self.refresh_pinned_flag(&header, &file_path, &context.filepath);
+        Ok(header)
+    }
+
+    fn load_from_disk_key(&self, key: &str, context: &CacheContext) -> Result<Vec<u8>> {
+        let heic_settings = &context.heic_settings;
+        let file_path = get_cache_file_path(&self.cache_dir, key, self.shard_depth);
+        let file_content = fs::read(&file_path)?;
 
         if file_content.len() < HEADER_SIZE {
             return Err(anyhow::anyhow!("Cache file too small"));
@@ -373,6 +910,7 @@ impl ImageCache {
 
         // Parse header
         let header = CacheFileHeader::from_bytes(&file_content[..HEADER_SIZE])?;
+        self.check_source(&header, &file_path, context)?;
 
         // Validate HEIC settings match
         if !header.matches_heic_settings(
@@ -387,20 +925,96 @@ impl ImageCache {
 
         let payload = &file_content[HEADER_SIZE..];
 
-        // AES-GCM provides authenticated encryption (integrity check on decrypt)
-        // For unencrypted, we trust the filesystem
-        if header.is_encrypted() {
+        // AES-GCM also authenticates the payload as it decrypts it
+        let data = if header.is_encrypted() {
             if !self.encryption_enabled {
                 return Err(anyhow::anyhow!(
                     "Cache file is encrypted but encryption is disabled"
                 ));
             }
-            self.decrypt_data(payload, &header.nonce, filepath)
+            self.decrypt_data(payload, &header.nonce, &context.filepath)?
         } else {
-            Ok(payload.to_vec())
+            payload.to_vec()
+        };
+
+        // The length check is free and catches truncated entries even without hashing.
+        // Decryption already authenticated encrypted payloads, only plain ones are hashed.
+        let corruption = if header.payload_len != 0 && header.payload_len != data.len() as u64 {
+            Some(format!(
+                "{} bytes instead of {}",
+                data.len(),
+                header.payload_len
+            ))
+        } else if self.verify_checksum
+            && !header.is_encrypted()
+            && Sha256::digest(&data)[..] != header.checksum
+        {
+            Some("checksum mismatch".to_string())
+        } else {
+            None
+        };
+        if let Some(corruption) = corruption {
+            warn!("Corrupted cache entry {file_path:?} ({corruption}), removing it");
+            if let Err(e) = fs::remove_file(&file_path) {
+                debug!("Failed to remove corrupted cache entry {file_path:?}: {e}");
+            }
+            anyhow::bail!("Corrupted cache entry: {corruption}");
         }
+        self.refresh_pinned_flag(&header, &file_path, &context.filepath);
+        Ok(data)
     }
 
+    /// Fail, and remove the entry, if it was converted from a different file than the
+    /// one now at the source path (replaced by rename, or rewritten with the same size)
+    fn check_source(
+        &self,
+        header: &CacheFileHeader,
+        file_path: &Path,
+        context: &CacheContext,
+    ) -> Result<()> {
+        if header.matches_source(context.source_fingerprint) {
+            return Ok(());
+        }
+        debug!(
+            "Source of {} changed, removing cached entry",
+            context.filepath
+        );
+        if let Err(e) = fs::remove_file(file_path) {
+            debug!("Failed to remove stale cache entry {file_path:?}: {e}");
+        }
+        Err(anyhow::anyhow!("Source file changed, cache entry invalid"))
+    }
+}
+
+impl Drop for ImageCache {
+    fn drop(&mut self) {
+        self.flush_stats();
+    }
+}
+
+/// False only if both fingerprints are known and differ
+fn source_matches(cached: Option<u64>, current: Option<u64>) -> bool {
+    match (cached, current) {
+        (Some(cached), Some(current)) => cached == current,
+        _ => true,
+    }
+}
+
+/// Identity of a source file: device, inode, modification time and size. Changes when
+/// the file is replaced (new inode) or rewritten, never 0 so that 0 can mean unknown
+pub fn source_fingerprint(metadata: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+
+    let mut hasher = Sha256::new();
+    hasher.update(metadata.dev().to_le_bytes());
+    hasher.update(metadata.ino().to_le_bytes());
+    hasher.update(metadata.mtime().to_le_bytes());
+    hasher.update(metadata.mtime_nsec().to_le_bytes());
+    hasher.update(metadata.len().to_le_bytes());
+    let hash = hasher.finalize();
+    let mut fingerprint = [0u8; 8];
+    fingerprint.copy_from_slice(&hash[..8]);
+    u64::from_le_bytes(fingerprint).max(1)
 }
 
 /// Create a cache key from filepath, original file size, and HEIC settings using SHA256
@@ -422,6 +1036,38 @@ pub fn create_cache_key(
         hasher.update(res_str.as_bytes());
     }
 
+    // Only an explicit lossless setting changes the key, so entries cached with the
+    // legacy quality heuristic stay valid
+    if let Some(lossless) = heic_settings.lossless {
+        hasher.update(b"lossless");
+        hasher.update([lossless as u8]);
+    }
+
+    if heic_settings.allow_upscale && heic_settings.max_resolution.is_some() {
+        hasher.update(b"upscale");
+    }
+
+    if !heic_settings.encoder_params.is_empty() {
+        hasher.update(b"encoder_params");
+        for (name, value) in &heic_settings.encoder_params {
+            // Length prefixes keep ("ab", "c") and ("a", "bc") apart
+            for part in [name, value] {
+                hasher.update((part.len() as u64).to_le_bytes());
+                hasher.update(part.as_bytes());
+            }
+        }
+    }
+
+    if let Some(min_convert_bytes) = heic_settings.min_convert_bytes {
+        hasher.update(b"min_convert_bytes");
+        hasher.update(min_convert_bytes.to_le_bytes());
+    }
+
+    if let Some(fallback) = heic_settings.active_fallback {
+        hasher.update(b"fallback");
+        hasher.update(fallback.extension().as_bytes());
+    }
+
     let hash = hasher.finalize();
     hex::encode(hash)
 }
@@ -434,15 +1080,600 @@ pub fn create_cache_key_and_context_for_path(
 ) -> (String, CacheContext) {
     let filepath_str = filepath.to_string_lossy().to_string();
     let key = create_cache_key(&filepath_str, original_size, heic_settings);
-    let context = CacheContext::new(filepath_str, heic_settings.clone());
+    let context = CacheContext::new(filepath_str, original_size, heic_settings.clone());
+    (key, context)
+}
+
+/// Create the cache key and context of an existing source file, stat'ing it for its
+/// size and fingerprint (both unknown if it cannot be stat'ed)
+pub fn create_cache_key_and_context_for_source(
+    filepath: &Path,
+    heic_settings: &HeicSettings,
+) -> (String, CacheContext) {
+    let metadata = fs::metadata(filepath).ok();
+    let original_size = metadata.as_ref().map_or(0, |m| m.len());
+    let (key, mut context) =
+        create_cache_key_and_context_for_path(filepath, original_size, heic_settings);
+    context.source_fingerprint = metadata.as_ref().map(source_fingerprint);
     (key, context)
 }
 
-/// Get the disk file path for a cache key using the xx/xxxxx directory structure
-fn get_cache_file_path(cache_dir: &Path, cache_key: &str) -> PathBuf {
-    // Take first 2 characters for subdirectory, remainder for filename
-    let subdir = &cache_key[0..2];
-    let filename = &cache_key[2..];
+/// Write a file through a temporary file in the same directory and rename it into
+/// place, so readers only ever see the previous or the complete new content.
+/// With `fsync` the data and the rename are also flushed to stable storage.
+fn write_file_atomically(file_path: &Path, content: &[u8], fsync: bool) -> Result<()> {
+    let parent = file_path
+        .parent()
+        .ok_or_else(|| anyhow::anyhow!("Cache file has no parent directory: {file_path:?}"))?;
+
+    let mut tmp_file = tempfile::NamedTempFile::new_in(parent)?;
+    tmp_file.write_all(content)?;
+    if fsync {
+        tmp_file.as_file().sync_all()?;
+    }
+    tmp_file.persist(file_path).map_err(|e| e.error)?;
+
+    if fsync {
+        // Persist the rename itself
+        fs::File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Get the disk file path for a cache key using the xx/xxxxx directory structure,
+/// with one 2 character subdirectory per shard level (xx/yy/xxxxx for depth 2)
+fn get_cache_file_path(cache_dir: &Path, cache_key: &str, shard_depth: usize) -> PathBuf {
+    let mut path = cache_dir.to_path_buf();
+    for level in 0..shard_depth {
+        path.push(&cache_key[level * 2..level * 2 + 2]);
+    }
+    path.join(&cache_key[shard_depth * 2..])
+}
+
+/// All files at entry depth below the cache root, skipping the root level sidecars
+fn cache_entry_files(cache_dir: &Path, shard_depth: usize) -> impl Iterator<Item = PathBuf> {
+    walkdir::WalkDir::new(cache_dir)
+        .min_depth(shard_depth + 1)
+        .max_depth(shard_depth + 1)
+        .into_iter()
+        .flatten()
+        .filter(|entry| entry.file_type().is_file())
+        .map(walkdir::DirEntry::into_path)
+}
+
+/// Whether the header of an entry file has the pinned flag, false if it is unreadable
+fn entry_is_pinned(path: &Path) -> bool {
+    use std::io::Read;
+
+    let mut header_bytes = [0u8; HEADER_SIZE];
+    fs::File::open(path)
+        .and_then(|mut file| file.read_exact(&mut header_bytes))
+        .is_ok()
+        && CacheFileHeader::from_bytes(&header_bytes).is_ok_and(|header| header.is_pinned())
+}
+
+/// Shard depth recorded in a cache directory, None if it has no marker
+fn read_shard_depth(cache_dir: &Path) -> Result<Option<usize>> {
+    let marker_path = cache_dir.join(SHARD_DEPTH_FILE_NAME);
+    match fs::read_to_string(&marker_path) {
+        Ok(content) => content
+            .trim()
+            .parse()
+            .map(Some)
+            .with_context(|| format!("Invalid cache shard depth marker {marker_path:?}")),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {marker_path:?}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn test_settings() -> HeicSettings {
+        HeicSettings::default()
+    }
+
+    /// Copy of a cached payload, for comparisons
+    fn get_vec(cache: &ImageCache, key: &str, context: &CacheContext) -> Option<Vec<u8>> {
+        cache
+            .get_with_context(key, context)
+            .map(|data| data.to_vec())
+    }
+
+    fn test_cache_settings(enable_encryption: bool) -> CacheSettings {
+        CacheSettings {
+            max_size_mb: 16,
+            enable_encryption,
+            ..CacheSettings::default()
+        }
+    }
+
+    #[test]
+    fn test_savings_ratio_tracks_puts() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let cache = ImageCache::new(temp_dir.path().to_path_buf(), &test_cache_settings(true))?;
+        let settings = test_settings();
+
+        // Three "conversions": 1000 -> 250, 2000 -> 500, 1000 -> 250
+        for (name, original, converted) in [
+            ("a.jpg", 1000, 250),
+            ("b.png", 2000, 500),
+            ("c.jpg", 1000, 250),
+        ] {
+            let (key, context) =
+                create_cache_key_and_context_for_path(Path::new(name), original, &settings);
+            cache.put_with_context(key, vec![0u8; converted], &context)?;
+        }
+
+        let stats = cache.stats();
+        assert_eq!(stats.original_bytes, 4000);
+        assert_eq!(stats.converted_bytes, 1000);
+        assert_eq!(stats.saved_bytes(), 3000);
+        assert!((stats.savings_ratio() - 0.75).abs() < f64::EPSILON);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stats_survive_restart() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let settings = test_settings();
+
+        {
+            let cache =
+                ImageCache::new(temp_dir.path().to_path_buf(), &test_cache_settings(false))?;
+            let (key, context) =
+                create_cache_key_and_context_for_path(Path::new("a.jpg"), 400, &settings);
+            cache.put_with_context(key, vec![0u8; 100], &context)?;
+            // Batched, not written on every put
+            assert_eq!(
+                ImageCache::load_stats(temp_dir.path())?,
+                CacheStatsSnapshot::default()
+            );
+        }
+
+        let persisted = ImageCache::load_stats(temp_dir.path())?;
+        assert_eq!(persisted.original_bytes, 400);
+        assert_eq!(persisted.converted_bytes, 100);
+
+        let cache = ImageCache::new(temp_dir.path().to_path_buf(), &test_cache_settings(false))?;
+        assert_eq!(cache.stats(), persisted);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_savings_ratio_empty() {
+        assert_eq!(CacheStatsSnapshot::default().savings_ratio(), 0.0);
+    }
+
+    #[test]
+    fn test_fsync_writes_are_never_partially_visible() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let settings = CacheSettings {
+            fsync: true,
+            ..test_cache_settings(false)
+        };
+        let cache = ImageCache::new(temp_dir.path().to_path_buf(), &settings)?;
+        let heic_settings = test_settings();
+
+        let (key, context) =
+            create_cache_key_and_context_for_path(Path::new("big.png"), 1, &heic_settings);
+        let file_path = get_cache_file_path(temp_dir.path(), &key, 1);
+        let payload_len = 4 * 1024 * 1024;
+        let expected_len = (HEADER_SIZE + payload_len) as u64;
+
+        let stop = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let reader = {
+            let stop = Arc::clone(&stop);
+            let file_path = file_path.clone();
+            thread::spawn(move || {
+                let mut observed = 0;
+                while !stop.load(Ordering::Relaxed) {
+                    match fs::read(&file_path) {
+                        Ok(content) => {
+                            assert_eq!(
+                                content.len() as u64,
+                                expected_len,
+                                "observed a partially written cache file"
+                            );
+                            observed += 1;
+                        }
+                        Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::NotFound),
+                    }
+                }
+                observed
+            })
+        };
+
+        for i in 0..20u8 {
+            cache.put_with_context(key.clone(), vec![i; payload_len], &context)?;
+        }
+        stop.store(true, Ordering::Relaxed);
+        reader.join().expect("reader thread panicked");
+
+        assert_eq!(fs::metadata(&file_path)?.len(), expected_len);
+        assert_eq!(
+            get_vec(&cache, &key, &context),
+            Some(vec![19u8; payload_len])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_writers_never_corrupt_entry() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let cache = Arc::new(ImageCache::new(
+            temp_dir.path().to_path_buf(),
+            &test_cache_settings(true),
+        )?);
+        let heic_settings = test_settings();
+
+        let (key, context) =
+            create_cache_key_and_context_for_path(Path::new("race.png"), 1, &heic_settings);
+        let payload = vec![0x5a; 1024 * 1024];
+        cache.put_with_context(key.clone(), payload.clone(), &context)?;
+
+        // Two writers mimic a prefetch worker and an on-demand read storing the same result
+        let writers: Vec<_> = (0..2)
+            .map(|_| {
+                let cache = Arc::clone(&cache);
+                let key = key.clone();
+                let context = context.clone();
+                let payload = payload.clone();
+                thread::spawn(move || {
+                    for _ in 0..20 {
+                        cache
+                            .put_with_context(key.clone(), payload.clone(), &context)
+                            .expect("cache write failed");
+                    }
+                })
+            })
+            .collect();
+
+        let stop = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let reader = {
+            let cache = Arc::clone(&cache);
+            let stop = Arc::clone(&stop);
+            let key = key.clone();
+            let context = context.clone();
+            let payload = payload.clone();
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    let data = cache
+                        .load_from_disk_key(&key, &context)
+                        .expect("reader observed a corrupt cache entry");
+                    assert_eq!(data, payload);
+                }
+            })
+        };
+
+        for writer in writers {
+            writer.join().expect("writer thread panicked");
+        }
+        stop.store(true, Ordering::Relaxed);
+        reader.join().expect("reader thread panicked");
+
+        // No temporary files are left next to the entry
+        let shard = get_cache_file_path(temp_dir.path(), &key, 1);
+        assert_eq!(fs::read_dir(shard.parent().unwrap())?.count(), 1);
+        Ok(())
+    }
+
+    fn secure_cache_settings(max_size_mb: u64) -> CacheSettings {
+        CacheSettings {
+            max_size_mb,
+            secure_memory_only: true,
+            ..test_cache_settings(true)
+        }
+    }
+
+    #[test]
+    fn test_shard_depth_nests_entries_and_migrates() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let heic_settings = test_settings();
+        let (key, context) =
+            create_cache_key_and_context_for_path(Path::new("photo.jpg"), 4096, &heic_settings);
+        let payload = vec![9u8; 2048];
+
+        {
+            let cache =
+                ImageCache::new(temp_dir.path().to_path_buf(), &test_cache_settings(false))?;
+            cache.put_with_context(key.clone(), payload.clone(), &context)?;
+        }
+        let flat_path = temp_dir.path().join(&key[..2]).join(&key[2..]);
+        assert!(flat_path.is_file());
+
+        let nested_settings = CacheSettings {
+            shard_depth: 2,
+            ..test_cache_settings(false)
+        };
+        let cache = ImageCache::new(temp_dir.path().to_path_buf(), &nested_settings)?;
+        let nested_path = temp_dir
+            .path()
+            .join(&key[..2])
+            .join(&key[2..4])
+            .join(&key[4..]);
+        assert!(
+            nested_path.is_file(),
+            "entry was not moved to {nested_path:?}"
+        );
+        assert!(!flat_path.exists());
+        assert_eq!(get_cache_file_path(temp_dir.path(), &key, 2), nested_path);
+        assert_eq!(get_vec(&cache, &key, &context), Some(payload.clone()));
+        let info = ImageCache::entry_info(temp_dir.path(), &key)?.expect("entry exists");
+        assert_eq!(info.path, nested_path);
+
+        // Going back to a single level moves the entry again
+        drop(cache);
+        let cache = ImageCache::new(temp_dir.path().to_path_buf(), &test_cache_settings(false))?;
+        assert!(flat_path.is_file());
+        assert_eq!(get_vec(&cache, &key, &context), Some(payload));
+        Ok(())
+    }
+
+    #[test]
+    fn test_entry_info_reports_stored_settings() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let cache = ImageCache::new(temp_dir.path().to_path_buf(), &test_cache_settings(false))?;
+        let heic_settings = HeicSettings {
+            quality: 70,
+            speed: 6,
+            chroma: 444,
+            ..HeicSettings::default()
+        };
+
+        let (key, context) =
+            create_cache_key_and_context_for_path(Path::new("photo.jpg"), 4096, &heic_settings);
+        assert_eq!(ImageCache::entry_info(temp_dir.path(), &key)?, None);
+
+        let payload = vec![3u8; 1000];
+        cache.put_with_context(key.clone(), payload.clone(), &context)?;
+
+        let info = ImageCache::entry_info(temp_dir.path(), &key)?.expect("entry exists");
+        assert_eq!((info.quality, info.speed, info.chroma), (70, 6, 444));
+        assert!(!info.encrypted);
+        assert!(!info.pinned);
+        assert_eq!(info.payload_size, 1000);
+        assert_eq!(cache.get_size_with_context(&key, &context), Some(1000));
+        assert_eq!(info.checksum, <[u8; 32]>::from(Sha256::digest(&payload)));
+        assert_eq!(info.path, get_cache_file_path(temp_dir.path(), &key, 1));
+
+        assert!(ImageCache::entry_info(temp_dir.path(), "../etc").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_session_keys_cannot_read_each_other() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let settings = CacheSettings {
+            session_key: true,
+            ..test_cache_settings(true)
+        };
+        let first = ImageCache::new(temp_dir.path().to_path_buf(), &settings)?;
+        let second = ImageCache::new(temp_dir.path().to_path_buf(), &settings)?;
+        let heic_settings = test_settings();
+
+        let (key, context) =
+            create_cache_key_and_context_for_path(Path::new("photo.jpg"), 4096, &heic_settings);
+        first.put_with_context(key.clone(), vec![7u8; 1024], &context)?;
+
+        assert_eq!(get_vec(&first, &key, &context), Some(vec![7u8; 1024]));
+        assert_eq!(second.get_with_context(&key, &context), None);
+        assert_eq!(second.get_checksum_with_context(&key, &context), None);
+
+        // Neither can the filepath-derived key
+        let path_keyed =
+            ImageCache::new(temp_dir.path().to_path_buf(), &test_cache_settings(true))?;
+        assert_eq!(path_keyed.get_with_context(&key, &context), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_secure_memory_only_writes_nothing_to_disk() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let cache = ImageCache::new(temp_dir.path().to_path_buf(), &secure_cache_settings(16))?;
+        let heic_settings = test_settings();
+
+        let (key, context) =
+            create_cache_key_and_context_for_path(Path::new("secret.jpg"), 4096, &heic_settings);
+        cache.put_with_context(key.clone(), vec![7u8; 1024], &context)?;
+
+        assert_eq!(get_vec(&cache, &key, &context), Some(vec![7u8; 1024]));
+        assert_eq!(cache.stats().converted_bytes, 1024);
+        assert_eq!(fs::read_dir(temp_dir.path())?.count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_secure_memory_eviction_scrubs_entries() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let cache = ImageCache::new(temp_dir.path().to_path_buf(), &secure_cache_settings(1))?;
+        let heic_settings = test_settings();
+        let entry_size = 600 * 1024;
+
+        let (first_key, first) =
+            create_cache_key_and_context_for_path(Path::new("first.jpg"), 1, &heic_settings);
+        let (second_key, second) =
+            create_cache_key_and_context_for_path(Path::new("second.jpg"), 1, &heic_settings);
+
+        cache.put_with_context(first_key.clone(), vec![1u8; entry_size], &first)?;
+
+        // Readers share the stored buffer instead of getting a plain copy of it
+        let read = cache.get_with_context(&first_key, &first).unwrap();
+        let again = cache.get_with_context(&first_key, &first).unwrap();
+        assert!(Arc::ptr_eq(&read, &again));
+        let first_data = Arc::downgrade(&read);
+        drop((read, again));
+
+        // Both entries do not fit in 1 MB, so the first one was evicted and released,
+        // which scrubs it
+        cache.put_with_context(second_key.clone(), vec![2u8; entry_size], &second)?;
+        assert_eq!(cache.get_with_context(&first_key, &first), None);
+        assert!(
+            first_data.upgrade().is_none(),
+            "evicted entry is still alive"
+        );
 
-    cache_dir.join(subdir).join(filename)
+        // Dropping the cache releases the remaining entry
+        let second_data = Arc::downgrade(&cache.get_with_context(&second_key, &second).unwrap());
+        drop(cache);
+        assert!(second_data.upgrade().is_none(), "entry outlived the cache");
+        Ok(())
+    }
+
+    #[test]
+    fn test_pinned_entry_survives_eviction_sweep() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let settings = CacheSettings {
+            max_size_mb: 1,
+            pinned_patterns: vec!["/hero/".to_string()],
+            ..test_cache_settings(false)
+        };
+        let cache = ImageCache::new(temp_dir.path().to_path_buf(), &settings)?;
+        let heic_settings = test_settings();
+
+        // Oldest first, 1.2 MB in a 1 MB cache
+        let mut entries = Vec::new();
+        for (age, name) in [
+            (300, "/photos/hero/banner.jpg"),
+            (200, "/photos/old.jpg"),
+            (100, "/photos/new.jpg"),
+        ] {
+            let (key, context) =
+                create_cache_key_and_context_for_path(Path::new(name), 1, &heic_settings);
+            cache.put_with_context(key.clone(), vec![0u8; 400 * 1024], &context)?;
+            let accessed = std::time::SystemTime::now() - Duration::from_secs(age);
+            fs::File::options()
+                .write(true)
+                .open(get_cache_file_path(temp_dir.path(), &key, 1))?
+                .set_times(fs::FileTimes::new().set_accessed(accessed))?;
+            entries.push((key, context));
+        }
+        let keys: Vec<&String> = entries.iter().map(|(key, _)| key).collect();
+
+        // The pinned status is stored with the entry, a restarted cache still spares it
+        drop(cache);
+        let cache = ImageCache::new(temp_dir.path().to_path_buf(), &settings)?;
+        cache.enforce_disk_limit();
+        let cached = |key: &str| get_cache_file_path(temp_dir.path(), key, 1).exists();
+        assert!(cached(keys[0]), "pinned entry was evicted");
+        assert!(!cached(keys[1]), "least recently used entry was kept");
+        assert!(cached(keys[2]));
+
+        // Reading the entry once its pattern is gone unpins it
+        drop(cache);
+        let unpinned = CacheSettings {
+            max_size_mb: 0,
+            pinned_patterns: Vec::new(),
+            ..settings
+        };
+        let cache = ImageCache::new(temp_dir.path().to_path_buf(), &unpinned)?;
+        let (key, context) = &entries[0];
+        assert!(cache.get_with_context(key, context).is_some());
+        assert!(
+            !ImageCache::entry_info(temp_dir.path(), key)?
+                .unwrap()
+                .pinned
+        );
+        cache.enforce_disk_limit();
+        assert!(!cached(keys[0]), "unpinned entry was kept");
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupted_payload_is_rejected() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let heic_settings = test_settings();
+        let (key, context) =
+            create_cache_key_and_context_for_path(Path::new("photo.jpg"), 1, &heic_settings);
+        let entry_path = get_cache_file_path(temp_dir.path(), &key, 1);
+        let data: Vec<u8> = (0..4096).map(|i| i as u8).collect();
+
+        for verify_checksum_on_read in [true, false] {
+            let settings = CacheSettings {
+                verify_checksum_on_read,
+                ..test_cache_settings(false)
+            };
+            let cache = ImageCache::new(temp_dir.path().to_path_buf(), &settings)?;
+
+            // Same length, flipped bits: only the checksum can tell
+            cache.put_with_context(key.clone(), data.clone(), &context)?;
+            let mut content = fs::read(&entry_path)?;
+            content[HEADER_SIZE + 100] ^= 0xff;
+            fs::write(&entry_path, &content)?;
+            let read = cache.get_with_context(&key, &context);
+            if verify_checksum_on_read {
+                assert_eq!(read, None);
+                assert!(!entry_path.exists(), "corrupted entry was kept");
+            } else {
+                assert_eq!(read.map(|read| read.len()), Some(data.len()));
+            }
+
+            // A truncated payload is caught by its length either way
+            cache.put_with_context(key.clone(), data.clone(), &context)?;
+            let content = fs::read(&entry_path)?;
+            fs::write(&entry_path, &content[..content.len() - 1])?;
+            assert_eq!(cache.get_with_context(&key, &context), None);
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with --release -- --ignored --nocapture"]
+    fn bench_disk_read_checksum_verification() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let heic_settings = test_settings();
+        let (key, context) =
+            create_cache_key_and_context_for_path(Path::new("large.heic"), 1, &heic_settings);
+        let data: Vec<u8> = (0..8 * 1024 * 1024).map(|i| (i * 31) as u8).collect();
+
+        let mut timings = Vec::new();
+        for verify_checksum_on_read in [true, false] {
+            let settings = CacheSettings {
+                verify_checksum_on_read,
+                ..test_cache_settings(false)
+            };
+            let cache = ImageCache::new(temp_dir.path().to_path_buf(), &settings)?;
+            cache.put_with_context(key.clone(), data.clone(), &context)?;
+
+            // Best of several reads from the (page cached) entry
+            let best = (0..20)
+                .map(|_| {
+                    let start = std::time::Instant::now();
+                    std::hint::black_box(cache.get_with_context(&key, &context));
+                    start.elapsed()
+                })
+                .min()
+                .unwrap();
+            timings.push(best);
+        }
+
+        println!(
+            "8 MB disk read: verified {:?}, unverified {:?}",
+            timings[0], timings[1]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_encoder_params_change_cache_key() {
+        let base = test_settings();
+        let with_params = |params: &[(&str, &str)]| HeicSettings {
+            encoder_params: params
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            ..base.clone()
+        };
+
+        let plain = create_cache_key("photo.jpg", 1, &base);
+        let slow = create_cache_key("photo.jpg", 1, &with_params(&[("preset", "slow")]));
+        let fast = create_cache_key("photo.jpg", 1, &with_params(&[("preset", "fast")]));
+        assert_ne!(plain, slow);
+        assert_ne!(slow, fast);
+        assert_eq!(plain, create_cache_key("photo.jpg", 1, &with_params(&[])));
+    }
 }
@@ -1,7 +1,10 @@
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
+
+use crate::file_detector::ImageFormat;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
@@ -9,18 +12,74 @@ pub struct Config {
     pub source_paths: Vec<SourcePath>,
     pub filename_patterns: Vec<String>,
     pub heic_settings: HeicSettings,
+    /// Extra encodings of every convertible file, listed as "photo.<suffix>.heic"
+    /// instead of "photo.heic"
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub variants: Vec<Variant>,
     pub cache: CacheSettings,
     #[serde(default)]
     pub fuse: FuseSettings,
+    #[serde(default)]
+    pub conversion: ConversionSettings,
     pub logging: LoggingSettings,
 }
 
+/// A named set of HEIC settings exposed as its own virtual file per source image
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Variant {
+    /// Name part inserted before ".heic", e.g. "web" for "photo.web.heic"
+    pub suffix: String,
+    pub heic_settings: HeicSettings,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SourcePath {
     pub path: PathBuf,
     pub recursive: bool,
     /// Name to appear in the FUSE mount (e.g., "pictures", "downloads")
     pub mount_name: String,
+    /// Directories to skip entirely: a plain name (e.g. ".thumbnails") matches a
+    /// directory of that name at any depth, a pattern containing "/" is matched
+    /// against the path relative to the source root ("*" and "?" globs allowed)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude_dirs: Vec<String>,
+    /// List every image of the source directly in its mount directory, "~N" is added
+    /// to the stem of names already taken ("photo.heic", "photo~1.heic")
+    #[serde(default)]
+    pub flatten: bool,
+}
+
+impl std::str::FromStr for SourcePath {
+    type Err = anyhow::Error;
+
+    /// Parse a command line source of the form "<name>=<path>[:recursive]",
+    /// e.g. "pictures=/tmp/test:true". Sources are recursive unless ":false" is given.
+    fn from_str(value: &str) -> Result<Self> {
+        let (mount_name, path) = value
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expected <name>=<path>[:recursive], got {value:?}"))?;
+
+        let (path, recursive) = match path.rsplit_once(':') {
+            Some((path, "true")) => (path, true),
+            Some((path, "false")) => (path, false),
+            _ => (path, true),
+        };
+
+        if mount_name.is_empty() || mount_name.contains('/') {
+            anyhow::bail!("Invalid mount name {mount_name:?} in source {value:?}");
+        }
+        if path.is_empty() {
+            anyhow::bail!("Missing path in source {value:?}");
+        }
+
+        Ok(Self {
+            path: PathBuf::from(path),
+            recursive,
+            mount_name: mount_name.to_string(),
+            exclude_dirs: Vec::new(),
+            flatten: false,
+        })
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -31,9 +90,163 @@ pub struct HeicSettings {
     /// Maximum pixel resolution - images larger than this will be resized
     /// Format: "width,height" or "2560,1440" for 1440p. None = no limit
     pub max_resolution: Option<String>,
+    /// Encode losslessly regardless of quality. When unset, the deprecated
+    /// behavior of switching to lossless at quality >= 95 is kept
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lossless: Option<bool>,
+    /// Also scale images smaller than max_resolution up to it (default: only scale down)
+    #[serde(default)]
+    pub allow_upscale: bool,
+    /// Encoder specific parameters applied after the standard settings, e.g.
+    /// `[["preset", "slow"], ["tune", "ssim"]]` for x265
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub encoder_params: Vec<(String, String)>,
+    /// Fail conversions on unknown or invalid encoder_params instead of warning
+    #[serde(default)]
+    pub encoder_params_strict: bool,
+    /// Format to encode instead when no HEVC encoder is available at startup, rather
+    /// than refusing to mount. Only the top-level heic_settings value is used. JPEG
+    /// output maps `quality` 1..=100 onto JPEG quality 50..=100.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fallback_format: Option<FallbackFormat>,
+    /// Sources smaller than this many bytes are served unconverted under their original
+    /// name. Only the top-level heic_settings value is used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_convert_bytes: Option<u64>,
+    /// Quality by source format, replacing `quality` for the listed formats, e.g.
+    /// `{"png": 70, "jpeg": 50}`; formats are named by any of their file extensions
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub per_format_quality: HashMap<String, u8>,
+    /// Source formats handed to a decoder, named by file extension, e.g. `["jpeg",
+    /// "png"]`; others are neither listed nor decoded. Empty allows every format.
+    /// Only the top-level heic_settings value is used.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_input_formats: Vec<String>,
+    /// Fallback actually in use, set at startup and never read from the config
+    #[serde(skip)]
+    pub active_fallback: Option<FallbackFormat>,
+}
+
+/// Output format used when HEIC cannot be encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FallbackFormat {
+    /// Re-encode as JPEG with the image crate
+    Jpeg,
+}
+
+impl FallbackFormat {
+    /// Extension of the virtual files in this format
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpg",
+        }
+    }
+}
+
+/// Chroma subsampling modes supported by the encoder
+const SUPPORTED_CHROMA: [u16; 3] = [420, 422, 444];
+
+impl Default for HeicSettings {
+    fn default() -> Self {
+        Self {
+            quality: 50,
+            speed: 4,
+            chroma: 420,
+            max_resolution: None, // No limit by default
+            lossless: None,
+            allow_upscale: false,
+            encoder_params: Vec::new(),
+            encoder_params_strict: false,
+            fallback_format: None,
+            min_convert_bytes: None,
+            per_format_quality: HashMap::new(),
+            allowed_input_formats: Vec::new(),
+            active_fallback: None,
+        }
+    }
 }
 
 impl HeicSettings {
+    /// Settings a source of `format` is converted with, `per_format_quality` applied
+    pub fn for_format(&self, format: &ImageFormat) -> Self {
+        let mut settings = self.clone();
+        if let Some(&quality) = self
+            .per_format_quality
+            .iter()
+            .find(|(name, _)| ImageFormat::from_extension(name).as_ref() == Some(format))
+            .map(|(_, quality)| quality)
+        {
+            settings.quality = quality;
+        }
+        settings
+    }
+
+    /// Whether sources of `format` may be decoded, see `allowed_input_formats`
+    pub fn allows_input_format(&self, format: &ImageFormat) -> bool {
+        self.allowed_input_formats.is_empty()
+            || self
+                .allowed_input_formats
+                .iter()
+                .any(|name| ImageFormat::from_extension(name).as_ref() == Some(format))
+    }
+
+    /// Whether images should be encoded losslessly
+    pub fn is_lossless(&self) -> bool {
+        self.lossless.unwrap_or(self.quality >= 95)
+    }
+
+    /// Reject values the encoder does not support instead of silently ignoring them
+    pub fn validate(&self) -> Result<()> {
+        if !SUPPORTED_CHROMA.contains(&self.chroma) {
+            anyhow::bail!(
+                "Unsupported heic_settings.chroma {}, allowed values: {}",
+                self.chroma,
+                SUPPORTED_CHROMA.map(|c| c.to_string()).join(", ")
+            );
+        }
+        if !(1..=100).contains(&self.quality) {
+            anyhow::bail!(
+                "heic_settings.quality must be between 1 and 100, got {}",
+                self.quality
+            );
+        }
+        if !(1..=9).contains(&self.speed) {
+            anyhow::bail!(
+                "heic_settings.speed must be between 1 and 9, got {}",
+                self.speed
+            );
+        }
+        // Sorted so the error for aliases of one format is stable
+        let mut per_format_quality: Vec<_> = self.per_format_quality.iter().collect();
+        per_format_quality.sort();
+        let mut formats: Vec<(ImageFormat, &String)> = Vec::new();
+        for (name, quality) in per_format_quality {
+            let Some(format) = ImageFormat::from_extension(name) else {
+                anyhow::bail!("Unknown format {name:?} in heic_settings.per_format_quality");
+            };
+            if !(1..=100).contains(quality) {
+                anyhow::bail!(
+                    "heic_settings.per_format_quality.{name} must be between 1 and 100, got {quality}"
+                );
+            }
+            if let Some((_, other)) = formats.iter().find(|(seen, _)| *seen == format) {
+                anyhow::bail!(
+                    "heic_settings.per_format_quality names the same format as both {other:?} and {name:?}"
+                );
+            }
+            formats.push((format, name));
+        }
+        if let Some(name) = self
+            .allowed_input_formats
+            .iter()
+            .find(|name| ImageFormat::from_extension(name).is_none())
+        {
+            anyhow::bail!("Unknown format {name:?} in heic_settings.allowed_input_formats");
+        }
+        Ok(())
+    }
+
     /// Parse max_resolution string into (width, height) tuple
     /// Returns None if no limit is set or parsing fails
     pub fn get_max_resolution(&self) -> Option<(u32, u32)> {
@@ -49,12 +262,28 @@ impl HeicSettings {
         None
     }
 
-    /// Check if image dimensions exceed the configured limit
-    pub fn should_resize(&self, width: u32, height: u32) -> bool {
-        if let Some((max_width, max_height)) = self.get_max_resolution() {
-            width > max_width || height > max_height
+    /// Dimensions to resize an image to so it fits max_resolution, preserving the aspect
+    /// ratio. Returns None when the image should be encoded at its original size, which
+    /// includes images already within bounds unless allow_upscale is set.
+    pub fn target_dimensions(&self, width: u32, height: u32) -> Option<(u32, u32)> {
+        let (max_width, max_height) = self.get_max_resolution()?;
+        if width == 0 || height == 0 {
+            return None;
+        }
+
+        let width_ratio = max_width as f64 / width as f64;
+        let height_ratio = max_height as f64 / height as f64;
+        let mut scale_ratio = width_ratio.min(height_ratio);
+        if !self.allow_upscale {
+            scale_ratio = scale_ratio.min(1.0);
+        }
+
+        let new_width = ((width as f64 * scale_ratio) as u32).max(1);
+        let new_height = ((height as f64 * scale_ratio) as u32).max(1);
+        if (new_width, new_height) == (width, height) {
+            None
         } else {
-            false
+            Some((new_width, new_height))
         }
     }
 }
@@ -67,12 +296,69 @@ pub struct CacheSettings {
     /// Default: true for security
     #[serde(default = "default_encryption")]
     pub enable_encryption: bool,
+    /// Write cache files to a temporary file, fsync it and atomically rename it into
+    /// place, so a crash never leaves a partially written entry behind
+    /// Default: false for speed
+    #[serde(default)]
+    pub fsync: bool,
+    /// Keep converted images only in RAM and scrub them when evicted, so nothing
+    /// (plaintext or ciphertext) is ever written to the cache directory
+    /// Default: false; every restart has to convert again
+    #[serde(default)]
+    pub secure_memory_only: bool,
+    /// Encrypt with a random key generated at startup and only kept in RAM instead of
+    /// the filepath-derived key, so entries from earlier runs can no longer be read
+    /// Default: false; every restart has to convert again
+    #[serde(default)]
+    pub session_key: bool,
+    /// Directory levels entries are spread over, each named after the next two hex
+    /// characters of the key: 1 is "xx/rest", 2 is "xx/yy/rest"
+    /// Default: 1; existing entries are moved when it changes
+    #[serde(default = "default_shard_depth")]
+    pub shard_depth: usize,
+    /// Regexes matched against source paths; entries of matching files are never
+    /// evicted and are converted in the background at startup
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pinned_patterns: Vec<String>,
+    /// Hash every unencrypted payload read from disk and drop entries whose SHA-256
+    /// does not match; the payload length is checked either way, and AES-GCM already
+    /// authenticates encrypted entries
+    /// Default: true; disable to trust the filesystem and save CPU on each read
+    #[serde(default = "default_verify_checksum_on_read")]
+    pub verify_checksum_on_read: bool,
 }
 
 fn default_encryption() -> bool {
     true
 }
 
+fn default_verify_checksum_on_read() -> bool {
+    true
+}
+
+fn default_shard_depth() -> usize {
+    1
+}
+
+/// Accepted `cache.shard_depth` values, up to 65536^2 leaf directories
+pub const SHARD_DEPTH_RANGE: std::ops::RangeInclusive<usize> = 1..=4;
+
+impl Default for CacheSettings {
+    fn default() -> Self {
+        Self {
+            max_size_mb: 1024,
+            cache_dir: None,         // Will use default XDG cache dir
+            enable_encryption: true, // Enable by default
+            fsync: false,
+            secure_memory_only: false,
+            session_key: false,
+            shard_depth: default_shard_depth(),
+            pinned_patterns: Vec::new(),
+            verify_checksum_on_read: default_verify_checksum_on_read(),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FuseSettings {
     /// How long FUSE should cache filesystem operations (seconds)
@@ -80,17 +366,148 @@ pub struct FuseSettings {
     /// Number of files to prefetch ahead during sequential access (0 to disable)
     #[serde(default = "default_prefetch_count")]
     pub prefetch_count: usize,
+    /// Allow overriding the quality per request with a name suffix,
+    /// e.g. "photo@q80.heic" or "photo.heic?q=80"
+    #[serde(default)]
+    pub quality_query: bool,
+    /// List the original file (served unconverted) next to its converted ".heic" name
+    #[serde(default)]
+    pub show_originals: bool,
+    /// Bypass the kernel page cache for file reads (FOPEN_DIRECT_IO), since converted
+    /// data is already cached in userspace
+    #[serde(default)]
+    pub direct_io: bool,
+    /// Keep the kernel page cache across opens of the same file (FOPEN_KEEP_CACHE)
+    #[serde(default)]
+    pub keep_cache: bool,
+    /// Expose the last conversion error of a file as a readable "<name>.error" file
+    #[serde(default)]
+    pub error_sidecars: bool,
+    /// Expose the SHA-256 of each converted file as a readable "<name>.sha256" file
+    #[serde(default)]
+    pub checksum_sidecars: bool,
+    /// Largest transfer negotiated with the kernel in `init`, in KiB. fuse3 does not
+    /// let the filesystem choose max_read or max_background, those keep its defaults.
+    #[serde(default = "default_max_write_kb")]
+    pub max_write_kb: u32,
+    /// Mount even if the mount point contains files, hiding them while mounted
+    #[serde(default)]
+    pub allow_nonempty: bool,
+    /// Size reported for files that are not converted yet
+    #[serde(default)]
+    pub size_estimation: SizeEstimation,
+    /// Hide zero-byte image files (e.g. interrupted downloads) instead of serving
+    /// them as empty files
+    #[serde(default)]
+    pub hide_empty_files: bool,
+    /// Keep listing a source whose root cannot be read (e.g. an unmounted network
+    /// drive) from the last listing that succeeded, instead of showing it empty
+    #[serde(default)]
+    pub source_fallback_cache: bool,
+    /// Only serve requests from this uid (EACCES for everyone else), since the mount
+    /// is shared with all users through allow_other
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub restrict_to_uid: Option<u32>,
+    /// Also serve root when restrict_to_uid is set
+    #[serde(default)]
+    pub allow_root: bool,
+    /// Convert files when they are opened instead of on their first read
+    #[serde(default)]
+    pub convert_on_open: bool,
+    /// Directory listings kept for reuse while their directory is unchanged, least
+    /// recently used first out (0 rescans on every readdir)
+    #[serde(default = "default_listing_cache_entries")]
+    pub listing_cache_entries: usize,
+    /// Proxy size estimates kept for files not converted yet, least recently used
+    /// first out (0 estimates again on every stat)
+    #[serde(default = "default_size_estimate_entries")]
+    pub size_estimate_entries: usize,
+}
+
+fn default_listing_cache_entries() -> usize {
+    1024
+}
+
+fn default_size_estimate_entries() -> usize {
+    16384
 }
 
 fn default_prefetch_count() -> usize {
     4
 }
 
+fn default_max_write_kb() -> u32 {
+    1024
+}
+
+/// Accepted `fuse.max_write_kb` values: one page up to 16 MiB
+pub const MAX_WRITE_KB_RANGE: std::ops::RangeInclusive<u32> = 4..=16384;
+
 impl Default for FuseSettings {
     fn default() -> Self {
         Self {
             cache_timeout: 60,
             prefetch_count: 4,
+            quality_query: false,
+            show_originals: false,
+            direct_io: false,
+            keep_cache: false,
+            error_sidecars: false,
+            checksum_sidecars: false,
+            max_write_kb: default_max_write_kb(),
+            allow_nonempty: false,
+            size_estimation: SizeEstimation::default(),
+            hide_empty_files: false,
+            source_fallback_cache: false,
+            restrict_to_uid: None,
+            allow_root: false,
+            convert_on_open: false,
+            listing_cache_entries: default_listing_cache_entries(),
+            size_estimate_entries: default_size_estimate_entries(),
+        }
+    }
+}
+
+/// How the size of a file that has no cached conversion yet is reported
+///
+/// The kernel does not read past the reported size unless direct_io is set, so an
+/// estimate that is too small truncates reads of the first open.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SizeEstimation {
+    /// The source file size, free but usually much larger than the result
+    #[default]
+    Heuristic,
+    /// Extrapolated from encoding a downscaled proxy, remembered per file. Files are
+    /// opened with direct_io since the estimate can be lower than the real size.
+    Proxy,
+    /// Convert the file on stat to report its real size; the stat (e.g. `ls -l`)
+    /// blocks until a conversion worker is done with the file
+    Exact,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConversionSettings {
+    /// Scratch directory exported as TMPDIR for the conversion workers, e.g. a tmpfs
+    /// or a disk with more room. Decoding and encoding happen in memory, so this does
+    /// not lower peak memory use; there is no max_pixels guard yet either.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temp_dir: Option<PathBuf>,
+    /// How long shutdown waits for queued conversions before workers are told
+    /// to stop; jobs still queued after that are cancelled
+    #[serde(default = "default_drain_timeout_secs")]
+    pub drain_timeout_secs: u64,
+}
+
+fn default_drain_timeout_secs() -> u64 {
+    10
+}
+
+impl Default for ConversionSettings {
+    fn default() -> Self {
+        Self {
+            temp_dir: None,
+            drain_timeout_secs: default_drain_timeout_secs(),
         }
     }
 }
@@ -98,6 +515,15 @@ impl Default for FuseSettings {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LoggingSettings {
     pub level: String,
+    /// Minimum time between two per-conversion info lines; conversions in between are
+    /// logged as one summary line
+    /// Default: 1000; 0 logs every conversion
+    #[serde(default = "default_conversion_summary_interval_ms")]
+    pub conversion_summary_interval_ms: u64,
+}
+
+pub(crate) const fn default_conversion_summary_interval_ms() -> u64 {
+    1000
 }
 
 impl Default for Config {
@@ -112,6 +538,8 @@ impl Default for Config {
                     )),
                     recursive: true,
                     mount_name: "pictures".to_string(),
+                    exclude_dirs: Vec::new(),
+                    flatten: false,
                 },
                 SourcePath {
                     path: PathBuf::from(format!(
@@ -120,23 +548,19 @@ impl Default for Config {
                     )),
                     recursive: false,
                     mount_name: "downloads".to_string(),
+                    exclude_dirs: Vec::new(),
+                    flatten: false,
                 },
             ],
             fuse: FuseSettings::default(),
+            conversion: ConversionSettings::default(),
             filename_patterns: vec![r".*\.(jpg|jpeg|png|gif|heic)$".to_string()],
-            heic_settings: HeicSettings {
-                quality: 50,
-                speed: 4,
-                chroma: 420,
-                max_resolution: None, // No limit by default
-            },
-            cache: CacheSettings {
-                max_size_mb: 1024,
-                cache_dir: None,         // Will use default XDG cache dir
-                enable_encryption: true, // Enable by default
-            },
+            heic_settings: HeicSettings::default(),
+            variants: Vec::new(),
+            cache: CacheSettings::default(),
             logging: LoggingSettings {
                 level: "warn".to_string(),
+                conversion_summary_interval_ms: default_conversion_summary_interval_ms(),
             },
         }
     }
@@ -144,6 +568,17 @@ impl Default for Config {
 
 impl Config {
     pub fn load(config_path: &Path) -> Result<Self> {
+        let (config, warnings) = Self::load_with_warnings(config_path)?;
+        for warning in warnings {
+            log::warn!("{warning}");
+        }
+        Ok(config)
+    }
+
+    /// Load the configuration, returning the warnings instead of logging them, for
+    /// callers that can only set up logging once the configuration is known
+    pub fn load_with_warnings(config_path: &Path) -> Result<(Self, Vec<String>)> {
+        let mut warnings = Vec::new();
         if config_path.exists() {
             let content = fs::read_to_string(config_path)
                 .with_context(|| format!("Failed to read config file: {config_path:?}"))?;
@@ -151,17 +586,155 @@ impl Config {
             let mut config: Config = serde_yaml::from_str(&content)
                 .with_context(|| format!("Failed to parse config file: {config_path:?}"))?;
 
+            config
+                .validate()
+                .with_context(|| format!("Invalid config file: {config_path:?}"))?;
+
+            if config.heic_settings.lossless.is_none() && config.heic_settings.quality >= 95 {
+                warnings.push(
+                    "heic_settings.quality >= 95 implies lossless encoding; this is deprecated, \
+                     set heic_settings.lossless explicitly"
+                        .to_string(),
+                );
+            }
+
+            // Variants are only listed for allowed sources, make their conversions agree
+            for variant in &mut config.variants {
+                variant.heic_settings.allowed_input_formats =
+                    config.heic_settings.allowed_input_formats.clone();
+            }
+
             // Set cache directory to XDG cache dir if not specified
             if config.cache.cache_dir.is_none() {
                 config.cache.cache_dir = Some(Self::get_cache_dir()?);
             }
 
-            Ok(config)
+            Ok((config, warnings))
         } else {
-            log::warn!("Config file not found at {config_path:?}, creating default config");
+            warnings.push(format!(
+                "Config file not found at {config_path:?}, creating default config"
+            ));
             let config = Self::default();
             config.save(config_path)?;
-            Ok(config)
+            Ok((config, warnings))
+        }
+    }
+
+    /// Check the configuration for values that would make the mount ambiguous
+    ///
+    /// Each source path must have a unique `mount_name`, and no source path may be
+    /// nested inside (or equal to) another one: a nested source would expose the same
+    /// images twice under different mount names, so it is rejected instead of being
+    /// silently shadowed. Configure only the outer directory to include the inner one.
+    /// Paths are compared after expanding "~" and resolving ".." and symlinks.
+    /// HEIC settings must be within the ranges the encoder supports.
+    pub fn validate(&self) -> Result<()> {
+        self.heic_settings.validate()?;
+
+        for (i, variant) in self.variants.iter().enumerate() {
+            if variant.suffix.is_empty() || variant.suffix.contains(['.', '/', '@', '?']) {
+                anyhow::bail!(
+                    "Invalid variant suffix {:?}: must be non-empty and not contain '.', '/', '@' or '?'",
+                    variant.suffix
+                );
+            }
+            if self.variants[..i]
+                .iter()
+                .any(|other| other.suffix == variant.suffix)
+            {
+                anyhow::bail!("Duplicate variant suffix {:?}", variant.suffix);
+            }
+            variant
+                .heic_settings
+                .validate()
+                .with_context(|| format!("Invalid settings for variant {:?}", variant.suffix))?;
+        }
+
+        let resolved_paths: Vec<PathBuf> = self
+            .source_paths
+            .iter()
+            .map(|source| resolve_path_for_comparison(&source.path))
+            .collect();
+        for (i, source) in self.source_paths.iter().enumerate() {
+            for (j, other) in self.source_paths.iter().enumerate().skip(i + 1) {
+                if source.mount_name == other.mount_name {
+                    anyhow::bail!(
+                        "Duplicate mount_name {:?} used by {:?} and {:?}",
+                        source.mount_name,
+                        source.path,
+                        other.path
+                    );
+                }
+
+                let (source_path, other_path) = (&resolved_paths[i], &resolved_paths[j]);
+                let (outer, inner) = if other_path.starts_with(source_path) {
+                    (source, other)
+                } else if source_path.starts_with(other_path) {
+                    (other, source)
+                } else {
+                    continue;
+                };
+                anyhow::bail!(
+                    "Source path {:?} ({}) is nested inside {:?} ({}); overlapping source paths are not supported",
+                    inner.path,
+                    inner.mount_name,
+                    outer.path,
+                    outer.mount_name
+                );
+            }
+        }
+
+        if self.fuse.direct_io && self.fuse.keep_cache {
+            anyhow::bail!("fuse.direct_io and fuse.keep_cache are mutually exclusive");
+        }
+        if self.fuse.size_estimation == SizeEstimation::Proxy && self.fuse.keep_cache {
+            anyhow::bail!(
+                "fuse.size_estimation: proxy opens files with direct_io, which is mutually \
+                 exclusive with fuse.keep_cache"
+            );
+        }
+        if self.logging.level.parse::<log::LevelFilter>().is_err() {
+            anyhow::bail!(
+                "Invalid logging.level {:?}, allowed values: off, error, warn, info, debug, trace",
+                self.logging.level
+            );
+        }
+        if self.cache.session_key && !self.cache.enable_encryption {
+            anyhow::bail!("cache.session_key requires cache.enable_encryption");
+        }
+        if !SHARD_DEPTH_RANGE.contains(&self.cache.shard_depth) {
+            anyhow::bail!(
+                "cache.shard_depth must be between {} and {}, got {}",
+                SHARD_DEPTH_RANGE.start(),
+                SHARD_DEPTH_RANGE.end(),
+                self.cache.shard_depth
+            );
+        }
+        for pattern in &self.cache.pinned_patterns {
+            regex::Regex::new(pattern)
+                .with_context(|| format!("Invalid cache.pinned_patterns entry {pattern:?}"))?;
+        }
+        if !MAX_WRITE_KB_RANGE.contains(&self.fuse.max_write_kb) {
+            anyhow::bail!(
+                "fuse.max_write_kb must be between {} and {}, got {}",
+                MAX_WRITE_KB_RANGE.start(),
+                MAX_WRITE_KB_RANGE.end(),
+                self.fuse.max_write_kb
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Replace the source with the same mount name, or append it if there is none
+    pub fn apply_source_override(&mut self, source: SourcePath) {
+        match self
+            .source_paths
+            .iter_mut()
+            .find(|sp| sp.mount_name == source.mount_name)
+        {
+            Some(existing) => *existing = source,
+            None => self.source_paths.push(source),
         }
     }
 
@@ -191,14 +764,19 @@ impl Config {
             .join("config.yaml"))
     }
 
-    pub fn get_cache_dir() -> Result<PathBuf> {
+    /// Default cache directory, without creating it
+    pub fn get_default_cache_dir_path() -> Result<PathBuf> {
         let home = std::env::var("HOME").context("HOME environment variable not set")?;
 
         // Use XDG_CACHE_HOME if set, otherwise ~/.cache
         let cache_home =
             std::env::var("XDG_CACHE_HOME").unwrap_or_else(|_| format!("{home}/.cache"));
 
-        let cache_dir = PathBuf::from(cache_home).join("fuse-img2heic-rs");
+        Ok(PathBuf::from(cache_home).join("fuse-img2heic-rs"))
+    }
+
+    pub fn get_cache_dir() -> Result<PathBuf> {
+        let cache_dir = Self::get_default_cache_dir_path()?;
 
         // Create cache directory if it doesn't exist
         fs::create_dir_all(&cache_dir)
@@ -218,3 +796,295 @@ impl Config {
         }
     }
 }
+
+/// Absolute form of a configured path to compare it with others: a leading "~" is
+/// expanded, symlinks are resolved and "." and ".." are folded. Components that do not
+/// exist yet are appended lexically to their deepest existing ancestor.
+fn resolve_path_for_comparison(path: &Path) -> PathBuf {
+    let path = match (path.strip_prefix("~"), std::env::var_os("HOME")) {
+        (Ok(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => path.to_path_buf(),
+    };
+    let path = std::path::absolute(&path).unwrap_or(path);
+
+    let mut resolved = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            component => {
+                resolved.push(component);
+                // Resolve as we go, so a later ".." leaves the symlink target
+                if let Ok(canonical) = resolved.canonicalize() {
+                    resolved = canonical;
+                }
+            }
+        }
+    }
+    resolved
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(path: &str, mount_name: &str) -> SourcePath {
+        SourcePath {
+            path: PathBuf::from(path),
+            recursive: true,
+            mount_name: mount_name.to_string(),
+            exclude_dirs: Vec::new(),
+            flatten: false,
+        }
+    }
+
+    #[test]
+    fn test_validate_non_overlapping_sources() {
+        let config = Config {
+            source_paths: vec![
+                source("/home/me/Pictures", "pictures"),
+                source("/home/me/Pictures2", "pictures2"),
+            ],
+            ..Config::default()
+        };
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_rejects_nested_sources() {
+        let config = Config {
+            source_paths: vec![
+                source("/home/me/Pictures/2024", "2024"),
+                source("/home/me/Pictures", "pictures"),
+            ],
+            ..Config::default()
+        };
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("nested"), "unexpected error: {err}");
+    }
+
+    #[test]
+    fn test_validate_rejects_nested_sources_after_resolving() -> Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let pictures = temp_dir.path().join("Pictures");
+        fs::create_dir_all(pictures.join("2024"))?;
+        let link = temp_dir.path().join("link");
+        std::os::unix::fs::symlink(&pictures, &link)?;
+
+        let validate = |inner: PathBuf| {
+            Config {
+                source_paths: vec![
+                    source(pictures.to_str().unwrap(), "pictures"),
+                    source(inner.to_str().unwrap(), "inner"),
+                ],
+                ..Config::default()
+            }
+            .validate()
+        };
+        assert!(validate(link.join("2024")).is_err(), "symlinked path");
+        assert!(
+            validate(pictures.join("2024/../2024/")).is_err(),
+            "dot-dot path"
+        );
+        assert!(
+            validate(pictures.join("missing/..")).is_err(),
+            "missing path"
+        );
+        assert!(validate(pictures.join("../Other")).is_ok());
+
+        let home = PathBuf::from(std::env::var("HOME")?);
+        assert_eq!(
+            resolve_path_for_comparison(Path::new("~/not-there/x")),
+            resolve_path_for_comparison(&home).join("not-there/x")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_rejects_duplicate_mount_names() {
+        let config = Config {
+            source_paths: vec![source("/a", "photos"), source("/b", "photos")],
+            ..Config::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_rejects_direct_io_with_keep_cache() {
+        let mut config = Config::default();
+        config.fuse.direct_io = true;
+        assert!(config.validate().is_ok());
+
+        config.fuse.keep_cache = true;
+        let err = config.validate().unwrap_err();
+        assert!(err.to_string().contains("mutually exclusive"), "{err}");
+
+        // Proxy size estimation implies direct_io
+        config.fuse.direct_io = false;
+        config.fuse.size_estimation = SizeEstimation::Proxy;
+        let err = config.validate().unwrap_err();
+        assert!(err.to_string().contains("mutually exclusive"), "{err}");
+    }
+
+    #[test]
+    fn test_max_write_kb_range() {
+        let mut config = Config::default();
+        config.source_paths.clear();
+        for (max_write_kb, valid) in [(4, true), (16384, true), (0, false), (16385, false)] {
+            config.fuse.max_write_kb = max_write_kb;
+            assert_eq!(config.validate().is_ok(), valid, "{max_write_kb}");
+        }
+    }
+
+    #[test]
+    fn test_target_dimensions_only_downscales() {
+        let settings = HeicSettings {
+            max_resolution: Some("2560,1440".to_string()),
+            ..HeicSettings::default()
+        };
+        assert_eq!(settings.target_dimensions(100, 100), None);
+        assert_eq!(settings.target_dimensions(2560, 1440), None);
+        assert_eq!(settings.target_dimensions(5120, 2880), Some((2560, 1440)));
+        assert_eq!(settings.target_dimensions(4000, 1000), Some((2560, 640)));
+
+        let upscale = HeicSettings {
+            allow_upscale: true,
+            ..settings
+        };
+        assert_eq!(upscale.target_dimensions(100, 100), Some((1440, 1440)));
+        assert_eq!(HeicSettings::default().target_dimensions(100, 100), None);
+    }
+
+    #[test]
+    fn test_load_rejects_out_of_range_heic_settings() -> Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let config_path = temp_dir.path().join("config.yaml");
+
+        let valid = serde_yaml::to_string(&Config::default())?;
+
+        for (setting, value, expected) in [
+            (
+                "chroma: 420",
+                "chroma: 400",
+                "allowed values: 420, 422, 444",
+            ),
+            ("chroma: 420", "chroma: 444444", "chroma"),
+            (
+                "quality: 50",
+                "quality: 0",
+                "quality must be between 1 and 100",
+            ),
+            (
+                "quality: 50",
+                "quality: 101",
+                "quality must be between 1 and 100",
+            ),
+            ("speed: 4", "speed: 10", "speed must be between 1 and 9"),
+            (
+                "speed: 4",
+                "speed: 4\n  per_format_quality:\n    jpg: 50\n    jpeg: 90",
+                "same format as both \"jpeg\" and \"jpg\"",
+            ),
+        ] {
+            assert!(valid.contains(setting), "{valid}");
+            fs::write(&config_path, valid.replace(setting, value))?;
+
+            let err = Config::load(&config_path).unwrap_err();
+            assert!(format!("{err:#}").contains(expected), "{value}: {err:#}");
+        }
+
+        Config::default().save(&config_path)?;
+        assert!(Config::load(&config_path).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_returns_warnings() -> Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let config_path = temp_dir.path().join("config.yaml");
+
+        let (_, warnings) = Config::load_with_warnings(&config_path)?;
+        assert!(
+            warnings[0].contains("creating default config"),
+            "{warnings:?}"
+        );
+
+        let (_, warnings) = Config::load_with_warnings(&config_path)?;
+        assert!(warnings.is_empty(), "{warnings:?}");
+
+        let lossy = fs::read_to_string(&config_path)?.replace("quality: 50", "quality: 95");
+        fs::write(&config_path, lossy)?;
+        let (_, warnings) = Config::load_with_warnings(&config_path)?;
+        assert!(warnings[0].contains("deprecated"), "{warnings:?}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_rejects_bad_variant_suffixes() {
+        let variant = |suffix: &str| Variant {
+            suffix: suffix.to_string(),
+            heic_settings: HeicSettings::default(),
+        };
+
+        let mut config = Config {
+            variants: vec![variant("hq"), variant("web")],
+            ..Config::default()
+        };
+        assert!(config.validate().is_ok());
+
+        config.variants.push(variant("web"));
+        assert!(config.validate().is_err());
+
+        config.variants = vec![variant("web.small")];
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_parse_source_override() -> Result<()> {
+        let source: SourcePath = "pictures=/tmp/test:false".parse()?;
+        assert_eq!(source.mount_name, "pictures");
+        assert_eq!(source.path, PathBuf::from("/tmp/test"));
+        assert!(!source.recursive);
+
+        // Recursive by default, and only a trailing ":true"/":false" is a flag
+        let source: SourcePath = "media=/mnt/a:b".parse()?;
+        assert_eq!(source.path, PathBuf::from("/mnt/a:b"));
+        assert!(source.recursive);
+
+        assert!("/tmp/test".parse::<SourcePath>().is_err());
+        assert!("=/tmp/test".parse::<SourcePath>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_source_override_replaces_matching_mount_name() -> Result<()> {
+        let mut config = Config {
+            source_paths: vec![
+                source("/home/me/Pictures", "pictures"),
+                source("/home/me/Downloads", "downloads"),
+            ],
+            ..Config::default()
+        };
+
+        config.apply_source_override("pictures=/tmp/test:true".parse()?);
+        config.apply_source_override("extra=/tmp/extra".parse()?);
+
+        let sources: Vec<(&str, &Path)> = config
+            .source_paths
+            .iter()
+            .map(|sp| (sp.mount_name.as_str(), sp.path.as_path()))
+            .collect();
+        assert_eq!(
+            sources,
+            vec![
+                ("pictures", Path::new("/tmp/test")),
+                ("downloads", Path::new("/home/me/Downloads")),
+                ("extra", Path::new("/tmp/extra")),
+            ]
+        );
+        assert!(config.validate().is_ok());
+        Ok(())
+    }
+}
@@ -0,0 +1,479 @@
+use libheif_rs::CompressionFormat;
+use std::ffi::CString;
+use std::fmt::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::file_detector::FileDetector;
+use crate::image_converter;
+use crate::mount_management::{self, MountPointState};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckStatus {
+    Pass,
+    /// Not fatal, but likely to cause surprises
+    Warn,
+    /// The mount will not work until this is fixed
+    Fail,
+}
+
+#[derive(Debug)]
+pub struct CheckResult {
+    pub name: String,
+    pub status: CheckStatus,
+    pub detail: String,
+    /// Remediation hint shown for warnings and failures
+    pub hint: Option<String>,
+}
+
+impl CheckResult {
+    fn pass(name: impl Into<String>, detail: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            status: CheckStatus::Pass,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn warn(name: impl Into<String>, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            status: CheckStatus::Warn,
+            detail: detail.into(),
+            hint: Some(hint.into()),
+        }
+    }
+
+    fn fail(name: impl Into<String>, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            status: CheckStatus::Fail,
+            detail: detail.into(),
+            hint: Some(hint.into()),
+        }
+    }
+}
+
+/// Run every environment check needed for a working mount
+pub fn run_checks(config: &Config, mount_point: &Path) -> Vec<CheckResult> {
+    let mut results = vec![
+        check_fuse_device(),
+        check_fusermount(),
+        // SAFETY: getuid takes no arguments, cannot fail and has no side effects
+        check_allow_other(Path::new("/etc/fuse.conf"), unsafe { libc::getuid() }),
+        check_heic_encoder(config),
+        check_mount_point(mount_point, config.fuse.allow_nonempty),
+        check_cache_dir(config),
+    ];
+    results.extend(check_source_paths(config));
+    results
+}
+
+pub fn has_failures(results: &[CheckResult]) -> bool {
+    results.iter().any(|r| r.status == CheckStatus::Fail)
+}
+
+/// Render the check results as a human readable pass/fail report
+pub fn format_report(results: &[CheckResult]) -> String {
+    let mut report = String::new();
+    for result in results {
+        let status = match result.status {
+            CheckStatus::Pass => "PASS",
+            CheckStatus::Warn => "WARN",
+            CheckStatus::Fail => "FAIL",
+        };
+        let _ = writeln!(report, "[{status}] {}: {}", result.name, result.detail);
+        if let Some(hint) = &result.hint {
+            let _ = writeln!(report, "       hint: {hint}");
+        }
+    }
+    report
+}
+
+fn check_fuse_device() -> CheckResult {
+    let name = "FUSE device";
+    if Path::new("/dev/fuse").exists() {
+        CheckResult::pass(name, "/dev/fuse is present")
+    } else {
+        CheckResult::fail(
+            name,
+            "/dev/fuse not found",
+            "load the kernel module with `modprobe fuse` and install fuse3",
+        )
+    }
+}
+
+fn check_fusermount() -> CheckResult {
+    let name = "fusermount";
+    match ["fusermount3", "fusermount"]
+        .iter()
+        .find_map(|binary| find_in_path(binary))
+    {
+        Some(path) => CheckResult::pass(name, format!("found {}", path.display())),
+        None => CheckResult::fail(
+            name,
+            "neither fusermount3 nor fusermount is in PATH",
+            "install the fuse3 package (e.g. `apt install fuse3`)",
+        ),
+    }
+}
+
+fn check_allow_other(fuse_conf: &Path, uid: u32) -> CheckResult {
+    let name = "allow_other";
+    if uid == 0 {
+        return CheckResult::pass(name, "running as root");
+    }
+
+    let enabled = std::fs::read_to_string(fuse_conf)
+        .map(|content| {
+            content
+                .lines()
+                .any(|line| line.trim() == "user_allow_other")
+        })
+        .unwrap_or(false);
+
+    if enabled {
+        CheckResult::pass(
+            name,
+            format!("user_allow_other set in {}", fuse_conf.display()),
+        )
+    } else {
+        CheckResult::fail(
+            name,
+            format!("user_allow_other is not enabled in {}", fuse_conf.display()),
+            format!("add a `user_allow_other` line to {}", fuse_conf.display()),
+        )
+    }
+}
+
+fn check_heic_encoder(config: &Config) -> CheckResult {
+    let name = "libheif HEVC encoder";
+    match image_converter::check_encoder_available(CompressionFormat::Hevc) {
+        Ok(()) => CheckResult::pass(name, "available"),
+        Err(e) => match config.heic_settings.fallback_format {
+            Some(fallback) => CheckResult::warn(
+                name,
+                format!("not available, files will be served as {fallback:?}"),
+                e.to_string(),
+            ),
+            None => CheckResult::fail(name, "not available", e.to_string()),
+        },
+    }
+}
+
+/// Same probe as startup, so stuck and non-empty mount points are caught here too
+fn check_mount_point(mount_point: &Path, allow_nonempty: bool) -> CheckResult {
+    let name = "mount point";
+    match mount_management::probe_mount_point(mount_point) {
+        MountPointState::Accessible { empty: true } => {
+            return CheckResult::pass(name, format!("{} is empty", mount_point.display()));
+        }
+        MountPointState::Accessible { empty: false } if allow_nonempty => {
+            return CheckResult::warn(
+                name,
+                format!(
+                    "{} is not empty, its contents are hidden while mounted",
+                    mount_point.display()
+                ),
+                "use an empty directory to keep its contents visible",
+            );
+        }
+        MountPointState::Accessible { empty: false } => {
+            return CheckResult::fail(
+                name,
+                format!(
+                    "{} is not empty, mounting would hide its contents",
+                    mount_point.display()
+                ),
+                mount_management::NONEMPTY_HINT,
+            );
+        }
+        MountPointState::Stuck => {
+            return CheckResult::warn(
+                name,
+                format!(
+                    "{} is a stuck FUSE mount of a previous run",
+                    mount_point.display()
+                ),
+                format!(
+                    "it is unmounted on startup, or run `fusermount -u {}`",
+                    mount_point.display()
+                ),
+            );
+        }
+        MountPointState::Inaccessible(e) if e.raw_os_error() == Some(libc::ENOTDIR) => {
+            return CheckResult::fail(
+                name,
+                format!("{} is not a directory", mount_point.display()),
+                "point mount_point (or -m) at a directory",
+            );
+        }
+        MountPointState::Inaccessible(e) => {
+            return CheckResult::fail(
+                name,
+                format!("cannot access {}: {e}", mount_point.display()),
+                "fix the mount point permissions or choose another location",
+            );
+        }
+        MountPointState::Missing => {}
+    }
+
+    // The mount point gets created on startup, so its closest existing ancestor must be writable
+    let ancestor = closest_existing_ancestor(mount_point);
+    if is_writable(ancestor) {
+        CheckResult::pass(name, format!("{} will be created", mount_point.display()))
+    } else {
+        CheckResult::fail(
+            name,
+            format!(
+                "{} does not exist and {} is not writable",
+                mount_point.display(),
+                ancestor.display()
+            ),
+            "create the mount point manually or choose a writable location",
+        )
+    }
+}
+
+fn check_cache_dir(config: &Config) -> CheckResult {
+    let name = "cache directory";
+    let hint = "fix the directory permissions or set cache.cache_dir";
+    // Only look, the directory is created on startup
+    let dir = match &config.cache.cache_dir {
+        Some(dir) => dir.clone(),
+        None => match Config::get_default_cache_dir_path() {
+            Ok(dir) => dir,
+            Err(e) => return CheckResult::fail(name, format!("{e:#}"), hint),
+        },
+    };
+
+    if dir.is_dir() {
+        return if is_writable(&dir) {
+            CheckResult::pass(name, format!("{} is writable", dir.display()))
+        } else {
+            CheckResult::fail(name, format!("{} is not writable", dir.display()), hint)
+        };
+    }
+    if dir.exists() {
+        return CheckResult::fail(name, format!("{} is not a directory", dir.display()), hint);
+    }
+
+    let ancestor = closest_existing_ancestor(&dir);
+    if is_writable(ancestor) {
+        CheckResult::pass(name, format!("{} will be created", dir.display()))
+    } else {
+        CheckResult::fail(
+            name,
+            format!(
+                "{} does not exist and {} is not writable",
+                dir.display(),
+                ancestor.display()
+            ),
+            hint,
+        )
+    }
+}
+
+fn check_source_paths(config: &Config) -> Vec<CheckResult> {
+    if let Err(e) = FileDetector::new(config.filename_patterns.clone()) {
+        return vec![CheckResult::fail(
+            "filename patterns",
+            format!("{e:#}"),
+            "fix filename_patterns in the config file",
+        )];
+    }
+
+    // Only the root is listed, walking the whole tree could take minutes
+    config
+        .source_paths
+        .iter()
+        .map(|source_path| {
+            let name = format!("source {}", source_path.mount_name);
+            match std::fs::read_dir(&source_path.path) {
+                Ok(_) => {
+                    CheckResult::pass(name, format!("{} is readable", source_path.path.display()))
+                }
+                Err(e) => CheckResult::fail(
+                    name,
+                    format!("{} is not readable: {e}", source_path.path.display()),
+                    "check that the path exists and is readable by this user",
+                ),
+            }
+        })
+        .collect()
+}
+
+fn find_in_path(binary: &str) -> Option<PathBuf> {
+    let path_var = std::env::var_os("PATH")?;
+    std::env::split_paths(&path_var)
+        .map(|dir| dir.join(binary))
+        .find(|candidate| candidate.is_file())
+}
+
+/// The path itself if it exists, else its deepest existing parent
+fn closest_existing_ancestor(path: &Path) -> &Path {
+    path.ancestors()
+        .find(|p| p.exists())
+        .unwrap_or(Path::new("/"))
+}
+
+fn is_writable(path: &Path) -> bool {
+    let Ok(c_path) = CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    // SAFETY: c_path is a valid NUL-terminated string that outlives the call
+    unsafe { libc::access(c_path.as_ptr(), libc::W_OK) == 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{CacheSettings, SourcePath};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_report_includes_encoder_check() -> anyhow::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let source_dir = temp_dir.path().join("source");
+        std::fs::create_dir_all(&source_dir)?;
+        std::fs::write(source_dir.join("photo.jpg"), b"test")?;
+
+        let config = Config {
+            source_paths: vec![SourcePath {
+                path: source_dir,
+                recursive: true,
+                mount_name: "pictures".to_string(),
+                exclude_dirs: Vec::new(),
+                flatten: false,
+            }],
+            cache: CacheSettings {
+                cache_dir: Some(temp_dir.path().join("cache")),
+                ..CacheSettings::default()
+            },
+            ..Config::default()
+        };
+
+        let results = run_checks(&config, &temp_dir.path().join("mnt"));
+        let report = format_report(&results);
+
+        assert!(
+            report.contains("[PASS] libheif HEVC encoder"),
+            "report: {report}"
+        );
+        assert!(report.contains("[PASS] mount point"), "report: {report}");
+        assert!(
+            report.contains("[PASS] source pictures"),
+            "report: {report}"
+        );
+        assert!(
+            report.contains("[PASS] cache directory"),
+            "report: {report}"
+        );
+
+        // Diagnosing does not create the cache directory
+        assert!(!temp_dir.path().join("cache").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_unreadable_source_fails() {
+        let config = Config {
+            source_paths: vec![SourcePath {
+                path: PathBuf::from("/nonexistent/fuse-img2heic-doctor"),
+                recursive: true,
+                mount_name: "missing".to_string(),
+                exclude_dirs: Vec::new(),
+                flatten: false,
+            }],
+            ..Config::default()
+        };
+
+        let results = check_source_paths(&config);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].status, CheckStatus::Fail);
+        assert!(has_failures(&results));
+    }
+
+    #[test]
+    fn test_cache_dir_check_creates_nothing() -> anyhow::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let cache_dir = temp_dir.path().join("cache/nested");
+        let config = Config {
+            cache: CacheSettings {
+                cache_dir: Some(cache_dir.clone()),
+                ..CacheSettings::default()
+            },
+            ..Config::default()
+        };
+
+        let result = check_cache_dir(&config);
+        assert_eq!(result.status, CheckStatus::Pass, "{}", result.detail);
+        assert!(
+            result.detail.contains("will be created"),
+            "{}",
+            result.detail
+        );
+        assert!(!temp_dir.path().join("cache").exists());
+
+        std::fs::create_dir_all(&cache_dir)?;
+        assert!(check_cache_dir(&config).detail.contains("is writable"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_mount_point_check_matches_startup_probe() -> anyhow::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let mount_point = temp_dir.path().join("mnt");
+        let result = check_mount_point(&mount_point, false);
+        assert_eq!(result.status, CheckStatus::Pass);
+        assert!(
+            result.detail.contains("will be created"),
+            "{}",
+            result.detail
+        );
+
+        std::fs::create_dir_all(&mount_point)?;
+        assert_eq!(
+            check_mount_point(&mount_point, false).status,
+            CheckStatus::Pass
+        );
+
+        std::fs::write(mount_point.join("file"), b"hidden")?;
+        let result = check_mount_point(&mount_point, false);
+        assert_eq!(result.status, CheckStatus::Fail);
+        assert_eq!(
+            result.hint.as_deref(),
+            Some(mount_management::NONEMPTY_HINT)
+        );
+        assert_eq!(
+            check_mount_point(&mount_point, true).status,
+            CheckStatus::Warn
+        );
+
+        let file = mount_point.join("file");
+        assert_eq!(check_mount_point(&file, false).status, CheckStatus::Fail);
+        Ok(())
+    }
+
+    #[test]
+    fn test_allow_other_reads_fuse_conf() -> anyhow::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let fuse_conf = temp_dir.path().join("fuse.conf");
+        std::fs::write(&fuse_conf, "#user_allow_other\n")?;
+        assert_eq!(
+            check_allow_other(&fuse_conf, 1000).status,
+            CheckStatus::Fail
+        );
+        // Root can always use allow_other
+        assert_eq!(check_allow_other(&fuse_conf, 0).status, CheckStatus::Pass);
+
+        std::fs::write(&fuse_conf, "user_allow_other\n")?;
+        assert_eq!(
+            check_allow_other(&fuse_conf, 1000).status,
+            CheckStatus::Pass
+        );
+        Ok(())
+    }
+}
@@ -1,8 +1,13 @@
 use anyhow::{Context, Result};
+use dashmap::DashMap;
 use log::debug;
 use regex::Regex;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use walkdir::WalkDir;
 
 use crate::config::SourcePath;
 
@@ -59,8 +64,36 @@ impl ImageFormat {
     }
 }
 
+/// Unique file names of a flattened source mapped to the image files they stand for
+struct FlatIndex {
+    names: HashMap<String, PathBuf>,
+    /// Flat name of each image by lowercase stem, for converted name lookups
+    by_stem: HashMap<String, String>,
+    /// Modification time of the source root when the index was built
+    root_mtime: Option<SystemTime>,
+    built: SystemTime,
+}
+
+/// A flat index is rebuilt once it is older than this even if the source root is
+/// unchanged, since changes in nested directories do not touch the root mtime
+const FLAT_INDEX_TTL: Duration = Duration::from_secs(10);
+
 pub struct FileDetector {
     filename_patterns: Vec<Regex>,
+    /// List convertible files under their original name as well as the ".heic" one
+    show_originals: bool,
+    /// When set, convertible files are listed once per suffix as "<stem>.<suffix>.heic"
+    variant_suffixes: Vec<String>,
+    /// Leave zero-byte files out of listings
+    hide_empty_files: bool,
+    /// Extension of converted files, "heic" unless a fallback format is in use
+    output_extension: &'static str,
+    /// Files smaller than this are listed under their original name only
+    min_convert_bytes: Option<u64>,
+    /// Only files of these formats are images, all formats when empty
+    allowed_formats: Vec<ImageFormat>,
+    /// Flat name mapping of each `flatten` source by mount name
+    flat_indexes: DashMap<String, Arc<FlatIndex>>,
 }
 
 impl FileDetector {
@@ -73,7 +106,64 @@ impl FileDetector {
             filename_patterns.push(regex);
         }
 
-        Ok(Self { filename_patterns })
+        Ok(Self {
+            filename_patterns,
+            show_originals: false,
+            variant_suffixes: Vec::new(),
+            hide_empty_files: false,
+            output_extension: "heic",
+            min_convert_bytes: None,
+            allowed_formats: Vec::new(),
+            flat_indexes: DashMap::new(),
+        })
+    }
+
+    pub fn with_show_originals(mut self, show_originals: bool) -> Self {
+        self.show_originals = show_originals;
+        self
+    }
+
+    pub fn with_variant_suffixes(mut self, variant_suffixes: Vec<String>) -> Self {
+        self.variant_suffixes = variant_suffixes;
+        self
+    }
+
+    pub fn with_hide_empty_files(mut self, hide_empty_files: bool) -> Self {
+        self.hide_empty_files = hide_empty_files;
+        self
+    }
+
+    pub fn with_output_extension(mut self, output_extension: &'static str) -> Self {
+        self.output_extension = output_extension;
+        self
+    }
+
+    pub fn output_extension(&self) -> &'static str {
+        self.output_extension
+    }
+
+    pub fn with_min_convert_bytes(mut self, min_convert_bytes: Option<u64>) -> Self {
+        self.min_convert_bytes = min_convert_bytes;
+        self
+    }
+
+    /// Restrict images to these formats, named by extension like
+    /// `heic_settings.allowed_input_formats`
+    pub fn with_allowed_formats(mut self, names: &[String]) -> Self {
+        self.allowed_formats = names
+            .iter()
+            .filter_map(|name| ImageFormat::from_extension(name))
+            .collect();
+        self
+    }
+
+    fn is_format_allowed(&self, format: &ImageFormat) -> bool {
+        self.allowed_formats.is_empty() || self.allowed_formats.contains(format)
+    }
+
+    /// Whether a source of this size is too small to be worth converting
+    pub fn is_below_convert_threshold(&self, size: u64) -> bool {
+        self.min_convert_bytes.is_some_and(|min| size < min)
     }
 
     pub fn is_image_file(&self, path: &Path) -> bool {
@@ -84,7 +174,8 @@ impl FileDetector {
                 .iter()
                 .any(|regex| regex.is_match(filename))
             {
-                return true;
+                return self.allowed_formats.is_empty()
+                    || matches!(self.detect_format(path), Ok(Some(_)));
             }
         }
 
@@ -94,7 +185,8 @@ impl FileDetector {
                 let mut buffer = [0; 512]; // Read first 512 bytes for detection
                 if let Ok(bytes_read) = std::io::Read::read(&mut file, &mut buffer) {
                     if bytes_read > 0 {
-                        return ImageFormat::from_content(&buffer[..bytes_read]).is_some();
+                        return ImageFormat::from_content(&buffer[..bytes_read])
+                            .is_some_and(|format| self.is_format_allowed(&format));
                     }
                 }
             }
@@ -116,7 +208,7 @@ impl FileDetector {
             if bytes_read > 0 {
                 if let Some(format) = ImageFormat::from_content(&buffer[..bytes_read]) {
                     debug!("Detected format by content: {path:?} -> {format:?}");
-                    return Ok(Some(format));
+                    return Ok(self.allowed(path, format));
                 }
             }
         }
@@ -125,13 +217,92 @@ impl FileDetector {
         if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
             if let Some(format) = ImageFormat::from_extension(ext) {
                 debug!("Detected format by extension: {path:?} -> {format:?}");
-                return Ok(Some(format));
+                return Ok(self.allowed(path, format));
             }
         }
 
         Ok(None)
     }
 
+    /// `format` if it is allowed, None (not an image) otherwise
+    fn allowed(&self, path: &Path, format: ImageFormat) -> Option<ImageFormat> {
+        if self.is_format_allowed(&format) {
+            return Some(format);
+        }
+        debug!("{format:?} is not an allowed input format: {path:?}");
+        None
+    }
+
+    /// Check if a directory (relative to the source root) is excluded by `exclude_dirs`
+    pub fn is_excluded_dir(source_path: &SourcePath, relative_dir: &Path) -> bool {
+        if source_path.exclude_dirs.is_empty() || relative_dir.as_os_str().is_empty() {
+            return false;
+        }
+
+        let Some(relative_str) = relative_dir.to_str() else {
+            return false;
+        };
+        let dir_name = relative_dir
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default();
+
+        source_path.exclude_dirs.iter().any(|pattern| {
+            let pattern = pattern.trim_matches('/');
+            if pattern.contains('/') {
+                glob_match(pattern, relative_str)
+            } else {
+                glob_match(pattern, dir_name)
+            }
+        })
+    }
+
+    /// Check if any directory leading to `relative_path` is excluded
+    fn is_under_excluded_dir(source_path: &SourcePath, relative_path: &Path) -> bool {
+        relative_path
+            .ancestors()
+            .skip(1)
+            .any(|dir| Self::is_excluded_dir(source_path, dir))
+    }
+
+    /// Walk a source path and return all image files in it, sorted by path
+    ///
+    /// Excluded directories and exclusion paths (e.g. the mount point) are pruned
+    /// at the walker level, so their subtrees are never descended into.
+    pub fn discover_images(
+        &self,
+        source_path: &SourcePath,
+        exclude_paths: &[&Path],
+    ) -> Vec<PathBuf> {
+        let max_depth = if source_path.recursive { usize::MAX } else { 1 };
+        let root = source_path.path.as_path();
+
+        WalkDir::new(root)
+            .max_depth(max_depth)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_entry(|entry| {
+                if entry.depth() == 0 || !entry.file_type().is_dir() {
+                    return true;
+                }
+                if exclude_paths.iter().any(|exclude| entry.path() == *exclude) {
+                    return false;
+                }
+                let relative = entry.path().strip_prefix(root).unwrap_or(entry.path());
+                !Self::is_excluded_dir(source_path, relative)
+            })
+            .filter_map(|entry| match entry {
+                Ok(entry) => Some(entry),
+                Err(e) => {
+                    debug!("Skipping unreadable entry while discovering images: {e}");
+                    None
+                }
+            })
+            .filter(|entry| entry.file_type().is_file() && self.is_image_file(entry.path()))
+            .map(|entry| entry.into_path())
+            .collect()
+    }
+
     /// Check if a virtual path corresponds to a real directory
     pub fn is_virtual_directory(&self, virtual_path: &Path, source_paths: &[SourcePath]) -> bool {
         if virtual_path == Path::new("/") || virtual_path.as_os_str().is_empty() {
@@ -152,10 +323,45 @@ impl FileDetector {
             return false;
         };
 
+        // Non-recursive and flattened sources only expose top-level files
+        if !source_path.recursive || source_path.flatten {
+            return false;
+        }
+
+        if Self::is_excluded_dir(source_path, subpath)
+            || Self::is_under_excluded_dir(source_path, subpath)
+        {
+            return false;
+        }
+
         let real_path = source_path.path.join(subpath);
         real_path.is_dir()
     }
 
+    /// Changes whenever the listing of a virtual directory may have changed: the real
+    /// directory mtime, or the build time of a flattened source's index. None for the
+    /// root, which is built from the config alone.
+    pub fn listing_version(
+        &self,
+        virtual_dir: &Path,
+        source_paths: &[SourcePath],
+        exclude_paths: &[&Path],
+    ) -> Option<SystemTime> {
+        if virtual_dir == Path::new("/") {
+            return None;
+        }
+        let (mount_name, subpath) = self.parse_virtual_path(virtual_dir).ok()?;
+        let source_path = self
+            .find_source_by_mount_name(&mount_name, source_paths)
+            .ok()?;
+        if source_path.flatten {
+            return Some(self.flat_index(source_path, exclude_paths).built);
+        }
+        fs::metadata(source_path.path.join(subpath))
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    }
+
     /// List entries in a specific virtual directory with path exclusions (e.g., mount points)
     pub fn list_virtual_directory_with_exclusions(
         &self,
@@ -170,18 +376,51 @@ impl FileDetector {
 
         let (mount_name, subpath) = self.parse_virtual_path(virtual_dir)?;
         let source_path = self.find_source_by_mount_name(&mount_name, source_paths)?;
+
+        if (!source_path.recursive || source_path.flatten) && !subpath.as_os_str().is_empty() {
+            return Ok(Vec::new());
+        }
+        if source_path.flatten {
+            return Ok(self.list_flattened_source(source_path, exclude_paths));
+        }
+
+        if Self::is_excluded_dir(source_path, subpath)
+            || Self::is_under_excluded_dir(source_path, subpath)
+        {
+            return Ok(Vec::new());
+        }
+
         let real_dir = source_path.path.join(subpath);
 
-        self.list_real_directory_with_exclusions(&real_dir, exclude_paths)
+        let mut entries = self.list_real_directory_with_exclusions(
+            &real_dir,
+            exclude_paths,
+            source_path.recursive,
+        )?;
+        entries.retain(|(name, is_directory)| {
+            !*is_directory || !Self::is_excluded_dir(source_path, &subpath.join(name))
+        });
+        Ok(entries)
     }
 
     fn list_root_directory(&self, source_paths: &[SourcePath]) -> Result<Vec<(String, bool)>> {
-        let mut entries = Vec::new();
+        // Sorted and de-duplicated by mount name so the root listing is deterministic
+        // regardless of config order; the first source with a given name wins, matching
+        // find_source_by_mount_name()
+        let mut entries: Vec<(String, bool)> = Vec::new();
         for source_path in source_paths {
-            if source_path.path.exists() {
-                entries.push((source_path.mount_name.clone(), true));
+            if !source_path.path.exists() {
+                continue;
+            }
+            if entries
+                .iter()
+                .any(|(name, _)| *name == source_path.mount_name)
+            {
+                continue;
             }
+            entries.push((source_path.mount_name.clone(), true));
         }
+        entries.sort();
         Ok(entries)
     }
 
@@ -210,11 +449,21 @@ impl FileDetector {
         &self,
         real_dir: &Path,
         exclude_paths: &[&Path],
+        include_directories: bool,
     ) -> Result<Vec<(String, bool)>> {
         if !real_dir.exists() || !real_dir.is_dir() {
             return Ok(Vec::new());
         }
 
+        // Never list anything from inside an excluded tree (e.g. our own mount point)
+        if exclude_paths
+            .iter()
+            .any(|exclude| real_dir.starts_with(exclude))
+        {
+            debug!("Skipping listing of excluded directory: {real_dir:?}");
+            return Ok(Vec::new());
+        }
+
         let mut entries = Vec::new();
         for entry in std::fs::read_dir(real_dir)? {
             let entry = entry?;
@@ -231,33 +480,234 @@ impl FileDetector {
             }
 
             if path.is_dir() {
-                entries.push((name.to_string(), true));
+                if include_directories {
+                    entries.push((name.to_string(), true));
+                }
             } else if self.is_image_file(&path) {
-                let display_name = self.get_display_name(&path, name);
-                entries.push((display_name, false));
+                let size = entry.metadata().map_or(0, |m| m.len());
+                self.push_file_entries(&mut entries, &path, name, size);
             }
         }
+
+        // read_dir order is filesystem dependent; keep listings stable between calls
+        entries.sort();
         Ok(entries)
     }
 
-    fn get_display_name(&self, path: &Path, original_name: &str) -> String {
+    /// Listing entries of an image file named `name` (its own or its flattened name)
+    fn push_file_entries(
+        &self,
+        entries: &mut Vec<(String, bool)>,
+        path: &Path,
+        name: &str,
+        size: u64,
+    ) {
+        if self.hide_empty_files && size == 0 {
+            debug!("Hiding empty file: {path:?}");
+            return;
+        }
+        let display_names = if self.is_below_convert_threshold(size) {
+            vec![name.to_string()]
+        } else {
+            self.get_display_names(Path::new(name), name)
+        };
+        if self.show_originals && !display_names.iter().any(|n| n == name) {
+            entries.push((name.to_string(), false));
+        }
+        entries.extend(display_names.into_iter().map(|n| (n, false)));
+    }
+
+    /// All images of a flattened source, listed under their flat names
+    fn list_flattened_source(
+        &self,
+        source_path: &SourcePath,
+        exclude_paths: &[&Path],
+    ) -> Vec<(String, bool)> {
+        let index = self.flat_index(source_path, exclude_paths);
+        let mut entries = Vec::new();
+        for (name, path) in &index.names {
+            let size = fs::metadata(path).map_or(0, |m| m.len());
+            self.push_file_entries(&mut entries, path, name, size);
+        }
+        entries.sort();
+        entries
+    }
+
+    /// Flat index of a source, rebuilt when the source root changed or it expired
+    fn flat_index(&self, source_path: &SourcePath, exclude_paths: &[&Path]) -> Arc<FlatIndex> {
+        let root_mtime = fs::metadata(&source_path.path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        if let Some(index) = self.flat_indexes.get(&source_path.mount_name) {
+            let fresh = index.built.elapsed().is_ok_and(|age| age < FLAT_INDEX_TTL);
+            if fresh && index.root_mtime == root_mtime {
+                return Arc::clone(&index);
+            }
+        }
+        self.build_flat_index(source_path, exclude_paths, root_mtime)
+    }
+
+    /// Assign each image of a flattened source a unique name and remember the mapping
+    ///
+    /// Images are named in walk order (sorted by path), the first one keeps its name and
+    /// later ones with the same stem get "~1", "~2", ... Stems are compared ignoring case
+    /// since lookups of converted names match them case-insensitively. Names are stable
+    /// as long as no image with the same stem is added earlier in the walk.
+    fn build_flat_index(
+        &self,
+        source_path: &SourcePath,
+        exclude_paths: &[&Path],
+        root_mtime: Option<SystemTime>,
+    ) -> Arc<FlatIndex> {
+        let mut taken_stems = HashSet::new();
+        let mut names = HashMap::new();
+        let mut by_stem = HashMap::new();
+        for path in self.discover_images(source_path, exclude_paths) {
+            let Some(stem) = path.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+            let mut flat_stem = stem.to_string();
+            let mut collisions = 0;
+            while !taken_stems.insert(flat_stem.to_lowercase()) {
+                collisions += 1;
+                flat_stem = format!("{stem}~{collisions}");
+            }
+            let ext = path.extension().and_then(|e| e.to_str());
+            let name = match ext {
+                Some(ext) => format!("{flat_stem}.{ext}"),
+                None => flat_stem.clone(),
+            };
+            // Only known formats have a converted name
+            if ext.and_then(ImageFormat::from_extension).is_some() {
+                by_stem.insert(flat_stem.to_lowercase(), name.clone());
+            }
+            names.insert(name, path);
+        }
+
+        let index = Arc::new(FlatIndex {
+            names,
+            by_stem,
+            root_mtime,
+            built: SystemTime::now(),
+        });
+        self.flat_indexes
+            .insert(source_path.mount_name.clone(), Arc::clone(&index));
+        index
+    }
+
+    /// Real path of a flat name of a flattened source
+    fn resolve_flat_name(
+        &self,
+        source_path: &SourcePath,
+        name: &str,
+        exclude_paths: &[&Path],
+    ) -> Option<PathBuf> {
+        let path = self.lookup_flat_name(&self.flat_index(source_path, exclude_paths), name)?;
+        if path.is_file() {
+            return Some(path);
+        }
+
+        // Removed since the index was built
+        let root_mtime = fs::metadata(&source_path.path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        let index = self.build_flat_index(source_path, exclude_paths, root_mtime);
+        self.lookup_flat_name(&index, name)
+    }
+
+    fn lookup_flat_name(&self, index: &FlatIndex, name: &str) -> Option<PathBuf> {
+        let name_path = Path::new(name);
+        if name_path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case(self.output_extension))
+        {
+            let stem = name_path.file_stem()?.to_str()?;
+            let flat_name = index.by_stem.get(&stem.to_lowercase())?;
+            return index.names.get(flat_name).cloned();
+        }
+        index
+            .names
+            .get(name)
+            .filter(|path| self.is_image_file(path))
+            .cloned()
+    }
+
+    fn get_display_names(&self, path: &Path, original_name: &str) -> Vec<String> {
         // Fast extension-only check for directory listings
         if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
             if let Some(format) = ImageFormat::from_extension(ext) {
                 if format.should_convert() {
                     if let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
-                        return format!("{stem}.heic");
+                        let ext = self.output_extension;
+                        if self.variant_suffixes.is_empty() {
+                            return vec![format!("{stem}.{ext}")];
+                        }
+                        return self
+                            .variant_suffixes
+                            .iter()
+                            .map(|suffix| format!("{stem}.{suffix}.{ext}"))
+                            .collect();
                     }
                 }
             }
         }
-        original_name.to_string()
+        vec![original_name.to_string()]
+    }
+
+    /// Split a configured variant suffix off a virtual file name
+    ///
+    /// "photo.web.heic" returns the plain virtual path ("photo.heic") and "web" if "web"
+    /// is one of the configured variant suffixes.
+    pub fn parse_variant_suffix<'a>(&'a self, virtual_path: &Path) -> Option<(PathBuf, &'a str)> {
+        let name = virtual_path.file_name()?.to_str()?;
+        let stem = name
+            .strip_suffix(self.output_extension)?
+            .strip_suffix('.')?;
+        let (base_stem, suffix) = stem.rsplit_once('.')?;
+        let suffix = self
+            .variant_suffixes
+            .iter()
+            .find(|configured| *configured == suffix)?;
+        if base_stem.is_empty() {
+            return None;
+        }
+
+        Some((
+            virtual_path.with_file_name(format!("{base_stem}.{}", self.output_extension)),
+            suffix,
+        ))
     }
 
+    /// Split an inline quality override off a virtual file name
+    ///
+    /// Accepts "photo@q80.heic" and "photo.heic?q=80"; returns the plain virtual path
+    /// ("photo.heic") and the requested quality, or None if there is no valid override.
+    pub fn parse_quality_suffix(virtual_path: &Path) -> Option<(PathBuf, u8)> {
+        let name = virtual_path.file_name()?.to_str()?;
+
+        let (base_name, quality_str) = if let Some((base, query)) = name.rsplit_once("?q=") {
+            (base.to_string(), query)
+        } else {
+            let (stem, ext) = name.rsplit_once('.')?;
+            let (base_stem, quality) = stem.rsplit_once("@q")?;
+            (format!("{base_stem}.{ext}"), quality)
+        };
+
+        let quality: u8 = quality_str.parse().ok()?;
+        if base_name.is_empty() || !(1..=100).contains(&quality) {
+            return None;
+        }
+
+        Some((virtual_path.with_file_name(base_name), quality))
+    }
+
+    /// Resolve a virtual file path to its source file, never looking inside
+    /// `exclude_paths` (e.g. the mount point) when a flattened source is re-indexed
     pub fn get_real_path(
         &self,
         virtual_path: &Path,
         source_paths: &[SourcePath],
+        exclude_paths: &[&Path],
     ) -> Option<PathBuf> {
         // Virtual path now starts with mount_name, e.g., "pictures/vacation/photo.heic"
         let mut components = virtual_path.components();
@@ -269,34 +719,35 @@ impl FileDetector {
         // Find the source path that matches this mount name
         for source_path in source_paths {
             if source_path.mount_name == mount_name {
+                if !source_path.recursive && relative_path.components().count() > 1 {
+                    return None;
+                }
+                if Self::is_under_excluded_dir(source_path, relative_path) {
+                    return None;
+                }
+                if source_path.flatten {
+                    if relative_path.components().count() != 1 {
+                        return None;
+                    }
+                    let name = relative_path.to_str()?;
+                    return self.resolve_flat_name(source_path, name, exclude_paths);
+                }
+
                 let base_path = source_path.path.join(relative_path);
                 log::trace!("get_real_path: base_path={base_path:?}");
 
                 // If requesting a .heic file, try to find the original with any supported extension
-                if virtual_path.extension().is_some_and(|ext| ext == "heic") {
+                if virtual_path
+                    .extension()
+                    .is_some_and(|ext| ext.eq_ignore_ascii_case(self.output_extension))
+                {
                     let stem = base_path.file_stem()?;
                     let parent = base_path.parent()?;
                     log::trace!("get_real_path: searching for stem={stem:?} in parent={parent:?}");
 
-                    // Scan directory to find matching file (handles case-insensitive extensions)
-                    if let Ok(entries) = std::fs::read_dir(parent) {
-                        for entry in entries.flatten() {
-                            let path = entry.path();
-                            if !path.is_file() {
-                                continue;
-                            }
-                            // Check if stem matches (case-sensitive for filename)
-                            if path.file_stem() != Some(stem) {
-                                continue;
-                            }
-                            // Check if extension is a supported image format
-                            if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
-                                if ImageFormat::from_extension(ext).is_some() {
-                                    log::trace!("get_real_path: found source file {path:?}");
-                                    return Some(path);
-                                }
-                            }
-                        }
+                    if let Some(path) = Self::find_source_for_stem(parent, stem) {
+                        log::trace!("get_real_path: found source file {path:?}");
+                        return Some(path);
                     }
                     log::trace!("get_real_path: no matching file found for {virtual_path:?}");
                 } else {
@@ -315,6 +766,72 @@ impl FileDetector {
     }
 }
 
+impl FileDetector {
+    /// Find the source image in `dir` whose stem matches `stem`
+    ///
+    /// Extensions match case-insensitively, and so does the stem when no exact match
+    /// exists (e.g. "PHOTO.JPG" for "photo.heic" on case-insensitive volumes). Exact
+    /// stem matches win, then the first name in sorted order, so the result does not
+    /// depend on directory iteration order.
+    fn find_source_for_stem(dir: &Path, stem: &std::ffi::OsStr) -> Option<PathBuf> {
+        let candidates: Vec<PathBuf> = std::fs::read_dir(dir)
+            .ok()?
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file())
+            .collect();
+        Self::best_stem_match(candidates.iter().map(PathBuf::as_path), stem).map(Path::to_path_buf)
+    }
+
+    /// The candidate image name matching `stem`, see `find_source_for_stem`
+    fn best_stem_match<'a>(
+        candidates: impl Iterator<Item = &'a Path>,
+        stem: &std::ffi::OsStr,
+    ) -> Option<&'a Path> {
+        let stem_str = stem.to_str();
+
+        candidates
+            .filter_map(|path| {
+                let ext = path.extension()?.to_str()?;
+                ImageFormat::from_extension(ext)?;
+
+                let candidate_stem = path.file_stem()?;
+                let inexact = if candidate_stem == stem {
+                    false
+                } else if stem_str.is_some_and(|s| {
+                    candidate_stem
+                        .to_str()
+                        .is_some_and(|c| c.to_lowercase() == s.to_lowercase())
+                }) {
+                    true
+                } else {
+                    return None;
+                };
+                Some((inexact, path))
+            })
+            .min()
+            .map(|(_, path)| path)
+    }
+}
+
+/// Minimal glob matching: "*" matches within a path component, "**" matches across
+/// components and "?" matches a single non-separator character
+fn glob_match(pattern: &str, text: &str) -> bool {
+    fn matches(pattern: &[u8], text: &[u8]) -> bool {
+        match pattern {
+            [] => text.is_empty(),
+            [b'*', b'*', rest @ ..] => (0..=text.len()).any(|i| matches(rest, &text[i..])),
+            [b'*', rest @ ..] => {
+                let segment_len = text.iter().position(|&c| c == b'/').unwrap_or(text.len());
+                (0..=segment_len).any(|i| matches(rest, &text[i..]))
+            }
+            [b'?', rest @ ..] => !text.is_empty() && text[0] != b'/' && matches(rest, &text[1..]),
+            [c, rest @ ..] => !text.is_empty() && text[0] == *c && matches(rest, &text[1..]),
+        }
+    }
+    matches(pattern.as_bytes(), text.as_bytes())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -352,4 +869,373 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_parse_quality_suffix() {
+        assert_eq!(
+            FileDetector::parse_quality_suffix(Path::new("pictures/photo@q80.heic")),
+            Some((PathBuf::from("pictures/photo.heic"), 80))
+        );
+        assert_eq!(
+            FileDetector::parse_quality_suffix(Path::new("pictures/photo.heic?q=35")),
+            Some((PathBuf::from("pictures/photo.heic"), 35))
+        );
+        assert_eq!(
+            FileDetector::parse_quality_suffix(Path::new("pictures/photo.heic")),
+            None
+        );
+        assert_eq!(
+            FileDetector::parse_quality_suffix(Path::new("pictures/photo@q0.heic")),
+            None
+        );
+        assert_eq!(
+            FileDetector::parse_quality_suffix(Path::new("pictures/photo@q101.heic")),
+            None
+        );
+    }
+
+    fn source(path: &Path, mount_name: &str) -> SourcePath {
+        SourcePath {
+            path: path.to_path_buf(),
+            recursive: true,
+            mount_name: mount_name.to_string(),
+            exclude_dirs: Vec::new(),
+            flatten: false,
+        }
+    }
+
+    #[test]
+    fn test_root_listing_is_sorted() -> Result<()> {
+        let detector = FileDetector::new(vec![])?;
+        let temp_dir = TempDir::new()?;
+        let pictures = temp_dir.path().join("Pictures");
+        let camera = temp_dir.path().join("DCIM");
+        fs::create_dir_all(&pictures)?;
+        fs::create_dir_all(&camera)?;
+
+        let sources = vec![source(&pictures, "pictures"), source(&camera, "camera")];
+        let entries =
+            detector.list_virtual_directory_with_exclusions(Path::new("/"), &sources, &[])?;
+
+        assert_eq!(
+            entries,
+            vec![("camera".to_string(), true), ("pictures".to_string(), true)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_root_listing_deduplicates_mount_names() -> Result<()> {
+        let detector = FileDetector::new(vec![])?;
+        let temp_dir = TempDir::new()?;
+        let pictures = temp_dir.path().join("Pictures");
+        let nested = pictures.join("2024");
+        fs::create_dir_all(&nested)?;
+
+        let sources = vec![source(&pictures, "pictures"), source(&nested, "pictures")];
+        let entries =
+            detector.list_virtual_directory_with_exclusions(Path::new("/"), &sources, &[])?;
+
+        assert_eq!(entries, vec![("pictures".to_string(), true)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_listing_renames_convertible_files() -> Result<()> {
+        let detector = FileDetector::new(vec![r".*\.(jpg|png)$".to_string()])?;
+        let temp_dir = TempDir::new()?;
+        fs::write(temp_dir.path().join("photo.jpg"), b"test")?;
+        fs::write(temp_dir.path().join("shot.png"), b"test")?;
+        fs::write(temp_dir.path().join("notes.txt"), b"test")?;
+
+        let sources = vec![source(temp_dir.path(), "pictures")];
+        let entries = detector.list_virtual_directory_with_exclusions(
+            Path::new("pictures"),
+            &sources,
+            &[],
+        )?;
+
+        assert_eq!(
+            entries,
+            vec![
+                ("photo.heic".to_string(), false),
+                ("shot.heic".to_string(), false)
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_flattened_source_lists_unique_names() -> Result<()> {
+        let detector = FileDetector::new(vec![r".*\.(jpg|png)$".to_string()])?;
+        let temp_dir = TempDir::new()?;
+        for file in ["a/photo.jpg", "b/c/PHOTO.jpg", "b/other.jpg", "b/photo.png"] {
+            let path = temp_dir.path().join(file);
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(path, b"fake")?;
+        }
+
+        let sources = vec![SourcePath {
+            flatten: true,
+            ..source(temp_dir.path(), "pictures")
+        }];
+        let list = || {
+            detector.list_virtual_directory_with_exclusions(Path::new("pictures"), &sources, &[])
+        };
+        let entries = list()?;
+        let names: Vec<&str> = entries.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec!["PHOTO~1.heic", "other.heic", "photo.heic", "photo~2.heic"]
+        );
+        assert!(entries.iter().all(|(_, is_directory)| !is_directory));
+        assert_eq!(list()?, entries);
+        assert!(!detector.is_virtual_directory(Path::new("pictures/a"), &sources));
+
+        for (name, file) in [
+            ("photo.heic", "a/photo.jpg"),
+            ("PHOTO~1.heic", "b/c/PHOTO.jpg"),
+            ("photo~2.heic", "b/photo.png"),
+            ("other.heic", "b/other.jpg"),
+        ] {
+            assert_eq!(
+                detector.get_real_path(&Path::new("pictures").join(name), &sources, &[]),
+                Some(temp_dir.path().join(file)),
+                "{name}"
+            );
+        }
+        assert_eq!(
+            detector.get_real_path(Path::new("pictures/b/photo.heic"), &sources, &[]),
+            None
+        );
+
+        // Listings and lookups, including misses, share one index until the root changes
+        let index = Arc::clone(&detector.flat_indexes.get("pictures").unwrap());
+        assert_eq!(list()?, entries);
+        assert_eq!(
+            detector.get_real_path(Path::new("pictures/missing.heic"), &sources, &[]),
+            None
+        );
+        assert!(Arc::ptr_eq(
+            &index,
+            &detector.flat_indexes.get("pictures").unwrap()
+        ));
+
+        fs::write(temp_dir.path().join("new.jpg"), b"fake")?;
+        fs::File::open(temp_dir.path())?
+            .set_modified(SystemTime::now() + Duration::from_secs(60))?;
+        assert!(list()?.contains(&("new.heic".to_string(), false)));
+        assert_eq!(
+            detector.get_real_path(Path::new("pictures/NEW.heic"), &sources, &[]),
+            Some(temp_dir.path().join("new.jpg"))
+        );
+
+        // A fresh detector rebuilds the mapping on lookup, without a listing first
+        let fresh = FileDetector::new(vec![r".*\.(jpg|png)$".to_string()])?;
+        assert_eq!(
+            fresh.get_real_path(Path::new("pictures/photo~2.heic"), &sources, &[]),
+            Some(temp_dir.path().join("b/photo.png"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_listing_excludes_mount_point() -> Result<()> {
+        let detector = FileDetector::new(vec![r".*\.jpg$".to_string()])?;
+        let temp_dir = TempDir::new()?;
+        let mount_point = temp_dir.path().join("mnt");
+        fs::create_dir_all(mount_point.join("inner"))?;
+        fs::create_dir_all(temp_dir.path().join("album"))?;
+
+        let sources = vec![source(temp_dir.path(), "pictures")];
+        let entries = detector.list_virtual_directory_with_exclusions(
+            Path::new("pictures"),
+            &sources,
+            &[&mount_point],
+        )?;
+        assert_eq!(entries, vec![("album".to_string(), true)]);
+
+        // Anything below the mount point is excluded as well
+        let entries = detector.list_virtual_directory_with_exclusions(
+            Path::new("pictures/mnt"),
+            &sources,
+            &[&mount_point],
+        )?;
+        assert!(entries.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_listing_subdirectory() -> Result<()> {
+        let detector = FileDetector::new(vec![r".*\.jpg$".to_string()])?;
+        let temp_dir = TempDir::new()?;
+        let album = temp_dir.path().join("vacation").join("day1");
+        fs::create_dir_all(&album)?;
+        fs::write(album.join("beach.jpg"), b"test")?;
+
+        let sources = vec![source(temp_dir.path(), "pictures")];
+        let entries = detector.list_virtual_directory_with_exclusions(
+            Path::new("pictures/vacation"),
+            &sources,
+            &[],
+        )?;
+        assert_eq!(entries, vec![("day1".to_string(), true)]);
+
+        let entries = detector.list_virtual_directory_with_exclusions(
+            Path::new("pictures/vacation/day1"),
+            &sources,
+            &[],
+        )?;
+        assert_eq!(entries, vec![("beach.heic".to_string(), false)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_listing_non_recursive_source_hides_directories() -> Result<()> {
+        let detector = FileDetector::new(vec![r".*\.jpg$".to_string()])?;
+        let temp_dir = TempDir::new()?;
+        fs::create_dir_all(temp_dir.path().join("sub"))?;
+        fs::write(temp_dir.path().join("top.jpg"), b"test")?;
+        fs::write(temp_dir.path().join("sub").join("deep.jpg"), b"test")?;
+
+        let mut downloads = source(temp_dir.path(), "downloads");
+        downloads.recursive = false;
+        let sources = vec![downloads];
+
+        let entries = detector.list_virtual_directory_with_exclusions(
+            Path::new("downloads"),
+            &sources,
+            &[],
+        )?;
+        assert_eq!(entries, vec![("top.heic".to_string(), false)]);
+        assert!(!detector.is_virtual_directory(Path::new("downloads/sub"), &sources));
+        assert!(detector
+            .get_real_path(Path::new("downloads/sub/deep.heic"), &sources, &[])
+            .is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(".thumbnails", ".thumbnails"));
+        assert!(glob_match("RAW*", "RAW_2024"));
+        assert!(!glob_match("RAW*", "raw"));
+        assert!(glob_match("20??/raw", "2024/raw"));
+        assert!(!glob_match("*/raw", "a/b/raw"));
+        assert!(glob_match("**/raw", "a/b/raw"));
+    }
+
+    #[test]
+    fn test_excluded_dirs_are_pruned() -> Result<()> {
+        let detector = FileDetector::new(vec![r".*\.jpg$".to_string()])?;
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+        for dir in [
+            "album",
+            "album/.thumbnails",
+            "RAW/deep",
+            "2024/raw",
+            "2024/keep",
+        ] {
+            fs::create_dir_all(root.join(dir))?;
+        }
+        for file in [
+            "top.jpg",
+            "album/a.jpg",
+            "album/.thumbnails/thumb.jpg",
+            "RAW/deep/r.jpg",
+            "2024/raw/x.jpg",
+            "2024/keep/k.jpg",
+        ] {
+            fs::write(root.join(file), b"test")?;
+        }
+
+        let mut pictures = source(root, "pictures");
+        pictures.exclude_dirs = vec![
+            ".thumbnails".to_string(),
+            "RAW".to_string(),
+            "2024/raw".to_string(),
+        ];
+
+        let found: Vec<PathBuf> = detector
+            .discover_images(&pictures, &[])
+            .into_iter()
+            .map(|p| p.strip_prefix(root).unwrap().to_path_buf())
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                PathBuf::from("2024/keep/k.jpg"),
+                PathBuf::from("album/a.jpg"),
+                PathBuf::from("top.jpg"),
+            ]
+        );
+
+        let sources = vec![pictures];
+        let entries = detector.list_virtual_directory_with_exclusions(
+            Path::new("pictures"),
+            &sources,
+            &[],
+        )?;
+        assert_eq!(
+            entries,
+            vec![
+                ("2024".to_string(), true),
+                ("album".to_string(), true),
+                ("top.heic".to_string(), false),
+            ]
+        );
+        let entries = detector.list_virtual_directory_with_exclusions(
+            Path::new("pictures/album"),
+            &sources,
+            &[],
+        )?;
+        assert_eq!(entries, vec![("a.heic".to_string(), false)]);
+        assert!(detector
+            .list_virtual_directory_with_exclusions(Path::new("pictures/RAW"), &sources, &[])?
+            .is_empty());
+        assert!(!detector.is_virtual_directory(Path::new("pictures/RAW/deep"), &sources));
+        assert!(detector
+            .get_real_path(
+                Path::new("pictures/album/.thumbnails/thumb.heic"),
+                &sources,
+                &[]
+            )
+            .is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_real_path_matches_case_insensitively() -> Result<()> {
+        let detector = FileDetector::new(vec![r"(?i).*\.(jpg|png)$".to_string()])?;
+        let temp_dir = TempDir::new()?;
+        let sources = vec![source(temp_dir.path(), "pictures")];
+        fs::write(temp_dir.path().join("PHOTO.JPG"), b"test")?;
+
+        for request in [
+            "pictures/photo.heic",
+            "pictures/PHOTO.heic",
+            "pictures/Photo.HEIC",
+        ] {
+            assert_eq!(
+                detector.get_real_path(Path::new(request), &sources, &[]),
+                Some(temp_dir.path().join("PHOTO.JPG")),
+                "{request}"
+            );
+        }
+
+        // An exact stem match wins, ties are broken by name rather than read_dir order
+        fs::write(temp_dir.path().join("photo.png"), b"test")?;
+        fs::write(temp_dir.path().join("photo.jpg"), b"test")?;
+        assert_eq!(
+            detector.get_real_path(Path::new("pictures/photo.heic"), &sources, &[]),
+            Some(temp_dir.path().join("photo.jpg"))
+        );
+        assert_eq!(
+            detector.get_real_path(Path::new("pictures/PHOTO.heic"), &sources, &[]),
+            Some(temp_dir.path().join("PHOTO.JPG"))
+        );
+        Ok(())
+    }
 }
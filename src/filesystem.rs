@@ -4,22 +4,187 @@ use dashmap::DashMap;
 use fuse3::raw::prelude::*;
 use fuse3::{Errno, FileType, Inode, Timestamp};
 use futures_util::stream::{self, BoxStream};
+use libheif_rs::CompressionFormat;
 use log::{debug, error, info, warn};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
 use std::ffi::OsStr;
 use std::num::NonZeroU32;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use zeroize::Zeroizing;
 
-use crate::cache::{create_cache_key_and_context_for_path, ImageCache};
-use crate::config::Config;
+use crate::cache::{create_cache_key_and_context_for_source, CacheContext, CachedData, ImageCache};
+use crate::config::{Config, HeicSettings, SizeEstimation, SourcePath};
 use crate::file_detector::FileDetector;
 use crate::image_converter;
 use crate::thread_pool::ConversionThreadPool;
+use crate::warm;
 
 const ROOT_INODE: u64 = 1;
 
+// Open reply flags from the FUSE protocol (fuse_kernel.h)
+const FOPEN_DIRECT_IO: u32 = 1 << 0;
+const FOPEN_KEEP_CACHE: u32 = 1 << 1;
+
+/// Suffix of the virtual file exposing the last conversion error of its base file
+const ERROR_SIDECAR_SUFFIX: &str = ".error";
+
+/// Suffix of the virtual file exposing the SHA-256 of its converted base file
+const CHECKSUM_SIDECAR_SUFFIX: &str = ".sha256";
+
+/// Size of a checksum sidecar: hex digest and a newline
+const CHECKSUM_SIDECAR_SIZE: u64 = 65;
+
+/// Name, inode and type of each entry of a listed virtual directory
+type DirectoryListing = Arc<Vec<(String, u64, FileType)>>;
+
+/// A listing kept in `listings`, with the listing version it was scanned at
+#[derive(Clone)]
+struct CachedListing {
+    version: SystemTime,
+    entries: DirectoryListing,
+}
+
+/// Concurrent map holding at most `capacity` entries, dropping the least recently used
+/// one when full; a capacity of 0 keeps nothing
+struct LruMap<K, V> {
+    entries: DashMap<K, (V, u64)>,
+    capacity: usize,
+    /// Incremented on each access, entries remember the value of their last one
+    clock: AtomicU64,
+}
+
+impl<K: std::hash::Hash + Eq + Clone, V: Clone> LruMap<K, V> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            entries: DashMap::new(),
+            capacity,
+            clock: AtomicU64::new(0),
+        }
+    }
+
+    fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: std::hash::Hash + Eq + ?Sized,
+    {
+        let mut entry = self.entries.get_mut(key)?;
+        entry.1 = self.clock.fetch_add(1, Ordering::Relaxed);
+        Some(entry.0.clone())
+    }
+
+    fn insert(&self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|entry| entry.1)
+                .map(|entry| entry.key().clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        let now = self.clock.fetch_add(1, Ordering::Relaxed);
+        self.entries.insert(key, (value, now));
+    }
+
+    fn remove<Q>(&self, key: &Q)
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: std::hash::Hash + Eq + ?Sized,
+    {
+        self.entries.remove(key);
+    }
+}
+
+/// Estimate the converted size of a file not in the cache with `size_estimation`
+fn estimate_file_size(
+    size_estimation: SizeEstimation,
+    thread_pool: &ConversionThreadPool,
+    real_path: &Path,
+    heic_settings: &HeicSettings,
+) -> Option<u64> {
+    match size_estimation {
+        SizeEstimation::Heuristic => None,
+        // Opened with direct_io, see open_flags, so a low estimate does not truncate
+        SizeEstimation::Proxy => {
+            image_converter::estimate_heic_size_from_proxy(real_path, heic_settings)
+                .inspect_err(|e| debug!("Size estimation failed for {real_path:?}: {e:#}"))
+                .ok()
+        }
+        // Holds the stat until a worker has converted the file, which also caches the
+        // result for the following read
+        SizeEstimation::Exact => thread_pool
+            .convert_image_blocking(real_path.to_path_buf(), heic_settings.clone())
+            .map(|data| data.len() as u64)
+            .inspect_err(|e| debug!("Conversion on stat failed for {real_path:?}: {e:#}"))
+            .ok(),
+    }
+}
+
+/// Run `f` while holding the slot of `key`, so concurrent callers for the same key
+/// wait for the first one instead of repeating its work
+fn single_flight<T>(
+    slots: &DashMap<String, Arc<Mutex<()>>>,
+    key: &str,
+    f: impl FnOnce() -> T,
+) -> T {
+    let slot = Arc::clone(&slots.entry(key.to_string()).or_default());
+    let result = {
+        let _guard = slot.lock();
+        f()
+    };
+    // Only the map and this caller still hold the slot: nobody is waiting for it
+    slots.remove_if(key, |_, slot| Arc::strong_count(slot) <= 2);
+    result
+}
+
+/// File in the cache directory recording the health of each source, shown by `stats`
+const SOURCE_STATUS_FILE_NAME: &str = "sources.yaml";
+
+/// Health of a source path, checked whenever a directory of it is listed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceStatus {
+    /// The source root could not be read on the last listing
+    pub degraded: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Read the source health recorded by a running (or the last) mount using `cache_dir`
+pub fn load_source_status(cache_dir: &Path) -> Result<BTreeMap<String, SourceStatus>> {
+    let status_path = cache_dir.join(SOURCE_STATUS_FILE_NAME);
+    if !status_path.exists() {
+        return Ok(BTreeMap::new());
+    }
+    let content = std::fs::read_to_string(&status_path)?;
+    Ok(serde_yaml::from_str(&content)?)
+}
+
+/// A regular file of the mount resolved to its source and the settings it is served with
+struct ResolvedFile {
+    real_path: PathBuf,
+    heic_settings: HeicSettings,
+    /// Serve the source bytes unconverted (original name with `show_originals`, or an
+    /// empty source that there is nothing to convert in)
+    passthrough: bool,
+}
+
+/// A file opened through `open`, with its cache key computed once for all reads
+struct OpenFile {
+    file: ResolvedFile,
+    cache_key: String,
+    context: CacheContext,
+}
+
 pub struct ImageFuseFS {
     config: Config,
     cache: Arc<ImageCache>,
@@ -30,23 +195,83 @@ pub struct ImageFuseFS {
     next_inode: AtomicU64,
     mount_point: PathBuf,
     ttl: Duration,
+    /// Last conversion error per source file, cleared once a conversion succeeds
+    conversion_errors: DashMap<PathBuf, String>,
+    /// Open file handles by `fh`; 0 is never allocated and means "resolve by inode"
+    open_files: DashMap<u64, Arc<OpenFile>>,
+    next_fh: AtomicU64,
+    /// Directory listings by virtual path, valid while their listing version is unchanged
+    /// Bounded by `fuse.listing_cache_entries`
+    listings: LruMap<PathBuf, CachedListing>,
+    /// Proxy size estimates by cache key, for files not converted yet, bounded by
+    /// `fuse.size_estimate_entries`
+    size_estimates: Arc<LruMap<String, u64>>,
+    /// Health of each source by mount name
+    source_status: DashMap<String, SourceStatus>,
+    /// Where source health is persisted, None in `secure_memory_only` mode
+    source_status_path: Option<PathBuf>,
+    /// Last listing of each directory whose sources were healthy, served while one of
+    /// them is degraded if `source_fallback_cache` is set
+    listing_snapshots: DashMap<PathBuf, DirectoryListing>,
+    /// Locks of the files being loaded by cache key, so concurrent reads of a file wait
+    /// for the first conversion instead of each starting one
+    loading: Arc<DashMap<String, Arc<Mutex<()>>>>,
 }
 
 impl ImageFuseFS {
     pub fn new(config: &Config, mount_point: PathBuf) -> Result<Self> {
+        let hevc_encoder = image_converter::check_encoder_available(CompressionFormat::Hevc);
+        Self::with_hevc_encoder(config, mount_point, hevc_encoder)
+    }
+
+    /// Like `new`, with the outcome of the HEVC encoder check passed in
+    fn with_hevc_encoder(
+        config: &Config,
+        mount_point: PathBuf,
+        hevc_encoder: Result<()>,
+    ) -> Result<Self> {
         info!("Initializing ImageFuseFS");
 
+        let mut config = config.clone();
+        image_converter::apply_encoder_fallback(&mut config, hevc_encoder)?;
+        let output_extension = config
+            .heic_settings
+            .active_fallback
+            .map_or("heic", |fallback| fallback.extension());
+
+        if let Some(temp_dir) = &config.conversion.temp_dir {
+            image_converter::prepare_temp_dir(temp_dir)?;
+        }
+
         let cache_dir = config.get_cache_dir_from_config()?;
-        let cache = ImageCache::new(
-            config.cache.max_size_mb,
-            cache_dir,
-            config.cache.enable_encryption,
-        )?;
+        let source_status_path =
+            (!config.cache.secure_memory_only).then(|| cache_dir.join(SOURCE_STATUS_FILE_NAME));
+        let cache = ImageCache::new(cache_dir, &config.cache)?;
 
         let num_workers = num_cpus::get();
-        let thread_pool = Arc::new(ConversionThreadPool::new(num_workers, Arc::clone(&cache)));
+        let thread_pool = Arc::new(ConversionThreadPool::new(
+            num_workers,
+            Arc::clone(&cache),
+            Duration::from_secs(config.conversion.drain_timeout_secs),
+        ));
+
+        if !config.cache.pinned_patterns.is_empty() {
+            let (config, mount_point) = (config.clone(), mount_point.clone());
+            let (cache, thread_pool) = (Arc::clone(&cache), Arc::clone(&thread_pool));
+            std::thread::spawn(move || {
+                if let Err(e) = warm::prefetch_pinned(&config, &mount_point, &cache, &thread_pool) {
+                    warn!("Failed to convert pinned files: {e:#}");
+                }
+            });
+        }
 
-        let file_detector = FileDetector::new(config.filename_patterns.clone())?;
+        let file_detector = FileDetector::new(config.filename_patterns.clone())?
+            .with_show_originals(config.fuse.show_originals)
+            .with_variant_suffixes(config.variants.iter().map(|v| v.suffix.clone()).collect())
+            .with_hide_empty_files(config.fuse.hide_empty_files)
+            .with_output_extension(output_extension)
+            .with_min_convert_bytes(config.heic_settings.min_convert_bytes)
+            .with_allowed_formats(&config.heic_settings.allowed_input_formats);
 
         let ttl = Duration::from_secs(config.fuse.cache_timeout);
         let inode_map = DashMap::new();
@@ -55,8 +280,10 @@ impl ImageFuseFS {
         inode_map.insert(ROOT_INODE, PathBuf::from("/"));
         path_map.insert(PathBuf::from("/"), ROOT_INODE);
 
+        let listings = LruMap::new(config.fuse.listing_cache_entries);
+        let size_estimates = Arc::new(LruMap::new(config.fuse.size_estimate_entries));
         let fs = Self {
-            config: config.clone(),
+            config,
             cache,
             thread_pool,
             file_detector,
@@ -65,6 +292,15 @@ impl ImageFuseFS {
             next_inode: AtomicU64::new(ROOT_INODE + 1),
             mount_point,
             ttl,
+            conversion_errors: DashMap::new(),
+            open_files: DashMap::new(),
+            next_fh: AtomicU64::new(1),
+            listings,
+            size_estimates,
+            source_status: DashMap::new(),
+            source_status_path,
+            listing_snapshots: DashMap::new(),
+            loading: Arc::new(DashMap::new()),
         };
 
         info!("ImageFuseFS initialized successfully");
@@ -90,8 +326,354 @@ impl ImageFuseFS {
     }
 
     fn get_real_path(&self, virtual_path: &Path) -> Option<PathBuf> {
-        self.file_detector
-            .get_real_path(virtual_path, &self.config.source_paths)
+        self.file_detector.get_real_path(
+            virtual_path,
+            &self.config.source_paths,
+            &[&self.mount_point],
+        )
+    }
+
+    /// Resolve a virtual file path, applying its variant settings and any inline
+    /// quality override
+    fn resolve_file(&self, virtual_path: &Path) -> Option<ResolvedFile> {
+        let (mut base_path, inline_quality) = match self
+            .config
+            .fuse
+            .quality_query
+            .then(|| FileDetector::parse_quality_suffix(virtual_path))
+            .flatten()
+        {
+            Some((base_path, quality)) => {
+                log::trace!("Inline quality {quality} requested for {base_path:?}");
+                (base_path, Some(quality))
+            }
+            None => (virtual_path.to_path_buf(), None),
+        };
+
+        let mut heic_settings = self.config.heic_settings.clone();
+        let mut real_path = None;
+        if let Some((variant_path, suffix)) = self.file_detector.parse_variant_suffix(&base_path) {
+            // A source whose own stem contains the suffix still resolves directly below
+            if let Some(variant_real_path) = self.get_real_path(&variant_path) {
+                let variant = self.config.variants.iter().find(|v| v.suffix == suffix)?;
+                heic_settings = variant.heic_settings.clone();
+                real_path = Some(variant_real_path);
+                base_path = variant_path;
+            }
+        }
+        let real_path = match real_path {
+            Some(real_path) => real_path,
+            None => self.get_real_path(&base_path)?,
+        };
+        heic_settings = image_converter::settings_for_source(&real_path, &heic_settings);
+
+        if let Some(quality) = inline_quality {
+            heic_settings.quality = quality;
+        }
+
+        let size = std::fs::metadata(&real_path).map_or(0, |m| m.len());
+        let is_empty = size == 0;
+        if is_empty && self.config.fuse.hide_empty_files {
+            return None;
+        }
+
+        // Sources below min_convert_bytes only exist under their original name
+        let below_threshold = self.file_detector.is_below_convert_threshold(size);
+        if below_threshold && real_path.file_name() != base_path.file_name() {
+            return None;
+        }
+
+        // Converted files are always exposed as ".heic" (or the fallback format's
+        // extension), any other name is the original
+        let passthrough = is_empty
+            || below_threshold
+            || (self.config.fuse.show_originals
+                && base_path
+                    .extension()
+                    .is_none_or(|ext| ext != self.file_detector.output_extension()));
+
+        Some(ResolvedFile {
+            real_path,
+            heic_settings,
+            passthrough,
+        })
+    }
+
+    /// Error text for an error sidecar path ("photo.heic.error"), only present for
+    /// files whose last conversion failed
+    fn resolve_error_sidecar(&self, virtual_path: &Path) -> Option<String> {
+        if !self.config.fuse.error_sidecars {
+            return None;
+        }
+
+        let base_path = virtual_path
+            .to_str()?
+            .strip_suffix(ERROR_SIDECAR_SUFFIX)
+            .map(PathBuf::from)?;
+        let file = self.resolve_file(&base_path)?;
+        self.conversion_errors
+            .get(&file.real_path)
+            .map(|error| error.clone())
+    }
+
+    /// Converted file behind a checksum sidecar path ("photo.heic.sha256")
+    fn resolve_checksum_sidecar(&self, virtual_path: &Path) -> Option<ResolvedFile> {
+        if !self.config.fuse.checksum_sidecars {
+            return None;
+        }
+
+        let base_path = virtual_path
+            .to_str()?
+            .strip_suffix(CHECKSUM_SIDECAR_SUFFIX)
+            .map(PathBuf::from)?;
+        self.resolve_file(&base_path)
+            .filter(|file| !file.passthrough)
+    }
+
+    /// Checksum sidecar text, converting the file only if it is not cached yet
+    fn checksum_sidecar_content(&self, file: ResolvedFile) -> fuse3::Result<String> {
+        let open_file = self.open_file(file);
+        let checksum = match self
+            .cache
+            .get_checksum_with_context(&open_file.cache_key, &open_file.context)
+        {
+            Some(checksum) => checksum,
+            None => Sha256::digest(self.load_open_file(&open_file)?.as_slice()).into(),
+        };
+        Ok(format!("{}\n", hex::encode(checksum)))
+    }
+
+    /// Compute the cache key and context a resolved file is read with
+    fn open_file(&self, file: ResolvedFile) -> OpenFile {
+        // Stat'ed on every open so an entry converted from a replaced source is not served
+        let (cache_key, context) =
+            create_cache_key_and_context_for_source(&file.real_path, &file.heic_settings);
+        OpenFile {
+            file,
+            cache_key,
+            context,
+        }
+    }
+
+    /// Exact size of a file when it is known without converting or estimating it: the
+    /// source size for files served as-is, else the size of the cached result
+    fn known_file_size(&self, file: &ResolvedFile) -> Option<u64> {
+        let (cache_key, context) =
+            create_cache_key_and_context_for_source(&file.real_path, &file.heic_settings);
+        // Served as-is once read, see load_open_file
+        if file.passthrough || !image_converter::is_convertible_format(&file.real_path) {
+            return Some(context.original_size);
+        }
+        self.cache.get_size_with_context(&cache_key, &context)
+    }
+
+    /// Size to report for a file: the exact size if known, otherwise the configured
+    /// `size_estimation`. Estimates run on the blocking pool, once per file at a time.
+    async fn resolve_file_size(&self, file: &ResolvedFile) -> u64 {
+        if let Some(size) = self.known_file_size(file) {
+            return size;
+        }
+        let (cache_key, context) =
+            create_cache_key_and_context_for_source(&file.real_path, &file.heic_settings);
+        let original_size = context.original_size;
+        let size_estimation = self.config.fuse.size_estimation;
+        if size_estimation == SizeEstimation::Heuristic {
+            return original_size;
+        }
+        if let Some(estimate) = self.size_estimates.get(&cache_key) {
+            return estimate;
+        }
+
+        let cache = Arc::clone(&self.cache);
+        let thread_pool = Arc::clone(&self.thread_pool);
+        let size_estimates = Arc::clone(&self.size_estimates);
+        let loading = Arc::clone(&self.loading);
+        let real_path = file.real_path.clone();
+        let heic_settings = file.heic_settings.clone();
+        let estimate = tokio::task::spawn_blocking(move || {
+            single_flight(&loading, &cache_key, || {
+                // Whoever held the slot before may have converted or estimated the file
+                if let Some(size) = cache.get_size_with_context(&cache_key, &context) {
+                    return size;
+                }
+                if let Some(estimate) = size_estimates.get(&cache_key) {
+                    return estimate;
+                }
+                estimate_file_size(size_estimation, &thread_pool, &real_path, &heic_settings)
+                    .inspect(|&estimate| {
+                        if size_estimation == SizeEstimation::Proxy {
+                            size_estimates.insert(cache_key.clone(), estimate);
+                        }
+                    })
+                    .unwrap_or(original_size)
+            })
+        })
+        .await;
+        estimate.unwrap_or(original_size)
+    }
+
+    fn read_open_file(
+        &self,
+        open_file: &OpenFile,
+        offset: u64,
+        size: u32,
+    ) -> fuse3::Result<ReplyData> {
+        let file = &open_file.file;
+        let real_path = &file.real_path;
+
+        if file.passthrough {
+            return Self::read_original(real_path, offset, size);
+        }
+
+        // Only a read from the start of a file signals moving on to it. Prefetching
+        // scans the directory and probes the cache, so it runs off the read path.
+        if offset == 0 && self.config.fuse.prefetch_count > 0 {
+            let thread_pool = Arc::clone(&self.thread_pool);
+            let heic_settings = self.config.heic_settings.clone();
+            let count = self.config.fuse.prefetch_count;
+            let real_path = real_path.clone();
+            tokio::task::spawn_blocking(move || {
+                Self::prefetch_next_files(&thread_pool, &real_path, count, heic_settings);
+            });
+        }
+
+        let data = self.load_open_file(open_file)?;
+
+        let end = std::cmp::min(offset as usize + size as usize, data.len());
+        let start = std::cmp::min(offset as usize, data.len());
+        log::trace!("Serving bytes {start}-{end} of {} total", data.len());
+
+        Ok(ReplyData {
+            data: Bytes::copy_from_slice(&data[start..end]),
+        })
+    }
+
+    /// Full content of a converted file, from the cache or by converting it now
+    fn load_open_file(&self, open_file: &OpenFile) -> fuse3::Result<CachedData> {
+        let OpenFile {
+            file,
+            cache_key,
+            context,
+        } = open_file;
+        let real_path = &file.real_path;
+
+        if let Some(cached_data) = self.cache.get_with_context(cache_key, context) {
+            log::trace!("Serving from cache: {real_path:?}");
+            return Ok(cached_data);
+        }
+
+        single_flight(&self.loading, cache_key, || {
+            // Whoever held the slot before may just have cached it
+            match self.cache.get_with_context(cache_key, context) {
+                Some(cached_data) => {
+                    log::trace!("Serving from cache after waiting: {real_path:?}");
+                    Ok(cached_data)
+                }
+                None => self
+                    .convert_open_file(open_file)
+                    .map(|data| CachedData::new(Zeroizing::new(data))),
+            }
+        })
+    }
+
+    /// Convert (or read, if not convertible) an opened file and cache the result
+    fn convert_open_file(&self, open_file: &OpenFile) -> fuse3::Result<Vec<u8>> {
+        let OpenFile {
+            file,
+            cache_key,
+            context,
+        } = open_file;
+        let real_path = &file.real_path;
+
+        let is_convertible = image_converter::is_convertible_format(real_path);
+        log::trace!("is_convertible_format({real_path:?}) = {is_convertible}");
+
+        let data = if is_convertible {
+            debug!("Converting image: {real_path:?}");
+            match self
+                .thread_pool
+                .convert_image_blocking(real_path.clone(), file.heic_settings.clone())
+            {
+                Ok(converted_data) => {
+                    // The conversion worker has already cached the result
+                    debug!("Conversion successful, {} bytes", converted_data.len());
+                    self.conversion_errors.remove(real_path);
+                    converted_data
+                }
+                Err(e) => {
+                    error!("Conversion failed for {real_path:?}: {e}");
+                    if self.config.fuse.error_sidecars {
+                        self.conversion_errors
+                            .insert(real_path.clone(), format!("{e:#}\n"));
+                    }
+                    return Err(Errno::from(libc::EIO));
+                }
+            }
+        } else {
+            match std::fs::read(real_path) {
+                Ok(original_data) => {
+                    if let Err(e) = self.cache.put_with_context(
+                        cache_key.clone(),
+                        original_data.clone(),
+                        context,
+                    ) {
+                        warn!("Failed to cache original file: {e}");
+                    }
+                    original_data
+                }
+                Err(e) => {
+                    error!("Failed to read file {real_path:?}: {e}");
+                    return Err(Errno::from(libc::EIO));
+                }
+            }
+        };
+
+        Ok(data)
+    }
+
+    /// Page cache behaviour requested from the kernel for opened files
+    fn open_flags(&self) -> u32 {
+        let mut flags = 0;
+        // The kernel does not read past the reported size without direct_io, and a
+        // proxy estimate can be lower than the converted size
+        if self.config.fuse.direct_io || self.config.fuse.size_estimation == SizeEstimation::Proxy {
+            flags |= FOPEN_DIRECT_IO;
+        }
+        if self.config.fuse.keep_cache {
+            flags |= FOPEN_KEEP_CACHE;
+        }
+        flags
+    }
+
+    /// Read a byte range straight from the source file, bypassing conversion and cache
+    fn read_original(real_path: &Path, offset: u64, size: u32) -> fuse3::Result<ReplyData> {
+        use std::io::{Read, Seek, SeekFrom};
+
+        let mut data = Vec::with_capacity(size.min(1024 * 1024) as usize);
+        let result = std::fs::File::open(real_path).and_then(|mut file| {
+            file.seek(SeekFrom::Start(offset))?;
+            file.take(size as u64).read_to_end(&mut data)
+        });
+
+        match result {
+            Ok(_) => Ok(ReplyData { data: data.into() }),
+            Err(e) => {
+                error!("Failed to read original file {real_path:?}: {e}");
+                Err(Errno::from(libc::EIO))
+            }
+        }
+    }
+
+    /// Refuse callers other than `restrict_to_uid` (and root if `allow_root`)
+    fn check_access(&self, req: &Request) -> fuse3::Result<()> {
+        let Some(uid) = self.config.fuse.restrict_to_uid else {
+            return Ok(());
+        };
+        if req.uid == uid || (req.uid == 0 && self.config.fuse.allow_root) {
+            return Ok(());
+        }
+        debug!("Denying request from uid {} (pid {})", req.uid, req.pid);
+        Err(Errno::from(libc::EACCES))
     }
 
     fn is_virtual_directory(&self, virtual_path: &Path) -> bool {
@@ -99,7 +681,12 @@ impl ImageFuseFS {
             .is_virtual_directory(virtual_path, &self.config.source_paths)
     }
 
-    fn prefetch_next_files(&self, current_real_path: &Path, count: usize) {
+    fn prefetch_next_files(
+        thread_pool: &ConversionThreadPool,
+        current_real_path: &Path,
+        count: usize,
+        heic_settings: HeicSettings,
+    ) {
         let Some(parent) = current_real_path.parent() else {
             return;
         };
@@ -111,10 +698,21 @@ impl ImageFuseFS {
             return;
         };
 
+        // Skip the files `resolve_real_path` serves as passthrough, they are never converted
+        let worth_converting = |size: u64| {
+            size > 0
+                && heic_settings
+                    .min_convert_bytes
+                    .is_none_or(|min| size >= min)
+        };
         let mut files: Vec<PathBuf> = entries
             .flatten()
             .map(|e| e.path())
-            .filter(|p| p.is_file() && image_converter::is_convertible_format(p))
+            .filter(|p| image_converter::is_convertible_format(p))
+            .filter(|p| {
+                p.metadata()
+                    .is_ok_and(|m| m.is_file() && worth_converting(m.len()))
+            })
             .collect();
         files.sort();
 
@@ -122,8 +720,8 @@ impl ImageFuseFS {
         if let Some(idx) = current_idx {
             for path in files.iter().skip(idx + 1).take(count) {
                 debug!("Prefetching: {path:?}");
-                self.thread_pool
-                    .prefetch(path.clone(), self.config.heic_settings.clone());
+                let heic_settings = image_converter::settings_for_source(path, &heic_settings);
+                thread_pool.prefetch(path.clone(), heic_settings);
             }
         }
     }
@@ -168,7 +766,133 @@ impl ImageFuseFS {
         }
     }
 
-    fn list_directory(&self, virtual_dir: &Path) -> Vec<(String, u64, FileType)> {
+    /// List a virtual directory, reusing the previous listing while the real directory
+    /// is unmodified so paging through a large directory scans it only once
+    fn list_directory(&self, virtual_dir: &Path) -> DirectoryListing {
+        // Every source for the root, else the one the directory belongs to
+        let mount_name = virtual_dir
+            .components()
+            .next()
+            .and_then(|c| c.as_os_str().to_str())
+            .filter(|_| virtual_dir != Path::new("/"));
+        let mut healthy = true;
+        for source in &self.config.source_paths {
+            if mount_name.is_none_or(|name| name == source.mount_name) {
+                healthy &= self.check_source_health(source);
+            }
+        }
+
+        let fallback = self.config.fuse.source_fallback_cache;
+        if !healthy && fallback {
+            if let Some(snapshot) = self.listing_snapshots.get(virtual_dir) {
+                debug!("Serving last known listing of {virtual_dir:?}");
+                return Arc::clone(&snapshot);
+            }
+        }
+
+        let listing = self.read_directory_listing(virtual_dir);
+        if healthy && fallback {
+            self.listing_snapshots
+                .insert(virtual_dir.to_path_buf(), Arc::clone(&listing));
+        }
+        listing
+    }
+
+    /// Stat the root of a source, recording whether it is degraded; true if healthy
+    fn check_source_health(&self, source: &SourcePath) -> bool {
+        let error = match std::fs::metadata(&source.path) {
+            Ok(metadata) if !metadata.is_dir() => Some("not a directory".to_string()),
+            Ok(_) => std::fs::read_dir(&source.path).err().map(|e| e.to_string()),
+            Err(e) => Some(e.to_string()),
+        };
+        let status = SourceStatus {
+            degraded: error.is_some(),
+            error,
+        };
+
+        let previous = self
+            .source_status
+            .insert(source.mount_name.clone(), status.clone());
+        if previous.as_ref() == Some(&status) {
+            return !status.degraded;
+        }
+
+        match &status.error {
+            Some(error) => warn!(
+                "Source {:?} ({:?}) is degraded: {error}",
+                source.mount_name, source.path
+            ),
+            None if previous.is_some() => {
+                info!("Source {:?} is readable again", source.mount_name)
+            }
+            None => {}
+        }
+        if let Err(e) = self.save_source_status() {
+            debug!("Failed to persist source status: {e}");
+        }
+        !status.degraded
+    }
+
+    /// Health of every source listed so far, by mount name
+    pub fn source_status(&self) -> BTreeMap<String, SourceStatus> {
+        self.source_status
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect()
+    }
+
+    fn save_source_status(&self) -> Result<()> {
+        let Some(status_path) = &self.source_status_path else {
+            return Ok(());
+        };
+        let content = serde_yaml::to_string(&self.source_status())?;
+        let tmp_path = status_path.with_extension("yaml.tmp");
+        std::fs::write(&tmp_path, content)?;
+        std::fs::rename(tmp_path, status_path)?;
+        Ok(())
+    }
+
+    /// Listing of a virtual directory, reused while its listing version is unchanged
+    fn read_directory_listing(&self, virtual_dir: &Path) -> DirectoryListing {
+        // A file growing out of being hidden or passed through changes its listed name
+        // without touching the directory mtime
+        let reusable = !self.config.fuse.hide_empty_files
+            && self.config.heic_settings.min_convert_bytes.is_none();
+        let version = reusable
+            .then(|| {
+                self.file_detector.listing_version(
+                    virtual_dir,
+                    &self.config.source_paths,
+                    &[&self.mount_point],
+                )
+            })
+            .flatten();
+
+        let Some(version) = version else {
+            // Not reusable, or the directory is gone: drop what was kept for it
+            self.listings.remove(virtual_dir);
+            return Arc::new(self.scan_directory(virtual_dir));
+        };
+
+        if let Some(cached) = self.listings.get(virtual_dir) {
+            if cached.version == version {
+                log::trace!("Reusing listing of {virtual_dir:?}");
+                return cached.entries;
+            }
+        }
+
+        let entries = Arc::new(self.scan_directory(virtual_dir));
+        self.listings.insert(
+            virtual_dir.to_path_buf(),
+            CachedListing {
+                version,
+                entries: Arc::clone(&entries),
+            },
+        );
+        entries
+    }
+
+    fn scan_directory(&self, virtual_dir: &Path) -> Vec<(String, u64, FileType)> {
         log::trace!("Listing directory: {virtual_dir:?}");
 
         let mut entries = Vec::new();
@@ -199,6 +923,20 @@ impl ImageFuseFS {
         log::trace!("Listed {} entries in {:?}", entries.len(), virtual_dir);
         entries
     }
+
+    /// "." and ".." entries that precede the listing of a directory
+    fn dot_entries(&self, inode: u64, virtual_path: &Path) -> Vec<(&'static str, u64)> {
+        let mut dot_entries = vec![(".", inode)];
+        if virtual_path != Path::new("/") {
+            let parent_inode = if let Some(parent_dir) = virtual_path.parent() {
+                self.get_or_create_inode(parent_dir)
+            } else {
+                ROOT_INODE
+            };
+            dot_entries.push(("..", parent_inode));
+        }
+        dot_entries
+    }
 }
 
 impl Filesystem for ImageFuseFS {
@@ -207,17 +945,27 @@ impl Filesystem for ImageFuseFS {
 
     async fn init(&self, _req: Request) -> fuse3::Result<ReplyInit> {
         info!("FUSE filesystem initialized");
-        Ok(ReplyInit {
-            max_write: NonZeroU32::new(1024 * 1024).unwrap(),
-        })
+        // Validated to be at least 4 KiB when the config is loaded
+        let max_write = NonZeroU32::new(self.config.fuse.max_write_kb.saturating_mul(1024))
+            .ok_or(Errno::from(libc::EINVAL))?;
+        debug!("Negotiating max_write of {max_write} bytes");
+        Ok(ReplyInit { max_write })
     }
 
     async fn destroy(&self, _req: Request) {
-        info!("FUSE filesystem destroyed");
+        self.cache.flush_stats();
+        let stats = self.cache.stats();
+        info!(
+            "FUSE filesystem destroyed, conversions saved {} of {} bytes ({:.1}%)",
+            stats.saved_bytes(),
+            stats.original_bytes,
+            stats.savings_ratio() * 100.0
+        );
     }
 
-    async fn lookup(&self, _req: Request, parent: Inode, name: &OsStr) -> fuse3::Result<ReplyEntry> {
+    async fn lookup(&self, req: Request, parent: Inode, name: &OsStr) -> fuse3::Result<ReplyEntry> {
         log::trace!("lookup: parent={parent}, name={name:?}");
+        self.check_access(&req)?;
 
         let parent_path = self
             .get_virtual_path(parent)
@@ -233,25 +981,35 @@ impl Filesystem for ImageFuseFS {
 
         log::trace!("Looking up virtual path: {virtual_path:?}");
 
-        if let Some(real_path) = self.get_real_path(&virtual_path) {
-            log::trace!("Found real path: {real_path:?}");
+        if let Some(error) = self.resolve_error_sidecar(&virtual_path) {
             let inode = self.get_or_create_inode(&virtual_path);
+            let attr = self.create_file_attr(inode, error.len() as u64, false);
 
-            let original_size = std::fs::metadata(&real_path).map(|m| m.len()).unwrap_or(0);
-            let (cache_key, context) = create_cache_key_and_context_for_path(
-                &real_path,
-                original_size,
-                &self.config.heic_settings,
-            );
-            let size = if let Some(cached_data) = self.cache.get_with_context(&cache_key, &context)
-            {
-                cached_data.len() as u64
-            } else {
-                original_size
-            };
+            return Ok(ReplyEntry {
+                ttl: self.ttl,
+                attr,
+                generation: 0,
+            });
+        }
+
+        if self.resolve_checksum_sidecar(&virtual_path).is_some() {
+            let inode = self.get_or_create_inode(&virtual_path);
+            let attr = self.create_file_attr(inode, CHECKSUM_SIDECAR_SIZE, false);
 
+            return Ok(ReplyEntry {
+                ttl: self.ttl,
+                attr,
+                generation: 0,
+            });
+        }
+
+        if let Some(file) = self.resolve_file(&virtual_path) {
+            log::trace!("Found real path: {:?}", file.real_path);
+            let inode = self.get_or_create_inode(&virtual_path);
+
+            let size = self.resolve_file_size(&file).await;
             let mut attr = self.create_file_attr(inode, size, false);
-            self.preserve_original_timestamps(&mut attr, &real_path);
+            self.preserve_original_timestamps(&mut attr, &file.real_path);
 
             return Ok(ReplyEntry {
                 ttl: self.ttl,
@@ -276,12 +1034,13 @@ impl Filesystem for ImageFuseFS {
 
     async fn getattr(
         &self,
-        _req: Request,
+        req: Request,
         inode: Inode,
         _fh: Option<u64>,
         _flags: u32,
     ) -> fuse3::Result<ReplyAttr> {
         log::trace!("getattr: ino={inode}");
+        self.check_access(&req)?;
 
         if inode == ROOT_INODE {
             let attr = self.create_file_attr(ROOT_INODE, 0, true);
@@ -295,22 +1054,26 @@ impl Filesystem for ImageFuseFS {
             .get_virtual_path(inode)
             .ok_or(Errno::from(libc::ENOENT))?;
 
-        if let Some(real_path) = self.get_real_path(&virtual_path) {
-            let original_size = std::fs::metadata(&real_path).map(|m| m.len()).unwrap_or(0);
-            let (cache_key, context) = create_cache_key_and_context_for_path(
-                &real_path,
-                original_size,
-                &self.config.heic_settings,
-            );
-            let size = if let Some(cached_data) = self.cache.get_with_context(&cache_key, &context)
-            {
-                cached_data.len() as u64
-            } else {
-                original_size
-            };
+        if let Some(error) = self.resolve_error_sidecar(&virtual_path) {
+            let attr = self.create_file_attr(inode, error.len() as u64, false);
+            return Ok(ReplyAttr {
+                ttl: self.ttl,
+                attr,
+            });
+        }
 
+        if self.resolve_checksum_sidecar(&virtual_path).is_some() {
+            let attr = self.create_file_attr(inode, CHECKSUM_SIDECAR_SIZE, false);
+            return Ok(ReplyAttr {
+                ttl: self.ttl,
+                attr,
+            });
+        }
+
+        if let Some(file) = self.resolve_file(&virtual_path) {
+            let size = self.resolve_file_size(&file).await;
             let mut attr = self.create_file_attr(inode, size, false);
-            self.preserve_original_timestamps(&mut attr, &real_path);
+            self.preserve_original_timestamps(&mut attr, &file.real_path);
 
             return Ok(ReplyAttr {
                 ttl: self.ttl,
@@ -331,116 +1094,97 @@ impl Filesystem for ImageFuseFS {
 
     async fn read(
         &self,
-        _req: Request,
+        req: Request,
         inode: Inode,
-        _fh: u64,
+        fh: u64,
         offset: u64,
         size: u32,
     ) -> fuse3::Result<ReplyData> {
-        log::trace!("read: ino={inode}, offset={offset}, size={size}");
+        log::trace!("read: ino={inode}, fh={fh}, offset={offset}, size={size}");
+        self.check_access(&req)?;
+
+        if let Some(open_file) = self.open_files.get(&fh).map(|f| Arc::clone(&f)) {
+            return self.read_open_file(&open_file, offset, size);
+        }
 
         let virtual_path = self
             .get_virtual_path(inode)
             .ok_or(Errno::from(libc::ENOENT))?;
 
-        let real_path = self
-            .get_real_path(&virtual_path)
-            .ok_or(Errno::from(libc::ENOENT))?;
-
-        if self.config.fuse.prefetch_count > 0 {
-            self.prefetch_next_files(&real_path, self.config.fuse.prefetch_count);
+        if let Some(error) = self.resolve_error_sidecar(&virtual_path) {
+            let end = std::cmp::min(offset as usize + size as usize, error.len());
+            let start = std::cmp::min(offset as usize, error.len());
+            return Ok(ReplyData {
+                data: Bytes::copy_from_slice(&error.as_bytes()[start..end]),
+            });
         }
 
-        let original_size = std::fs::metadata(&real_path).map(|m| m.len()).unwrap_or(0);
-        let (cache_key, context) = create_cache_key_and_context_for_path(
-            &real_path,
-            original_size,
-            &self.config.heic_settings,
-        );
-
-        if let Some(cached_data) = self.cache.get_with_context(&cache_key, &context) {
-            log::trace!("Serving from cache: {real_path:?}");
-            let end = std::cmp::min(offset as usize + size as usize, cached_data.len());
-            let start = std::cmp::min(offset as usize, cached_data.len());
-            log::trace!(
-                "Serving cached bytes {start}-{end} of {} total",
-                cached_data.len()
-            );
+        if let Some(file) = self.resolve_checksum_sidecar(&virtual_path) {
+            let checksum = self.checksum_sidecar_content(file)?;
+            let end = std::cmp::min(offset as usize + size as usize, checksum.len());
+            let start = std::cmp::min(offset as usize, checksum.len());
             return Ok(ReplyData {
-                data: Bytes::copy_from_slice(&cached_data[start..end]),
+                data: Bytes::copy_from_slice(&checksum.as_bytes()[start..end]),
             });
         }
 
-        let is_convertible = image_converter::is_convertible_format(&real_path);
-        log::trace!("is_convertible_format({real_path:?}) = {is_convertible}");
-
-        let data = if is_convertible {
-            debug!("Converting image: {real_path:?}");
-            match self
-                .thread_pool
-                .convert_image_blocking(real_path.clone(), self.config.heic_settings.clone())
-            {
-                Ok(converted_data) => {
-                    debug!(
-                        "Conversion successful, {} bytes, caching result",
-                        converted_data.len()
-                    );
-                    if let Err(e) =
-                        self.cache
-                            .put_with_context(cache_key, converted_data.clone(), &context)
-                    {
-                        warn!("Failed to cache converted image: {e}");
-                    }
-                    converted_data
-                }
-                Err(e) => {
-                    error!("Conversion failed for {real_path:?}: {e}");
-                    return Err(Errno::from(libc::EIO));
-                }
-            }
-        } else {
-            match std::fs::read(&real_path) {
-                Ok(original_data) => {
-                    if let Err(e) =
-                        self.cache
-                            .put_with_context(cache_key, original_data.clone(), &context)
-                    {
-                        warn!("Failed to cache original file: {e}");
-                    }
-                    original_data
-                }
-                Err(e) => {
-                    error!("Failed to read file {real_path:?}: {e}");
-                    return Err(Errno::from(libc::EIO));
-                }
-            }
-        };
-
-        let end = std::cmp::min(offset as usize + size as usize, data.len());
-        let start = std::cmp::min(offset as usize, data.len());
-        log::trace!("Serving bytes {start}-{end} of {} total", data.len());
-
-        Ok(ReplyData {
-            data: Bytes::copy_from_slice(&data[start..end]),
-        })
+        let file = self
+            .resolve_file(&virtual_path)
+            .ok_or(Errno::from(libc::ENOENT))?;
+        self.read_open_file(&self.open_file(file), offset, size)
     }
 
-    async fn open(&self, _req: Request, inode: Inode, _flags: u32) -> fuse3::Result<ReplyOpen> {
+    async fn open(&self, req: Request, inode: Inode, _flags: u32) -> fuse3::Result<ReplyOpen> {
         log::trace!("open: ino={inode}");
+        self.check_access(&req)?;
 
         let virtual_path = self
             .get_virtual_path(inode)
             .ok_or(Errno::from(libc::ENOENT))?;
 
-        if self.get_real_path(&virtual_path).is_some() {
-            Ok(ReplyOpen { fh: 0, flags: 0 })
-        } else {
-            Err(Errno::from(libc::ENOENT))
+        // Sidecars are small and change with each conversion, read them by inode
+        if self.resolve_error_sidecar(&virtual_path).is_some()
+            || self.resolve_checksum_sidecar(&virtual_path).is_some()
+        {
+            return Ok(ReplyOpen {
+                fh: 0,
+                flags: self.open_flags(),
+            });
+        }
+
+        let file = self
+            .resolve_file(&virtual_path)
+            .ok_or(Errno::from(libc::ENOENT))?;
+        let open_file = self.open_file(file);
+        if self.config.fuse.convert_on_open && !open_file.file.passthrough {
+            self.load_open_file(&open_file)?;
         }
+        let fh = self.next_fh.fetch_add(1, Ordering::Relaxed);
+        self.open_files.insert(fh, Arc::new(open_file));
+
+        Ok(ReplyOpen {
+            fh,
+            flags: self.open_flags(),
+        })
     }
 
-    async fn opendir(&self, _req: Request, inode: Inode, _flags: u32) -> fuse3::Result<ReplyOpen> {
+    async fn release(
+        &self,
+        _req: Request,
+        inode: Inode,
+        fh: u64,
+        _flags: u32,
+        _lock_owner: u64,
+        _flush: bool,
+    ) -> fuse3::Result<()> {
+        log::trace!("release: ino={inode}, fh={fh}");
+        self.open_files.remove(&fh);
+        Ok(())
+    }
+
+    async fn opendir(&self, req: Request, inode: Inode, _flags: u32) -> fuse3::Result<ReplyOpen> {
         log::trace!("opendir: ino={inode}");
+        self.check_access(&req)?;
 
         if inode == ROOT_INODE {
             return Ok(ReplyOpen { fh: 0, flags: 0 });
@@ -459,56 +1203,36 @@ impl Filesystem for ImageFuseFS {
 
     async fn readdir<'a>(
         &'a self,
-        _req: Request,
+        req: Request,
         parent: Inode,
         _fh: u64,
         offset: i64,
     ) -> fuse3::Result<ReplyDirectory<Self::DirEntryStream<'a>>> {
         log::trace!("readdir: ino={parent}, offset={offset}");
+        self.check_access(&req)?;
 
         let virtual_path = self
             .get_virtual_path(parent)
             .ok_or(Errno::from(libc::ENOENT))?;
 
+        let dot_entries = self.dot_entries(parent, &virtual_path);
         let entries = self.list_directory(&virtual_path);
 
-        let mut all_entries: Vec<fuse3::Result<DirectoryEntry>> = Vec::new();
-        let mut index = 0i64;
-
-        all_entries.push(Ok(DirectoryEntry {
-            inode: parent,
-            kind: FileType::Directory,
-            name: ".".into(),
-            offset: index + 1,
-        }));
-        index += 1;
-
-        if virtual_path != Path::new("/") {
-            let parent_inode = if let Some(parent_dir) = virtual_path.parent() {
-                self.get_or_create_inode(parent_dir)
-            } else {
-                ROOT_INODE
+        // Entry offsets are 1-based positions, so `offset` is the number of entries to skip
+        let total = dot_entries.len() + entries.len();
+        let start = (offset.max(0) as usize).min(total);
+        let stream = stream::iter((start..total).map(move |index| {
+            let (name, inode, kind) = match dot_entries.get(index) {
+                Some(&(name, inode)) => (name.to_string(), inode, FileType::Directory),
+                None => entries[index - dot_entries.len()].clone(),
             };
-            all_entries.push(Ok(DirectoryEntry {
-                inode: parent_inode,
-                kind: FileType::Directory,
-                name: "..".into(),
-                offset: index + 1,
-            }));
-            index += 1;
-        }
-
-        for (name, entry_inode, file_type) in entries {
-            all_entries.push(Ok(DirectoryEntry {
-                inode: entry_inode,
-                kind: file_type,
+            Ok(DirectoryEntry {
+                inode,
+                kind,
                 name: name.into(),
-                offset: index + 1,
-            }));
-            index += 1;
-        }
-
-        let stream = stream::iter(all_entries.into_iter().skip(offset as usize));
+                offset: index as i64 + 1,
+            })
+        }));
 
         Ok(ReplyDirectory {
             entries: Box::pin(stream),
@@ -517,94 +1241,942 @@ impl Filesystem for ImageFuseFS {
 
     async fn readdirplus<'a>(
         &'a self,
-        _req: Request,
+        req: Request,
         parent: Inode,
         _fh: u64,
         offset: u64,
         _lock_owner: u64,
     ) -> fuse3::Result<ReplyDirectoryPlus<Self::DirEntryPlusStream<'a>>> {
         log::trace!("readdirplus: ino={parent}, offset={offset}");
+        self.check_access(&req)?;
 
         let virtual_path = self
             .get_virtual_path(parent)
             .ok_or(Errno::from(libc::ENOENT))?;
 
+        let dot_entries = self.dot_entries(parent, &virtual_path);
         let entries = self.list_directory(&virtual_path);
 
-        let mut all_entries: Vec<fuse3::Result<DirectoryEntryPlus>> = Vec::new();
-        let mut index = 0u64;
-
-        // Add "."
-        let dot_attr = self.create_file_attr(parent, 0, true);
-        all_entries.push(Ok(DirectoryEntryPlus {
-            inode: parent,
-            generation: 0,
-            kind: FileType::Directory,
-            name: ".".into(),
-            offset: (index + 1) as i64,
-            attr: dot_attr,
-            entry_ttl: self.ttl,
-            attr_ttl: self.ttl,
-        }));
-        index += 1;
-
-        // Add ".."
-        if virtual_path != Path::new("/") {
-            let parent_inode = if let Some(parent_dir) = virtual_path.parent() {
-                self.get_or_create_inode(parent_dir)
-            } else {
-                ROOT_INODE
+        // Attributes are only looked up for the entries actually returned
+        let total = dot_entries.len() + entries.len();
+        let start = (offset as usize).min(total);
+        let stream = stream::iter((start..total).map(move |index| {
+            let (name, inode, kind) = match dot_entries.get(index) {
+                Some(&(name, inode)) => (name.to_string(), inode, FileType::Directory),
+                None => entries[index - dot_entries.len()].clone(),
             };
-            let dotdot_attr = self.create_file_attr(parent_inode, 0, true);
-            all_entries.push(Ok(DirectoryEntryPlus {
-                inode: parent_inode,
-                generation: 0,
-                kind: FileType::Directory,
-                name: "..".into(),
-                offset: (index + 1) as i64,
-                attr: dotdot_attr,
-                entry_ttl: self.ttl,
-                attr_ttl: self.ttl,
-            }));
-            index += 1;
-        }
 
-        for (name, entry_inode, file_type) in entries {
-            let is_dir = file_type == FileType::Directory;
-            let mut attr = self.create_file_attr(entry_inode, 0, is_dir);
+            let is_dir = kind == FileType::Directory;
+            let mut attr = self.create_file_attr(inode, 0, is_dir);
+            let mut attr_ttl = self.ttl;
 
-            // For files, try to get size from cache or real file
+            // Only exact sizes may be cached by the kernel, lookup and getattr apply
+            // size_estimation to the others
             if !is_dir {
                 let entry_virtual_path = if virtual_path == Path::new("/") {
                     PathBuf::from(&name)
                 } else {
                     virtual_path.join(&name)
                 };
-                if let Some(real_path) = self.get_real_path(&entry_virtual_path) {
-                    let original_size = std::fs::metadata(&real_path).map(|m| m.len()).unwrap_or(0);
-                    attr.size = original_size;
-                    attr.blocks = original_size.div_ceil(512);
-                    self.preserve_original_timestamps(&mut attr, &real_path);
+                let file = self.resolve_file(&entry_virtual_path);
+                match file.as_ref().and_then(|file| self.known_file_size(file)) {
+                    Some(size) => {
+                        attr.size = size;
+                        attr.blocks = size.div_ceil(512);
+                    }
+                    None => attr_ttl = Duration::ZERO,
+                }
+                if let Some(file) = &file {
+                    self.preserve_original_timestamps(&mut attr, &file.real_path);
                 }
             }
 
-            all_entries.push(Ok(DirectoryEntryPlus {
-                inode: entry_inode,
+            Ok(DirectoryEntryPlus {
+                inode,
                 generation: 0,
-                kind: file_type,
+                kind,
                 name: name.into(),
-                offset: (index + 1) as i64,
+                offset: index as i64 + 1,
                 attr,
                 entry_ttl: self.ttl,
-                attr_ttl: self.ttl,
-            }));
-            index += 1;
-        }
-
-        let stream = stream::iter(all_entries.into_iter().skip(offset as usize));
+                attr_ttl,
+            })
+        }));
 
         Ok(ReplyDirectoryPlus {
             entries: Box::pin(stream),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::create_cache_key;
+    use crate::config::{CacheSettings, SourcePath, Variant};
+    use image::{DynamicImage, ImageFormat as ImageCrateFormat};
+    use tempfile::TempDir;
+
+    const TEST_REQUEST: Request = Request {
+        unique: 0,
+        uid: 0,
+        gid: 0,
+        pid: 0,
+    };
+
+    struct TestMount {
+        temp_dir: TempDir,
+        config: Config,
+    }
+
+    impl TestMount {
+        fn new() -> Result<Self> {
+            let temp_dir = TempDir::new()?;
+            let source_dir = temp_dir.path().join("source");
+            std::fs::create_dir_all(&source_dir)?;
+
+            let config = Config {
+                mount_point: temp_dir.path().join("mnt"),
+                source_paths: vec![SourcePath {
+                    path: source_dir,
+                    recursive: true,
+                    mount_name: "pictures".to_string(),
+                    exclude_dirs: Vec::new(),
+                    flatten: false,
+                }],
+                cache: CacheSettings {
+                    cache_dir: Some(temp_dir.path().join("cache")),
+                    ..CacheSettings::default()
+                },
+                ..Config::default()
+            };
+
+            Ok(Self { temp_dir, config })
+        }
+
+        fn source_dir(&self) -> PathBuf {
+            self.temp_dir.path().join("source")
+        }
+
+        fn cache_dir(&self) -> PathBuf {
+            self.temp_dir.path().join("cache")
+        }
+
+        fn write_jpeg(&self, name: &str) -> Result<PathBuf> {
+            let path = self.source_dir().join(name);
+            let mut img = image::RgbImage::new(256, 256);
+            for (x, y, pixel) in img.enumerate_pixels_mut() {
+                *pixel = image::Rgb([
+                    ((x * y) % 256) as u8,
+                    ((x * 3 + y) % 256) as u8,
+                    ((y * 7) % 256) as u8,
+                ]);
+            }
+            DynamicImage::ImageRgb8(img).save_with_format(&path, ImageCrateFormat::Jpeg)?;
+            Ok(path)
+        }
+
+        fn mount(&self) -> Result<ImageFuseFS> {
+            ImageFuseFS::new(&self.config, self.config.mount_point.clone())
+        }
+
+        /// Number of entries stored in the disk cache shards
+        fn cache_entry_count(&self) -> usize {
+            walkdir::WalkDir::new(self.cache_dir())
+                .min_depth(2)
+                .into_iter()
+                .flatten()
+                .filter(|e| e.file_type().is_file())
+                .count()
+        }
+    }
+
+    async fn lookup_path(fs: &ImageFuseFS, virtual_path: &str) -> fuse3::Result<ReplyEntry> {
+        let mut parent = ROOT_INODE;
+        let mut reply = None;
+        for name in virtual_path.split('/') {
+            let entry = fs.lookup(TEST_REQUEST, parent, OsStr::new(name)).await?;
+            parent = entry.attr.ino;
+            reply = Some(entry);
+        }
+        reply.ok_or(Errno::from(libc::ENOENT))
+    }
+
+    async fn read_all(fs: &ImageFuseFS, inode: u64) -> fuse3::Result<Vec<u8>> {
+        let reply = fs.read(TEST_REQUEST, inode, 0, 0, u32::MAX).await?;
+        Ok(reply.data.to_vec())
+    }
+
+    #[tokio::test]
+    async fn test_inline_quality_caches_separately() -> Result<()> {
+        let mut mount = TestMount::new()?;
+        mount.config.fuse.quality_query = true;
+        mount.config.fuse.prefetch_count = 0;
+        mount.write_jpeg("photo.jpg")?;
+        let fs = mount.mount()?;
+
+        let low = lookup_path(&fs, "pictures/photo@q10.heic").await?;
+        let high = lookup_path(&fs, "pictures/photo.heic?q=90").await?;
+        assert_ne!(low.attr.ino, high.attr.ino);
+
+        let low_data = read_all(&fs, low.attr.ino).await?;
+        let high_data = read_all(&fs, high.attr.ino).await?;
+        assert!(
+            low_data.len() < high_data.len(),
+            "q10 ({}) should be smaller than q90 ({})",
+            low_data.len(),
+            high_data.len()
+        );
+        assert_eq!(mount.cache_entry_count(), 2);
+
+        // The plain name still uses the configured quality and gets its own entry
+        let base = lookup_path(&fs, "pictures/photo.heic").await?;
+        read_all(&fs, base.attr.ino).await?;
+        assert_eq!(mount.cache_entry_count(), 3);
+
+        // Once cached, lookups report the size of each variant
+        let low = lookup_path(&fs, "pictures/photo@q10.heic").await?;
+        assert_eq!(low.attr.size, low_data.len() as u64);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_show_originals_serves_both_names() -> Result<()> {
+        let mut mount = TestMount::new()?;
+        mount.config.fuse.show_originals = true;
+        mount.config.fuse.prefetch_count = 0;
+        let source = mount.write_jpeg("photo.jpg")?;
+        let fs = mount.mount()?;
+
+        let names: Vec<String> = fs
+            .list_directory(Path::new("pictures"))
+            .iter()
+            .map(|(name, _, _)| name.clone())
+            .collect();
+        assert_eq!(names, vec!["photo.heic", "photo.jpg"]);
+
+        let original = lookup_path(&fs, "pictures/photo.jpg").await?;
+        let original_bytes = std::fs::read(&source)?;
+        assert_eq!(original.attr.size, original_bytes.len() as u64);
+        assert_eq!(read_all(&fs, original.attr.ino).await?, original_bytes);
+        // Serving the original must not populate the cache
+        assert_eq!(mount.cache_entry_count(), 0);
+
+        let converted = lookup_path(&fs, "pictures/photo.heic").await?;
+        let converted_bytes = read_all(&fs, converted.attr.ino).await?;
+        assert_eq!(
+            &converted_bytes[4..8],
+            b"ftyp",
+            "expected an ISOBMFF/HEIF file"
+        );
+        assert_eq!(mount.cache_entry_count(), 1);
+
+        let converted = lookup_path(&fs, "pictures/photo.heic").await?;
+        assert_eq!(converted.attr.size, converted_bytes.len() as u64);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_paging_large_directory_scans_once() -> Result<()> {
+        use futures_util::StreamExt;
+
+        let mount = TestMount::new()?;
+        for i in 0..300 {
+            std::fs::write(mount.source_dir().join(format!("img_{i:04}.jpg")), b"test")?;
+        }
+        let fs = mount.mount()?;
+        let dir = lookup_path(&fs, "pictures").await?.attr.ino;
+
+        // Every page must come from the same cached listing, a rescan replaces it
+        async fn page_through(
+            fs: &ImageFuseFS,
+            dir: u64,
+        ) -> fuse3::Result<(Vec<String>, DirectoryListing)> {
+            let mut names = Vec::new();
+            let mut offset = 0;
+            let mut listing: Option<DirectoryListing> = None;
+            loop {
+                let reply = fs.readdir(TEST_REQUEST, dir, 0, offset).await?;
+                let cached = Arc::clone(&fs.listings.get(Path::new("pictures")).unwrap().entries);
+                match &listing {
+                    Some(listing) => assert!(Arc::ptr_eq(listing, &cached), "rescanned"),
+                    None => listing = Some(cached),
+                }
+
+                let page: Vec<DirectoryEntry> = reply
+                    .entries
+                    .take(64)
+                    .collect::<Vec<_>>()
+                    .await
+                    .into_iter()
+                    .collect::<fuse3::Result<_>>()?;
+                let Some(last) = page.last() else {
+                    return Ok((names, listing.unwrap()));
+                };
+                offset = last.offset;
+                names.extend(page.iter().map(|e| e.name.to_string_lossy().into_owned()));
+            }
+        }
+
+        let (names, listing) = page_through(&fs, dir).await?;
+        assert_eq!(names.len(), 302);
+        assert_eq!(&names[..3], [".", "..", "img_0000.heic"]);
+        assert_eq!(listing.len(), 300);
+
+        // Modifying the directory invalidates the listing
+        std::fs::write(mount.source_dir().join("new.jpg"), b"test")?;
+        std::fs::File::open(mount.source_dir())?
+            .set_modified(SystemTime::now() + Duration::from_secs(1))?;
+        let (names, rescanned) = page_through(&fs, dir).await?;
+        assert!(names.contains(&"new.heic".to_string()));
+        assert!(!Arc::ptr_eq(&listing, &rescanned));
+        Ok(())
+    }
+
+    #[test]
+    fn test_lru_map_drops_least_recently_used() {
+        let map = LruMap::new(2);
+        map.insert("a".to_string(), 1);
+        map.insert("b".to_string(), 2);
+        assert_eq!(map.get("a"), Some(1));
+        map.insert("c".to_string(), 3);
+        assert_eq!(map.get("b"), None);
+        assert_eq!(map.get("a"), Some(1));
+        assert_eq!(map.get("c"), Some(3));
+
+        let disabled = LruMap::new(0);
+        disabled.insert("a".to_string(), 1);
+        assert_eq!(disabled.get("a"), None);
+    }
+
+    #[test]
+    fn test_listing_cache_is_bounded() -> Result<()> {
+        let mut mount = TestMount::new()?;
+        mount.config.fuse.listing_cache_entries = 2;
+        for dir in ["a", "b", "c"] {
+            std::fs::create_dir(mount.source_dir().join(dir))?;
+        }
+        let fs = mount.mount()?;
+        let kept = || {
+            let mut kept: Vec<PathBuf> = fs
+                .listings
+                .entries
+                .iter()
+                .map(|e| e.key().clone())
+                .collect();
+            kept.sort();
+            kept
+        };
+
+        fs.list_directory(Path::new("pictures/a"));
+        fs.list_directory(Path::new("pictures/b"));
+        fs.list_directory(Path::new("pictures/a"));
+        fs.list_directory(Path::new("pictures/c"));
+        assert_eq!(kept(), [Path::new("pictures/a"), Path::new("pictures/c")]);
+
+        // A listing of a directory that is gone is dropped
+        std::fs::remove_dir(mount.source_dir().join("c"))?;
+        assert!(fs.list_directory(Path::new("pictures/c")).is_empty());
+        assert_eq!(kept(), [Path::new("pictures/a")]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_jpeg_fallback_without_hevc_encoder() -> Result<()> {
+        let mut mount = TestMount::new()?;
+        mount.config.fuse.prefetch_count = 0;
+        DynamicImage::ImageRgb8(image::RgbImage::from_fn(256, 256, |x, y| {
+            image::Rgb([x as u8, y as u8, 128])
+        }))
+        .save_with_format(mount.source_dir().join("photo.png"), ImageCrateFormat::Png)?;
+        let mount_without_encoder = |mount: &TestMount| {
+            ImageFuseFS::with_hevc_encoder(
+                &mount.config,
+                mount.config.mount_point.clone(),
+                Err(anyhow::anyhow!(
+                    "The linked libheif has no Hevc encoder (simulated)"
+                )),
+            )
+        };
+        assert!(
+            mount_without_encoder(&mount).is_err(),
+            "mount must fail without a fallback"
+        );
+
+        mount.config.heic_settings.fallback_format = Some(crate::config::FallbackFormat::Jpeg);
+        let fs = mount_without_encoder(&mount)?;
+
+        let names: Vec<String> = fs
+            .list_directory(Path::new("pictures"))
+            .iter()
+            .map(|(name, _, _)| name.clone())
+            .collect();
+        assert_eq!(names, vec!["photo.jpg"]);
+        assert!(lookup_path(&fs, "pictures/photo.heic").await.is_err());
+
+        let entry = lookup_path(&fs, "pictures/photo.jpg").await?;
+        let data = read_all(&fs, entry.attr.ino).await?;
+        assert_eq!(
+            image::guess_format(&data)?,
+            ImageCrateFormat::Jpeg,
+            "expected JPEG output"
+        );
+        let decoded = image::load_from_memory(&data)?;
+        assert_eq!((decoded.width(), decoded.height()), (256, 256));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_init_negotiates_configured_max_write() -> Result<()> {
+        let mut mount = TestMount::new()?;
+        mount.config.fuse.max_write_kb = 256;
+        let fs = mount.mount()?;
+
+        let reply = fs.init(TEST_REQUEST).await?;
+        assert_eq!(reply.max_write.get(), 256 * 1024);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_open_reply_carries_page_cache_flags() -> Result<()> {
+        let mut mount = TestMount::new()?;
+        mount.write_jpeg("photo.jpg")?;
+
+        for (direct_io, keep_cache, size_estimation, expected) in [
+            (false, false, SizeEstimation::Heuristic, 0),
+            (true, false, SizeEstimation::Heuristic, FOPEN_DIRECT_IO),
+            (false, true, SizeEstimation::Heuristic, FOPEN_KEEP_CACHE),
+            // A low estimate must not truncate reads
+            (false, false, SizeEstimation::Proxy, FOPEN_DIRECT_IO),
+        ] {
+            mount.config.fuse.direct_io = direct_io;
+            mount.config.fuse.keep_cache = keep_cache;
+            mount.config.fuse.size_estimation = size_estimation;
+            let fs = mount.mount()?;
+
+            let entry = lookup_path(&fs, "pictures/photo.heic").await?;
+            let reply = fs.open(TEST_REQUEST, entry.attr.ino, 0).await?;
+            assert_eq!(reply.flags, expected);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_readdirplus_only_caches_exact_sizes() -> Result<()> {
+        use futures_util::StreamExt;
+
+        let mut mount = TestMount::new()?;
+        mount.config.fuse.prefetch_count = 0;
+        mount.write_jpeg("photo.jpg")?;
+        let fs = mount.mount()?;
+        let dir = lookup_path(&fs, "pictures").await?.attr.ino;
+
+        async fn photo_entry(fs: &ImageFuseFS, dir: u64) -> fuse3::Result<DirectoryEntryPlus> {
+            let reply = fs.readdirplus(TEST_REQUEST, dir, 0, 0, 0).await?;
+            let entries: Vec<_> = reply.entries.collect().await;
+            for entry in entries {
+                let entry = entry?;
+                if entry.name == "photo.heic" {
+                    return Ok(entry);
+                }
+            }
+            Err(Errno::from(libc::ENOENT))
+        }
+
+        // Not converted yet: getattr has to decide the size
+        let entry = photo_entry(&fs, dir).await?;
+        assert_eq!(entry.attr_ttl, Duration::ZERO);
+
+        let data = read_all(&fs, entry.inode).await?;
+        let entry = photo_entry(&fs, dir).await?;
+        assert_eq!(entry.attr.size, data.len() as u64);
+        assert_eq!(entry.attr_ttl, fs.ttl);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_conversion_exposes_error_sidecar() -> Result<()> {
+        let mut mount = TestMount::new()?;
+        mount.config.fuse.error_sidecars = true;
+        mount.config.fuse.prefetch_count = 0;
+        mount.write_jpeg("photo.jpg")?;
+        std::fs::write(mount.source_dir().join("broken.jpg"), b"not a jpeg")?;
+        let fs = mount.mount()?;
+
+        // Nothing failed yet, so no sidecar exists
+        assert!(lookup_path(&fs, "pictures/broken.heic.error")
+            .await
+            .is_err());
+
+        let broken = lookup_path(&fs, "pictures/broken.heic").await?;
+        assert!(read_all(&fs, broken.attr.ino).await.is_err());
+
+        let sidecar = lookup_path(&fs, "pictures/broken.heic.error").await?;
+        let error = String::from_utf8(read_all(&fs, sidecar.attr.ino).await?)?;
+        assert_eq!(sidecar.attr.size, error.len() as u64);
+        assert!(
+            error.contains("broken.jpg"),
+            "unexpected error text: {error}"
+        );
+
+        // Converted files have no sidecar and sidecars are never listed
+        let photo = lookup_path(&fs, "pictures/photo.heic").await?;
+        read_all(&fs, photo.attr.ino).await?;
+        assert!(lookup_path(&fs, "pictures/photo.heic.error").await.is_err());
+        let names: Vec<String> = fs
+            .list_directory(Path::new("pictures"))
+            .iter()
+            .map(|(name, _, _)| name.clone())
+            .collect();
+        assert_eq!(names, vec!["broken.heic", "photo.heic"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_checksum_sidecar_matches_served_bytes() -> Result<()> {
+        let mut mount = TestMount::new()?;
+        mount.config.fuse.checksum_sidecars = true;
+        mount.config.fuse.prefetch_count = 0;
+        mount.write_jpeg("photo.jpg")?;
+        let fs = mount.mount()?;
+
+        // Reading the sidecar first converts the file
+        let sidecar = lookup_path(&fs, "pictures/photo.heic.sha256").await?;
+        let checksum = String::from_utf8(read_all(&fs, sidecar.attr.ino).await?)?;
+        assert_eq!(sidecar.attr.size, checksum.len() as u64);
+        assert_eq!(mount.cache_entry_count(), 1);
+
+        let photo = lookup_path(&fs, "pictures/photo.heic").await?;
+        let served = read_all(&fs, photo.attr.ino).await?;
+        assert_eq!(
+            checksum,
+            format!("{}\n", hex::encode(Sha256::digest(&served)))
+        );
+
+        // Once cached the value comes from the entry header
+        assert_eq!(
+            String::from_utf8(read_all(&fs, sidecar.attr.ino).await?)?,
+            checksum
+        );
+
+        let names: Vec<String> = fs
+            .list_directory(Path::new("pictures"))
+            .iter()
+            .map(|(name, _, _)| name.clone())
+            .collect();
+        assert_eq!(names, vec!["photo.heic"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_variants_are_listed_and_converted_separately() -> Result<()> {
+        let mut mount = TestMount::new()?;
+        mount.config.fuse.prefetch_count = 0;
+        mount.config.variants = vec![
+            Variant {
+                suffix: "hq".to_string(),
+                heic_settings: HeicSettings {
+                    quality: 90,
+                    ..HeicSettings::default()
+                },
+            },
+            Variant {
+                suffix: "web".to_string(),
+                heic_settings: HeicSettings {
+                    quality: 20,
+                    max_resolution: Some("128,128".to_string()),
+                    ..HeicSettings::default()
+                },
+            },
+        ];
+        mount.write_jpeg("photo.jpg")?;
+        let fs = mount.mount()?;
+
+        let names: Vec<String> = fs
+            .list_directory(Path::new("pictures"))
+            .iter()
+            .map(|(name, _, _)| name.clone())
+            .collect();
+        assert_eq!(names, vec!["photo.hq.heic", "photo.web.heic"]);
+
+        let hq = lookup_path(&fs, "pictures/photo.hq.heic").await?;
+        let web = lookup_path(&fs, "pictures/photo.web.heic").await?;
+        let hq_data = read_all(&fs, hq.attr.ino).await?;
+        let web_data = read_all(&fs, web.attr.ino).await?;
+        assert!(
+            web_data.len() < hq_data.len(),
+            "web ({}) should be smaller than hq ({})",
+            web_data.len(),
+            hq_data.len()
+        );
+        assert_eq!(mount.cache_entry_count(), 2);
+
+        // Once cached, each variant reports its own size
+        let web = lookup_path(&fs, "pictures/photo.web.heic").await?;
+        assert_eq!(web.attr.size, web_data.len() as u64);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_empty_and_truncated_sources() -> Result<()> {
+        let mut mount = TestMount::new()?;
+        mount.config.fuse.error_sidecars = true;
+        mount.config.fuse.prefetch_count = 0;
+        std::fs::write(mount.source_dir().join("empty.jpg"), b"")?;
+        let photo = mount.write_jpeg("photo.jpg")?;
+        let jpeg = std::fs::read(&photo)?;
+        std::fs::write(
+            mount.source_dir().join("partial.jpg"),
+            &jpeg[..jpeg.len() / 2],
+        )?;
+        let fs = mount.mount()?;
+
+        // Empty files are served as empty passthrough files
+        let empty = lookup_path(&fs, "pictures/empty.heic").await?;
+        assert_eq!(empty.attr.size, 0);
+        assert!(read_all(&fs, empty.attr.ino).await?.is_empty());
+        assert_eq!(mount.cache_entry_count(), 0);
+
+        // Truncated files fail with an explicit error
+        let partial = lookup_path(&fs, "pictures/partial.heic").await?;
+        assert!(read_all(&fs, partial.attr.ino).await.is_err());
+        let sidecar = lookup_path(&fs, "pictures/partial.heic.error").await?;
+        let error = String::from_utf8(read_all(&fs, sidecar.attr.ino).await?)?;
+        assert!(
+            error.contains("truncated"),
+            "unexpected error text: {error}"
+        );
+
+        // Empty files can be hidden entirely
+        mount.config.fuse.hide_empty_files = true;
+        let fs = mount.mount()?;
+        assert!(lookup_path(&fs, "pictures/empty.heic").await.is_err());
+        let names: Vec<String> = fs
+            .list_directory(Path::new("pictures"))
+            .iter()
+            .map(|(name, _, _)| name.clone())
+            .collect();
+        assert_eq!(names, vec!["partial.heic", "photo.heic"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chunked_reads_use_cache_key_from_open() -> Result<()> {
+        let mut mount = TestMount::new()?;
+        mount.config.fuse.prefetch_count = 0;
+        let source = mount.write_jpeg("photo.jpg")?;
+        let fs = mount.mount()?;
+
+        let entry = lookup_path(&fs, "pictures/photo.heic").await?;
+        let expected = read_all(&fs, entry.attr.ino).await?;
+
+        let fh = fs.open(TEST_REQUEST, entry.attr.ino, 0).await?.fh;
+        assert_ne!(fh, 0);
+        let open_file = Arc::clone(&fs.open_files.get(&fh).unwrap());
+
+        // Reads must not stat the source again: with it gone, a key computed per read
+        // would miss the cache
+        std::fs::remove_file(&source)?;
+
+        let mut data = Vec::new();
+        loop {
+            let chunk = fs
+                .read(TEST_REQUEST, entry.attr.ino, fh, data.len() as u64, 1024)
+                .await?;
+            if chunk.data.is_empty() {
+                break;
+            }
+            data.extend_from_slice(&chunk.data);
+            assert!(Arc::ptr_eq(&open_file, &fs.open_files.get(&fh).unwrap()));
+        }
+        assert_eq!(data, expected);
+        assert!(data.len() > 4 * 1024, "expected several chunks");
+
+        fs.release(TEST_REQUEST, entry.attr.ino, fh, 0, 0, false)
+            .await?;
+        assert!(fs.open_files.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_conversion_temp_dir_must_be_writable() -> Result<()> {
+        let mut mount = TestMount::new()?;
+        mount.config.fuse.prefetch_count = 0;
+        mount.write_jpeg("photo.jpg")?;
+
+        let scratch = mount.temp_dir.path().join("scratch");
+        mount.config.conversion.temp_dir = Some(scratch.clone());
+        let fs = mount.mount()?;
+        assert!(scratch.is_dir());
+        let entry = lookup_path(&fs, "pictures/photo.heic").await?;
+        assert!(!read_all(&fs, entry.attr.ino).await?.is_empty());
+
+        // A directory below a regular file can never be created, even by root
+        let blocker = mount.temp_dir.path().join("not-a-dir");
+        std::fs::write(&blocker, b"")?;
+        mount.config.conversion.temp_dir = Some(blocker.join("scratch"));
+        let err = match mount.mount() {
+            Ok(_) => panic!("mount must fail with an unusable temp_dir"),
+            Err(e) => format!("{e:#}"),
+        };
+        assert!(err.contains("conversion.temp_dir"), "{err}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_atomic_replace_of_source_forces_reconversion() -> Result<()> {
+        let mut mount = TestMount::new()?;
+        mount.config.fuse.prefetch_count = 0;
+        // BMP files of the same dimensions have the same size, so only the new inode
+        // tells the two sources apart
+        let path = mount.source_dir().join("photo.bmp");
+        image::RgbImage::from_pixel(64, 64, image::Rgb([200, 30, 30])).save(&path)?;
+        let mtime = std::fs::metadata(&path)?.modified()?;
+        let fs = mount.mount()?;
+
+        let entry = lookup_path(&fs, "pictures/photo.heic").await?;
+        let before = read_all(&fs, entry.attr.ino).await?;
+        assert_eq!(read_all(&fs, entry.attr.ino).await?, before);
+
+        let replacement = mount.temp_dir.path().join("replacement.bmp");
+        image::RgbImage::from_pixel(64, 64, image::Rgb([30, 30, 200])).save(&replacement)?;
+        std::fs::File::options()
+            .write(true)
+            .open(&replacement)?
+            .set_modified(mtime)?;
+        assert_eq!(
+            std::fs::metadata(&replacement)?.len(),
+            std::fs::metadata(&path)?.len()
+        );
+        std::fs::rename(&replacement, &path)?;
+
+        let after = read_all(&fs, entry.attr.ino).await?;
+        assert_ne!(after, before, "served the entry of the replaced source");
+        assert_eq!(mount.cache_entry_count(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sources_below_min_convert_bytes_pass_through() -> Result<()> {
+        let mut mount = TestMount::new()?;
+        mount.config.fuse.prefetch_count = 0;
+        mount.config.heic_settings.min_convert_bytes = Some(1024);
+        let icon = mount.source_dir().join("icon.png");
+        image::RgbImage::from_pixel(4, 4, image::Rgb([0, 128, 255])).save(&icon)?;
+        mount.write_jpeg("photo.jpg")?;
+        let fs = mount.mount()?;
+
+        let names: Vec<String> = fs
+            .list_directory(Path::new("pictures"))
+            .iter()
+            .map(|(name, _, _)| name.clone())
+            .collect();
+        assert_eq!(names, vec!["icon.png", "photo.heic"]);
+        assert!(lookup_path(&fs, "pictures/icon.heic").await.is_err());
+
+        let entry = lookup_path(&fs, "pictures/icon.png").await?;
+        assert_eq!(read_all(&fs, entry.attr.ino).await?, std::fs::read(&icon)?);
+        assert_eq!(mount.cache_entry_count(), 0);
+
+        let photo = lookup_path(&fs, "pictures/photo.heic").await?;
+        read_all(&fs, photo.attr.ino).await?;
+        assert_eq!(mount.cache_entry_count(), 1);
+
+        // Growing past the threshold in place leaves the directory mtime alone
+        let noise = image::RgbImage::from_fn(64, 64, |x, y| {
+            image::Rgb([(x * 37 + y * 11) as u8, (x ^ y) as u8, (x * y) as u8])
+        });
+        let mut grown = std::io::Cursor::new(Vec::new());
+        noise.write_to(&mut grown, image::ImageFormat::Png)?;
+        assert!(grown.get_ref().len() >= 1024);
+        std::fs::write(&icon, grown.into_inner())?;
+        let names: Vec<String> = fs
+            .list_directory(Path::new("pictures"))
+            .iter()
+            .map(|(name, _, _)| name.clone())
+            .collect();
+        assert_eq!(names, vec!["icon.heic", "photo.heic"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_prefetch_skips_passthrough_files() -> Result<()> {
+        let mut mount = TestMount::new()?;
+        mount.config.fuse.prefetch_count = 3;
+        mount.config.heic_settings.min_convert_bytes = Some(1024);
+        mount.write_jpeg("a.jpg")?;
+        image::RgbImage::from_pixel(4, 4, image::Rgb([0, 128, 255]))
+            .save(mount.source_dir().join("b.png"))?;
+        std::fs::write(mount.source_dir().join("c.jpg"), b"")?;
+        mount.write_jpeg("d.jpg")?;
+        let fs = mount.mount()?;
+
+        let entry = lookup_path(&fs, "pictures/a.heic").await?;
+        read_all(&fs, entry.attr.ino).await?;
+
+        // d.jpg is queued after the small and empty files, so once it is cached
+        // every prefetch job has been submitted
+        let deadline = std::time::Instant::now() + Duration::from_secs(30);
+        while mount.cache_entry_count() < 2 && std::time::Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        // Dropping the mount drains the conversion queues
+        drop(fs);
+        assert_eq!(mount.cache_entry_count(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_per_format_quality_overrides_base_quality() -> Result<()> {
+        let mut mount = TestMount::new()?;
+        mount.config.fuse.prefetch_count = 0;
+        mount.config.heic_settings.quality = 50;
+        mount.config.heic_settings.per_format_quality =
+            [("png".to_string(), 90), ("jpg".to_string(), 10)].into();
+        let jpeg = mount.write_jpeg("photo.jpg")?;
+        let png = mount.source_dir().join("screenshot.png");
+        image::open(&jpeg)?.save(&png)?;
+        let fs = mount.mount()?;
+
+        let mut sizes = Vec::new();
+        for (name, path, quality) in [("photo.heic", &jpeg, 10), ("screenshot.heic", &png, 90)] {
+            let file = fs
+                .resolve_file(&Path::new("pictures").join(name))
+                .expect("file resolves");
+            assert_eq!(file.heic_settings.quality, quality, "{name}");
+
+            let open_file = fs.open_file(file);
+            let size = std::fs::metadata(path)?.len();
+            let base_key =
+                create_cache_key(&path.to_string_lossy(), size, &mount.config.heic_settings);
+            assert_ne!(open_file.cache_key, base_key, "{name}");
+
+            let entry = lookup_path(&fs, &format!("pictures/{name}")).await?;
+            sizes.push(read_all(&fs, entry.attr.ino).await?.len());
+        }
+        assert!(
+            sizes[1] > sizes[0],
+            "quality 90 output ({}) not larger than quality 10 ({})",
+            sizes[1],
+            sizes[0]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unreadable_source_is_reported_degraded() -> Result<()> {
+        let mut mount = TestMount::new()?;
+        mount.write_jpeg("photo.jpg")?;
+        let moved_away = mount.temp_dir.path().join("unmounted");
+        let names = |fs: &ImageFuseFS, dir: &str| -> Vec<String> {
+            fs.list_directory(Path::new(dir))
+                .iter()
+                .map(|(name, _, _)| name.clone())
+                .collect()
+        };
+
+        let fs = mount.mount()?;
+        assert_eq!(names(&fs, "pictures"), vec!["photo.heic"]);
+        assert!(!fs.source_status()["pictures"].degraded);
+
+        // Like a network drive going away underneath the source path
+        std::fs::rename(mount.source_dir(), &moved_away)?;
+        assert!(names(&fs, "pictures").is_empty());
+        let status = &fs.source_status()["pictures"];
+        assert!(status.degraded);
+        assert!(status.error.is_some());
+        assert_eq!(load_source_status(&mount.cache_dir())?["pictures"], *status);
+        std::fs::rename(&moved_away, mount.source_dir())?;
+
+        // With the fallback the last good listing stays visible meanwhile
+        mount.config.fuse.source_fallback_cache = true;
+        let fs = mount.mount()?;
+        assert_eq!(names(&fs, "/"), vec!["pictures"]);
+        assert_eq!(names(&fs, "pictures"), vec!["photo.heic"]);
+        std::fs::rename(mount.source_dir(), &moved_away)?;
+        assert_eq!(names(&fs, "/"), vec!["pictures"]);
+        assert_eq!(names(&fs, "pictures"), vec!["photo.heic"]);
+        assert!(fs.source_status()["pictures"].degraded);
+
+        std::fs::rename(&moved_away, mount.source_dir())?;
+        names(&fs, "pictures");
+        assert!(!fs.source_status()["pictures"].degraded);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_restrict_to_uid_denies_other_callers() -> Result<()> {
+        let mut mount = TestMount::new()?;
+        mount.config.fuse.restrict_to_uid = Some(1000);
+        mount.write_jpeg("photo.jpg")?;
+        let as_uid = |uid| Request {
+            uid,
+            ..TEST_REQUEST
+        };
+        let eacces = Errno::from(libc::EACCES);
+
+        let fs = mount.mount()?;
+        let owner = as_uid(1000);
+        let dir = fs.lookup(owner, ROOT_INODE, OsStr::new("pictures")).await?;
+        let file = fs
+            .lookup(owner, dir.attr.ino, OsStr::new("photo.heic"))
+            .await?;
+        assert!(!fs
+            .read(owner, file.attr.ino, 0, 0, 16)
+            .await?
+            .data
+            .is_empty());
+
+        for denied in [as_uid(1001), as_uid(0)] {
+            let lookup = fs.lookup(denied, ROOT_INODE, OsStr::new("pictures")).await;
+            assert_eq!(lookup.err(), Some(eacces));
+            let getattr = fs.getattr(denied, file.attr.ino, None, 0).await;
+            assert_eq!(getattr.err(), Some(eacces));
+            let read = fs.read(denied, file.attr.ino, 0, 0, 16).await;
+            assert_eq!(read.err(), Some(eacces));
+            let readdir = fs.readdir(denied, dir.attr.ino, 0, 0).await;
+            assert_eq!(readdir.err(), Some(eacces));
+        }
+
+        mount.config.fuse.allow_root = true;
+        let fs = mount.mount()?;
+        let root = as_uid(0);
+        fs.lookup(root, ROOT_INODE, OsStr::new("pictures")).await?;
+        let other = fs
+            .lookup(as_uid(1001), ROOT_INODE, OsStr::new("pictures"))
+            .await;
+        assert_eq!(other.err(), Some(eacces));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_convert_on_open_makes_first_read_a_cache_hit() -> Result<()> {
+        let mut mount = TestMount::new()?;
+        mount.config.fuse.prefetch_count = 0;
+        let source = mount.write_jpeg("photo.jpg")?;
+        let moved_away = mount.temp_dir.path().join("photo.jpg");
+
+        for convert_on_open in [false, true] {
+            mount.config.fuse.convert_on_open = convert_on_open;
+            let fs = mount.mount()?;
+            let file = lookup_path(&fs, "pictures/photo.heic").await?;
+            let fh = fs.open(TEST_REQUEST, file.attr.ino, 0).await?.fh;
+
+            // Only a conversion that already ran can serve the read now
+            std::fs::rename(&source, &moved_away)?;
+            let read = fs.read(TEST_REQUEST, file.attr.ino, fh, 0, u32::MAX).await;
+            std::fs::rename(&moved_away, &source)?;
+
+            if convert_on_open {
+                let data = read?.data;
+                assert!(data.len() > 12 && &data[4..12] == b"ftypheic");
+                assert_eq!(mount.cache_entry_count(), 1);
+            } else {
+                assert_eq!(read.err(), Some(Errno::from(libc::EIO)));
+                assert_eq!(mount.cache_entry_count(), 0);
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,13 +1,17 @@
 use anyhow::{Context, Result};
 use image::DynamicImage;
 use libheif_rs::{
-    Channel, ColorSpace, CompressionFormat, EncoderQuality, HeifContext, Image, LibHeif, RgbChroma,
+    Channel, ColorSpace, CompressionFormat, Encoder, EncoderParameterValue, EncoderQuality,
+    HeifContext, Image, LibHeif, RgbChroma,
 };
-use log::debug;
+use log::{debug, info, warn};
+use parking_lot::Mutex;
 use std::fs;
 use std::path::Path;
+use std::time::{Duration, Instant};
 
-use crate::config::HeicSettings;
+use crate::config::{Config, FallbackFormat, HeicSettings};
+use crate::file_detector::ImageFormat;
 
 fn decode_heic_with_libheif(input_data: &[u8]) -> Result<DynamicImage> {
     let lib_heif = LibHeif::new();
@@ -65,10 +69,89 @@ pub fn convert_to_heic_blocking(
 ) -> Result<Vec<u8>> {
     debug!("Converting image: {input_path:?}");
 
+    let (input_size, mut rgb_img) = load_rgb_image(input_path, heic_settings)?;
+    let (mut width, mut height) = rgb_img.dimensions();
+
+    // Resize if image exceeds configured maximum resolution (or is below it with allow_upscale)
+    if let Some((new_width, new_height)) = heic_settings.target_dimensions(width, height) {
+        debug!("Resizing image from {width}x{height} to {new_width}x{new_height}");
+        rgb_img = resize_rgb_image(rgb_img, new_width, new_height);
+        width = new_width;
+        height = new_height;
+    }
+
+    debug!("Image dimensions: {width}x{height}");
+
+    let output_data = encode_output(&rgb_img, heic_settings)?;
+
+    debug!(
+        "Converted {} bytes -> {} bytes (compression: {:.1}%)",
+        input_size,
+        output_data.len(),
+        (1.0 - output_data.len() as f64 / input_size as f64) * 100.0
+    );
+    log_conversion_summary(input_path, input_size, output_data.len() as u64);
+
+    Ok(output_data)
+}
+
+/// Longest side of the larger proxy image encoded by `estimate_heic_size_from_proxy`
+const PROXY_MAX_SIDE: u32 = 512;
+
+/// Estimate the converted size by encoding downscaled proxies with the same settings
+/// and extrapolating their size by the pixel count ratio
+///
+/// Two proxies (full and half `PROXY_MAX_SIDE`) give the rate at which the size grows
+/// with the pixel count, since smooth images grow much slower than linearly. Much
+/// closer than the source size, but still an estimate that can come out low.
+pub fn estimate_heic_size_from_proxy(
+    input_path: &Path,
+    heic_settings: &HeicSettings,
+) -> Result<u64> {
+    let (_, rgb_img) = load_rgb_image(input_path, heic_settings)?;
+    let (width, height) = rgb_img.dimensions();
+    let (target_width, target_height) = heic_settings
+        .target_dimensions(width, height)
+        .unwrap_or((width, height));
+    let target_pixels = target_width as f64 * target_height as f64;
+
+    let encode_proxy = |max_side: u32| -> Result<(f64, f64)> {
+        let scale = (max_side as f64 / target_width.max(target_height) as f64).min(1.0);
+        let proxy_width = ((target_width as f64 * scale).round() as u32).max(1);
+        let proxy_height = ((target_height as f64 * scale).round() as u32).max(1);
+        let proxy = resize_rgb_image(rgb_img.clone(), proxy_width, proxy_height);
+        let size = encode_output(&proxy, heic_settings)?.len() as f64;
+        Ok((proxy_width as f64 * proxy_height as f64, size))
+    };
+
+    let (pixels, size) = encode_proxy(PROXY_MAX_SIDE)?;
+    if pixels >= target_pixels {
+        // Small enough that the proxy is the real conversion
+        return Ok(size as u64);
+    }
+    let (small_pixels, small_size) = encode_proxy(PROXY_MAX_SIDE / 2)?;
+
+    // size ~ pixels^exponent, from sub-linear (smooth) up to linear (fine detail)
+    let exponent = ((size / small_size).ln() / (pixels / small_pixels).ln()).clamp(0.5, 1.0);
+    let estimate = (size * (target_pixels / pixels).powf(exponent)).round() as u64;
+    debug!("Estimated {input_path:?} at {estimate} bytes (size exponent {exponent:.2})");
+    Ok(estimate)
+}
+
+/// Read and decode a source image, returning its file size and RGB8 pixels
+///
+/// Sources whose format is not in `allowed_input_formats` are refused before decoding.
+fn load_rgb_image(
+    input_path: &Path,
+    heic_settings: &HeicSettings,
+) -> Result<(u64, image::RgbImage)> {
     // Read the input image
     let input_data = fs::read(input_path)
         .with_context(|| format!("Failed to read input image: {input_path:?}"))?;
 
+    check_complete(&input_data).with_context(|| format!("Cannot convert {input_path:?}"))?;
+    let decoder_format = check_input_format(input_path, &input_data, heic_settings)?;
+
     // Load image - use libheif for HEIC/HEIF files, image crate for others
     let img = if input_path
         .extension()
@@ -81,42 +164,124 @@ pub fn convert_to_heic_blocking(
         decode_heic_with_libheif(&input_data)
             .with_context(|| format!("Failed to decode HEIC image: {input_path:?}"))?
     } else {
-        // Use image crate for other formats
-        image::load_from_memory(&input_data)
-            .with_context(|| format!("Failed to decode image: {input_path:?}"))?
+        // Use image crate for other formats, with the decoder that was checked if any
+        match decoder_format {
+            Some(format) => image::load_from_memory_with_format(&input_data, format),
+            None => image::load_from_memory(&input_data),
+        }
+        .with_context(|| format!("Failed to decode image: {input_path:?}"))?
     };
 
     // Convert to RGB8 format for HEIC encoding
-    let mut rgb_img = img.to_rgb8();
-    let (mut width, mut height) = rgb_img.dimensions();
+    Ok((input_data.len() as u64, img.to_rgb8()))
+}
 
-    // Resize if image exceeds configured maximum resolution
-    if heic_settings.should_resize(width, height) {
-        if let Some((max_width, max_height)) = heic_settings.get_max_resolution() {
-            // Calculate resize dimensions while preserving aspect ratio
-            let width_ratio = max_width as f64 / width as f64;
-            let height_ratio = max_height as f64 / height as f64;
-            let scale_ratio = width_ratio.min(height_ratio);
+/// Refuse a source not in `allowed_input_formats`, detected by content like the
+/// image crate does. With an allow-list, returns the detected format so that
+/// decoder is used and no other is guessed.
+fn check_input_format(
+    input_path: &Path,
+    input_data: &[u8],
+    heic_settings: &HeicSettings,
+) -> Result<Option<image::ImageFormat>> {
+    if heic_settings.allowed_input_formats.is_empty() {
+        return Ok(None);
+    }
 
-            let new_width = (width as f64 * scale_ratio) as u32;
-            let new_height = (height as f64 * scale_ratio) as u32;
+    let Some(format) = ImageFormat::from_content(input_data) else {
+        anyhow::bail!(
+            "Refusing to decode {input_path:?}: unrecognized format and \
+             heic_settings.allowed_input_formats is set"
+        );
+    };
+    if !heic_settings.allows_input_format(&format) {
+        anyhow::bail!(
+            "Refusing to decode {input_path:?}: {format:?} is not in \
+             heic_settings.allowed_input_formats"
+        );
+    }
 
-            debug!("Resizing image from {width}x{height} to {new_width}x{new_height}");
+    Ok(match format {
+        ImageFormat::Jpeg => Some(image::ImageFormat::Jpeg),
+        ImageFormat::Png => Some(image::ImageFormat::Png),
+        ImageFormat::Gif => Some(image::ImageFormat::Gif),
+        ImageFormat::Webp => Some(image::ImageFormat::WebP),
+        ImageFormat::Bmp => Some(image::ImageFormat::Bmp),
+        ImageFormat::Tiff => Some(image::ImageFormat::Tiff),
+        ImageFormat::Heic => None,
+    })
+}
 
-            // Resize using the image crate's resize method
-            let resized_img = image::DynamicImage::ImageRgb8(rgb_img).resize(
-                new_width,
-                new_height,
-                image::imageops::FilterType::Lanczos3,
-            );
+fn resize_rgb_image(rgb_img: image::RgbImage, width: u32, height: u32) -> image::RgbImage {
+    // Resize using the image crate's resize method
+    DynamicImage::ImageRgb8(rgb_img)
+        .resize_exact(width, height, image::imageops::FilterType::Lanczos3)
+        .to_rgb8()
+}
 
-            rgb_img = resized_img.to_rgb8();
-            width = new_width;
-            height = new_height;
+/// Encode RGB8 pixels to HEIC, or to the fallback format if one is active
+fn encode_output(rgb_img: &image::RgbImage, heic_settings: &HeicSettings) -> Result<Vec<u8>> {
+    match heic_settings.active_fallback {
+        None => encode_rgb_image(rgb_img, heic_settings),
+        Some(FallbackFormat::Jpeg) => {
+            let mut output = Vec::new();
+            let quality = jpeg_fallback_quality(heic_settings.quality);
+            image::codecs::jpeg::JpegEncoder::new_with_quality(&mut output, quality)
+                .encode_image(rgb_img)
+                .context("Failed to encode image to JPEG")?;
+            Ok(output)
         }
     }
+}
 
-    debug!("Image dimensions: {width}x{height}");
+/// JPEG quality for a HEIC `quality`: HEIC keeps detail at far lower settings than
+/// JPEG, so 1..=100 maps linearly onto 50..=100 (the default 50 gives 75)
+fn jpeg_fallback_quality(heic_quality: u8) -> u8 {
+    50 + heic_quality.min(100) / 2
+}
+
+/// Images with at least this many pixels fill their planes on the rayon pool
+const PARALLEL_FILL_MIN_PIXELS: usize = 1 << 20;
+
+/// Split interleaved RGB8 pixels into R, G and B planes of `stride` bytes per row
+///
+/// Rows past the end of the planes and the padding after `width` are left untouched.
+fn fill_planes(rgb: &[u8], width: usize, stride: usize, planes: [&mut [u8]; 3]) {
+    if width == 0 || stride == 0 {
+        return;
+    }
+    let [plane_r, plane_g, plane_b] = planes;
+    if rgb.len() / 3 >= PARALLEL_FILL_MIN_PIXELS && rayon::current_num_threads() > 1 {
+        use rayon::prelude::*;
+        rgb.par_chunks_exact(width * 3)
+            .zip(plane_r.par_chunks_mut(stride))
+            .zip(plane_g.par_chunks_mut(stride))
+            .zip(plane_b.par_chunks_mut(stride))
+            .for_each(|(((src, r), g), b)| fill_row(src, r, g, b));
+    } else {
+        rgb.chunks_exact(width * 3)
+            .zip(plane_r.chunks_mut(stride))
+            .zip(plane_g.chunks_mut(stride))
+            .zip(plane_b.chunks_mut(stride))
+            .for_each(|(((src, r), g), b)| fill_row(src, r, g, b));
+    }
+}
+
+/// Deinterleave one row; the shortest of the source and plane rows bounds the copy
+fn fill_row(src: &[u8], r: &mut [u8], g: &mut [u8], b: &mut [u8]) {
+    // Cut every row to the same length up front so the loop needs no bounds checks
+    let len = (src.len() / 3).min(r.len()).min(g.len()).min(b.len());
+    let (r, g, b) = (&mut r[..len], &mut g[..len], &mut b[..len]);
+    for (x, pixel) in src[..len * 3].chunks_exact(3).enumerate() {
+        r[x] = pixel[0];
+        g[x] = pixel[1];
+        b[x] = pixel[2];
+    }
+}
+
+/// Encode RGB8 pixels to HEIC with the given settings
+fn encode_rgb_image(rgb_img: &image::RgbImage, heic_settings: &HeicSettings) -> Result<Vec<u8>> {
+    let (width, height) = rgb_img.dimensions();
 
     // Create HEIF image
     let mut heif_image = Image::new(width, height, ColorSpace::Rgb(RgbChroma::C444))
@@ -140,22 +305,12 @@ pub fn convert_to_heic_blocking(
         let plane_g = planes.g.as_mut().context("G plane missing")?;
         let plane_b = planes.b.as_mut().context("B plane missing")?;
 
-        let stride = plane_r.stride;
-
-        // Copy RGB data to planes
-        for y in 0..height {
-            let row_start = (stride * y as usize).min(plane_r.data.len());
-            let row_end = (row_start + width as usize).min(plane_r.data.len());
-
-            for (x, pixel_idx) in (row_start..row_end).enumerate() {
-                if x < width as usize && y < height {
-                    let pixel = rgb_img.get_pixel(x as u32, y);
-                    plane_r.data[pixel_idx] = pixel[0];
-                    plane_g.data[pixel_idx] = pixel[1];
-                    plane_b.data[pixel_idx] = pixel[2];
-                }
-            }
-        }
+        fill_planes(
+            rgb_img.as_raw(),
+            width as usize,
+            plane_r.stride,
+            [&mut *plane_r.data, &mut *plane_g.data, &mut *plane_b.data],
+        );
     }
 
     // Encode the image to HEIC
@@ -167,7 +322,7 @@ pub fn convert_to_heic_blocking(
         .context("Failed to create HEVC encoder")?;
 
     // Map quality setting (1-100) to encoder quality
-    let encoder_quality = if heic_settings.quality >= 95 {
+    let encoder_quality = if heic_settings.is_lossless() {
         EncoderQuality::LossLess
     } else {
         EncoderQuality::Lossy(heic_settings.quality)
@@ -176,24 +331,274 @@ pub fn convert_to_heic_blocking(
     encoder
         .set_quality(encoder_quality)
         .context("Failed to set encoder quality")?;
+    apply_encoder_params(&encoder, heic_settings)?;
 
     context
         .encode_image(&heif_image, &mut encoder, None)
         .context("Failed to encode image to HEIF")?;
 
     // Write to memory buffer
-    let output_data = context
+    context
         .write_to_bytes()
-        .context("Failed to write HEIF data to memory")?;
+        .context("Failed to write HEIF data to memory")
+}
 
-    debug!(
-        "Converted {} bytes -> {} bytes (compression: {:.1}%)",
-        input_data.len(),
-        output_data.len(),
-        (1.0 - output_data.len() as f64 / input_data.len() as f64) * 100.0
-    );
+/// Apply `encoder_params` to the encoder, converting each value to the parameter's type
+///
+/// Unknown parameters and invalid values only log a warning unless
+/// `encoder_params_strict` is set, since available parameters vary between plugins.
+fn apply_encoder_params(encoder: &Encoder, heic_settings: &HeicSettings) -> Result<()> {
+    for (name, value) in &heic_settings.encoder_params {
+        if let Err(e) = set_encoder_param(encoder, name, value) {
+            if heic_settings.encoder_params_strict {
+                return Err(e);
+            }
+            warn!("Ignoring encoder parameter: {e:#}");
+        }
+    }
+    Ok(())
+}
 
-    Ok(output_data)
+fn set_encoder_param(encoder: &Encoder, name: &str, value: &str) -> Result<()> {
+    if name.contains('\0') || value.contains('\0') {
+        anyhow::bail!("Invalid encoder parameter {name:?}: contains a NUL byte");
+    }
+
+    let current = encoder
+        .parameter(name)
+        .with_context(|| format!("Failed to query encoder parameter {name:?}"))?
+        .ok_or_else(|| anyhow::anyhow!("Unknown encoder parameter {name:?}"))?;
+
+    let parsed = match current {
+        EncoderParameterValue::Int(_) => value.parse().map(EncoderParameterValue::Int).ok(),
+        EncoderParameterValue::Bool(_) => match value {
+            "true" | "1" => Some(EncoderParameterValue::Bool(true)),
+            "false" | "0" => Some(EncoderParameterValue::Bool(false)),
+            _ => None,
+        },
+        EncoderParameterValue::String(_) => Some(EncoderParameterValue::String(value.to_string())),
+    }
+    .ok_or_else(|| anyhow::anyhow!("Invalid value {value:?} for encoder parameter {name:?}"))?;
+
+    debug!("Setting encoder parameter {name}={value}");
+    encoder
+        .set_parameter_value(name, parsed)
+        .with_context(|| format!("Encoder rejected {name}={value:?}"))
+}
+
+/// Per-conversion summary line shown at info level
+fn format_conversion_summary(source: &Path, original_bytes: u64, heic_bytes: u64) -> String {
+    format!(
+        "{} → {original_bytes} → {heic_bytes} bytes (saved {:.1}%)",
+        source.display(),
+        saved_percent(original_bytes, heic_bytes)
+    )
+}
+
+fn saved_percent(original_bytes: u64, heic_bytes: u64) -> f64 {
+    if original_bytes == 0 {
+        return 0.0;
+    }
+    (1.0 - heic_bytes as f64 / original_bytes as f64) * 100.0
+}
+
+/// Rate limit of the per-conversion info lines, see
+/// `logging.conversion_summary_interval_ms`. Conversions in between are folded into a
+/// rolling summary so bulk runs do not flood the log.
+struct ConversionSummary {
+    interval: Duration,
+    last_logged: Option<Instant>,
+    suppressed_count: u64,
+    suppressed_original_bytes: u64,
+    suppressed_heic_bytes: u64,
+    /// A timer is already waiting to log the suppressed conversions
+    flush_scheduled: bool,
+}
+
+impl ConversionSummary {
+    const fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last_logged: None,
+            suppressed_count: 0,
+            suppressed_original_bytes: 0,
+            suppressed_heic_bytes: 0,
+            flush_scheduled: false,
+        }
+    }
+
+    /// Record a conversion and return the lines to log for it, if any
+    fn record(
+        &mut self,
+        now: Instant,
+        source: &Path,
+        original_bytes: u64,
+        heic_bytes: u64,
+    ) -> Vec<String> {
+        if self
+            .last_logged
+            .is_some_and(|last| now.duration_since(last) < self.interval)
+        {
+            self.suppressed_count += 1;
+            self.suppressed_original_bytes += original_bytes;
+            self.suppressed_heic_bytes += heic_bytes;
+            return Vec::new();
+        }
+
+        let mut lines: Vec<String> = self.flush(now).into_iter().collect();
+        lines.push(format_conversion_summary(
+            source,
+            original_bytes,
+            heic_bytes,
+        ));
+        lines
+    }
+
+    /// Summary line of the conversions suppressed so far, if any, restarting the interval
+    fn flush(&mut self, now: Instant) -> Option<String> {
+        let line = (self.suppressed_count > 0).then(|| {
+            format!(
+                "{} more conversions: {} → {} bytes (saved {:.1}%)",
+                self.suppressed_count,
+                self.suppressed_original_bytes,
+                self.suppressed_heic_bytes,
+                saved_percent(self.suppressed_original_bytes, self.suppressed_heic_bytes)
+            )
+        });
+        *self = Self {
+            last_logged: Some(now),
+            flush_scheduled: self.flush_scheduled,
+            ..Self::new(self.interval)
+        };
+        line
+    }
+}
+
+static CONVERSION_SUMMARY: Mutex<ConversionSummary> = Mutex::new(ConversionSummary::new(
+    Duration::from_millis(crate::config::default_conversion_summary_interval_ms()),
+));
+
+/// Apply `logging.conversion_summary_interval_ms`
+pub fn set_conversion_summary_interval(interval: Duration) {
+    CONVERSION_SUMMARY.lock().interval = interval;
+}
+
+fn log_conversion_summary(source: &Path, original_bytes: u64, heic_bytes: u64) {
+    if !log::log_enabled!(log::Level::Info) {
+        return;
+    }
+    let mut summary = CONVERSION_SUMMARY.lock();
+    let lines = summary.record(Instant::now(), source, original_bytes, heic_bytes);
+    // The tail of a bulk run has no later conversion to carry its summary line
+    if summary.suppressed_count > 0 && !summary.flush_scheduled {
+        summary.flush_scheduled = true;
+        let interval = summary.interval;
+        std::thread::spawn(move || {
+            std::thread::sleep(interval);
+            CONVERSION_SUMMARY.lock().flush_scheduled = false;
+            flush_conversion_summary();
+        });
+    }
+    drop(summary);
+    for line in lines {
+        info!("{line}");
+    }
+}
+
+/// Log the conversions still folded into the rolling summary, e.g. at shutdown
+pub fn flush_conversion_summary() {
+    let line = CONVERSION_SUMMARY.lock().flush(Instant::now());
+    if let Some(line) = line {
+        info!("{line}");
+    }
+}
+
+/// Create the conversion scratch directory if needed and check that files can be
+/// created in it, so a bad `conversion.temp_dir` fails at startup rather than mid-read
+pub fn prepare_temp_dir(temp_dir: &Path) -> Result<()> {
+    fs::create_dir_all(temp_dir)
+        .with_context(|| format!("Failed to create conversion.temp_dir {temp_dir:?}"))?;
+    tempfile::tempfile_in(temp_dir)
+        .with_context(|| format!("conversion.temp_dir {temp_dir:?} is not writable"))?;
+    Ok(())
+}
+
+/// Verify that the linked libheif provides an encoder for `format`
+///
+/// libheif builds without the matching plugin only fail once an image is encoded,
+/// so this is checked once at startup to fail fast with an actionable message.
+pub fn check_encoder_available(format: CompressionFormat) -> Result<()> {
+    let lib_heif = LibHeif::new();
+    let descriptors = lib_heif.encoder_descriptors(1, Some(format), None);
+
+    match descriptors.first() {
+        Some(descriptor) => {
+            debug!("Using {format:?} encoder: {}", descriptor.name());
+            Ok(())
+        }
+        None => Err(anyhow::anyhow!(
+            "The linked libheif has no {format:?} encoder; install {}",
+            encoder_plugin_hint(format)
+        )),
+    }
+}
+
+/// Switch every settings block to `heic_settings.fallback_format` when there is no HEVC
+/// encoder, failing if no fallback is configured. `hevc_encoder` is the result of
+/// `check_encoder_available(CompressionFormat::Hevc)`.
+pub fn apply_encoder_fallback(config: &mut Config, hevc_encoder: Result<()>) -> Result<()> {
+    if let Err(e) = hevc_encoder {
+        let Some(fallback) = config.heic_settings.fallback_format else {
+            return Err(e);
+        };
+        warn!("{e:#}; serving {fallback:?} files instead of HEIC");
+        config.heic_settings.active_fallback = Some(fallback);
+        for variant in &mut config.variants {
+            variant.heic_settings.active_fallback = Some(fallback);
+        }
+    }
+    Ok(())
+}
+
+fn encoder_plugin_hint(format: CompressionFormat) -> &'static str {
+    match format {
+        CompressionFormat::Hevc => {
+            "the x265 plugin (e.g. libheif-plugin-x265) or a libheif built with x265"
+        }
+        CompressionFormat::Av1 => "an AV1 encoder plugin (e.g. libheif-plugin-aomenc)",
+        CompressionFormat::Jpeg => "the JPEG plugin (e.g. libheif-plugin-jpegenc)",
+        CompressionFormat::Jpeg2000 => "the OpenJPEG plugin (e.g. libheif-plugin-j2kenc)",
+        _ => "a libheif build with an encoder plugin for this format",
+    }
+}
+
+/// Cheap sanity check that rejects empty and visibly truncated files (e.g. interrupted
+/// downloads) with a clear message before they reach the decoder
+fn check_complete(data: &[u8]) -> Result<()> {
+    if data.is_empty() {
+        anyhow::bail!("source file is empty");
+    }
+
+    // A complete JPEG contains an end-of-image marker, a complete PNG an IEND chunk.
+    // Trailing data after them is allowed, so search instead of checking the last bytes.
+    if data.starts_with(&[0xff, 0xd8, 0xff]) && !data[2..].windows(2).any(|w| w == [0xff, 0xd9]) {
+        anyhow::bail!("JPEG file is truncated (no end-of-image marker)");
+    }
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") && !data.windows(4).rev().any(|w| w == b"IEND") {
+        anyhow::bail!("PNG file is truncated (no IEND chunk)");
+    }
+    Ok(())
+}
+
+/// Settings to convert `path` with: `per_format_quality` applied for its detected format
+pub fn settings_for_source(path: &Path, heic_settings: &HeicSettings) -> HeicSettings {
+    if heic_settings.per_format_quality.is_empty() {
+        return heic_settings.clone();
+    }
+    match crate::file_detector::FileDetector::new(vec![]).map(|d| d.detect_format(path)) {
+        Ok(Ok(Some(format))) => heic_settings.for_format(&format),
+        _ => heic_settings.clone(),
+    }
 }
 
 pub fn is_convertible_format(path: &Path) -> bool {
@@ -220,6 +625,18 @@ mod tests {
         let _ = is_convertible_format(path);
     }
 
+    #[test]
+    fn test_encoder_check() {
+        assert!(check_encoder_available(CompressionFormat::Hevc).is_ok());
+
+        // No shipped libheif plugin encodes VVC, so this must fail with a hint
+        let err = check_encoder_available(CompressionFormat::Vvc)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("no Vvc encoder"), "unexpected error: {err}");
+        assert!(err.contains("install"), "unexpected error: {err}");
+    }
+
     #[test]
     fn test_conversion_is_deterministic_jpg() -> Result<()> {
         let temp_dir = TempDir::new()?;
@@ -236,12 +653,7 @@ mod tests {
         }
         DynamicImage::ImageRgb8(img).save_with_format(&test_file, ImageCrateFormat::Jpeg)?;
 
-        let settings = HeicSettings {
-            quality: 50,
-            speed: 4,
-            chroma: 420,
-            max_resolution: None,
-        };
+        let settings = HeicSettings::default();
 
         // Convert twice
         let result1 = convert_to_heic_blocking(&test_file, &settings)?;
@@ -271,12 +683,7 @@ mod tests {
         }
         DynamicImage::ImageRgb8(img).save_with_format(&test_file, ImageCrateFormat::Png)?;
 
-        let settings = HeicSettings {
-            quality: 50,
-            speed: 4,
-            chroma: 420,
-            max_resolution: None,
-        };
+        let settings = HeicSettings::default();
 
         // Convert twice
         let result1 = convert_to_heic_blocking(&test_file, &settings)?;
@@ -289,4 +696,394 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_lossless_is_explicit() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let test_file = temp_dir.path().join("test.png");
+
+        let mut img = image::RgbImage::new(200, 200);
+        for (x, y, pixel) in img.enumerate_pixels_mut() {
+            *pixel = image::Rgb([
+                ((x * y) % 256) as u8,
+                ((x * 5) % 256) as u8,
+                ((y * 3) % 256) as u8,
+            ]);
+        }
+        DynamicImage::ImageRgb8(img).save_with_format(&test_file, ImageCrateFormat::Png)?;
+
+        let lossy = HeicSettings {
+            quality: 98,
+            lossless: Some(false),
+            ..HeicSettings::default()
+        };
+        let lossless = HeicSettings {
+            lossless: Some(true),
+            ..lossy.clone()
+        };
+        assert!(!lossy.is_lossless());
+        assert!(HeicSettings {
+            lossless: None,
+            ..lossy.clone()
+        }
+        .is_lossless());
+
+        let lossy_data = convert_to_heic_blocking(&test_file, &lossy)?;
+        let lossless_data = convert_to_heic_blocking(&test_file, &lossless)?;
+        assert!(
+            lossy_data.len() < lossless_data.len(),
+            "lossy q98 ({}) should be smaller than lossless ({})",
+            lossy_data.len(),
+            lossless_data.len()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_resolution_never_upscales_by_default() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let test_file = temp_dir.path().join("small.png");
+        let img = image::RgbImage::from_fn(100, 100, |x, y| {
+            image::Rgb([(x * 2) as u8, (y * 2) as u8, ((x + y) % 256) as u8])
+        });
+        DynamicImage::ImageRgb8(img).save_with_format(&test_file, ImageCrateFormat::Png)?;
+
+        let settings = HeicSettings {
+            max_resolution: Some("2560,1440".to_string()),
+            ..HeicSettings::default()
+        };
+        let data = convert_to_heic_blocking(&test_file, &settings)?;
+        let decoded = decode_heic_with_libheif(&data)?;
+        assert_eq!((decoded.width(), decoded.height()), (100, 100));
+
+        let upscale = HeicSettings {
+            allow_upscale: true,
+            ..settings
+        };
+        let data = convert_to_heic_blocking(&test_file, &upscale)?;
+        let decoded = decode_heic_with_libheif(&data)?;
+        assert_eq!((decoded.width(), decoded.height()), (1440, 1440));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_proxy_estimate_is_close_to_converted_size() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let test_file = temp_dir.path().join("large.png");
+        // Gradients and soft waves: detail that survives downscaling, like most photos
+        let img = image::RgbImage::from_fn(1600, 1200, |x, y| {
+            let (fx, fy) = (x as f32, y as f32);
+            let wave = ((fx / 37.0).sin() * (fy / 23.0).cos() * 60.0) as i32;
+            image::Rgb([
+                (x * 255 / 1600) as u8,
+                (128 + wave).clamp(0, 255) as u8,
+                (y * 255 / 1200) as u8,
+            ])
+        });
+        DynamicImage::ImageRgb8(img).save_with_format(&test_file, ImageCrateFormat::Png)?;
+
+        let settings = HeicSettings::default();
+        let actual = convert_to_heic_blocking(&test_file, &settings)?.len() as f64;
+        let estimate = estimate_heic_size_from_proxy(&test_file, &settings)? as f64;
+        let source = fs::metadata(&test_file)?.len() as f64;
+
+        let error = (estimate - actual).abs() / actual;
+        assert!(
+            error < 0.25,
+            "estimate {estimate} vs actual {actual} ({:.0}% off)",
+            error * 100.0
+        );
+        // And much closer than reporting the source size
+        assert!((estimate - actual).abs() < (source - actual).abs());
+        Ok(())
+    }
+
+    #[test]
+    fn test_empty_and_truncated_inputs_fail_clearly() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let settings = HeicSettings::default();
+
+        let empty = temp_dir.path().join("empty.jpg");
+        fs::write(&empty, b"")?;
+        let err = convert_to_heic_blocking(&empty, &settings).unwrap_err();
+        assert!(format!("{err:#}").contains("empty"), "{err:#}");
+
+        let img = image::RgbImage::from_fn(64, 64, |x, y| image::Rgb([x as u8, y as u8, 0]));
+        for (name, format, expected) in [
+            ("cut.jpg", ImageCrateFormat::Jpeg, "JPEG file is truncated"),
+            ("cut.png", ImageCrateFormat::Png, "PNG file is truncated"),
+        ] {
+            let path = temp_dir.path().join(name);
+            DynamicImage::ImageRgb8(img.clone()).save_with_format(&path, format)?;
+            let data = fs::read(&path)?;
+            fs::write(&path, &data[..data.len() / 2])?;
+
+            let err = convert_to_heic_blocking(&path, &settings).unwrap_err();
+            assert!(format!("{err:#}").contains(expected), "{err:#}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_encoder_params_are_applied() -> Result<()> {
+        let lib_heif = LibHeif::new();
+        let encoder = lib_heif.encoder_for_format(CompressionFormat::Hevc)?;
+        let settings = HeicSettings {
+            encoder_params: vec![("preset".to_string(), "ultrafast".to_string())],
+            encoder_params_strict: true,
+            ..HeicSettings::default()
+        };
+
+        apply_encoder_params(&encoder, &settings)?;
+        assert_eq!(
+            encoder.parameter("preset")?,
+            Some(EncoderParameterValue::String("ultrafast".to_string()))
+        );
+
+        // Unknown parameters only fail in strict mode
+        let unknown = HeicSettings {
+            encoder_params: vec![("no-such-parameter".to_string(), "1".to_string())],
+            ..settings
+        };
+        let err = apply_encoder_params(&encoder, &unknown).unwrap_err();
+        assert!(
+            err.to_string().contains("Unknown encoder parameter"),
+            "{err}"
+        );
+        apply_encoder_params(
+            &encoder,
+            &HeicSettings {
+                encoder_params_strict: false,
+                ..unknown
+            },
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_conversion_summary_is_rate_limited() {
+        let interval = Duration::from_secs(1);
+        let mut summary = ConversionSummary::new(interval);
+        let start = Instant::now();
+
+        assert_eq!(
+            summary.record(start, Path::new("a.jpg"), 1000, 250),
+            vec!["a.jpg → 1000 → 250 bytes (saved 75.0%)"]
+        );
+        assert!(summary
+            .record(
+                start + Duration::from_millis(10),
+                Path::new("b.jpg"),
+                1000,
+                500
+            )
+            .is_empty());
+        assert!(summary
+            .record(
+                start + Duration::from_millis(20),
+                Path::new("c.jpg"),
+                1000,
+                500
+            )
+            .is_empty());
+        assert_eq!(
+            summary.record(start + interval, Path::new("d.jpg"), 400, 100),
+            vec![
+                "2 more conversions: 2000 → 1000 bytes (saved 50.0%)",
+                "d.jpg → 400 → 100 bytes (saved 75.0%)",
+            ]
+        );
+
+        // A suppressed tail is logged by a flush without waiting for another conversion
+        let later = start + interval + Duration::from_millis(10);
+        assert!(summary
+            .record(later, Path::new("e.jpg"), 1000, 100)
+            .is_empty());
+        assert_eq!(
+            summary.flush(later).as_deref(),
+            Some("1 more conversions: 1000 → 100 bytes (saved 90.0%)")
+        );
+        assert_eq!(summary.flush(later), None);
+    }
+
+    struct CaptureLogger {
+        records: Mutex<Vec<(log::Level, String)>>,
+    }
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            metadata.level() <= log::Level::Info
+        }
+
+        fn log(&self, record: &log::Record) {
+            if self.enabled(record.metadata()) {
+                self.records
+                    .lock()
+                    .push((record.level(), record.args().to_string()));
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    static CAPTURE_LOGGER: CaptureLogger = CaptureLogger {
+        records: Mutex::new(Vec::new()),
+    };
+
+    #[test]
+    fn test_conversion_logs_summary_at_info() -> Result<()> {
+        log::set_logger(&CAPTURE_LOGGER).expect("no other logger is installed in tests");
+        log::set_max_level(log::LevelFilter::Info);
+
+        let temp_dir = TempDir::new()?;
+        let test_file = temp_dir.path().join("summary.png");
+        let img = image::RgbImage::from_fn(64, 64, |x, y| image::Rgb([x as u8, y as u8, 0]));
+        DynamicImage::ImageRgb8(img).save_with_format(&test_file, ImageCrateFormat::Png)?;
+        let original_bytes = fs::metadata(&test_file)?.len();
+
+        *CONVERSION_SUMMARY.lock() = ConversionSummary::new(Duration::from_secs(1));
+        let heic = convert_to_heic_blocking(&test_file, &HeicSettings::default())?;
+        let expected = format_conversion_summary(&test_file, original_bytes, heic.len() as u64);
+
+        // A conversion from a concurrently running test may still slip in between the
+        // reset and this conversion and fold it into the next rolling summary
+        let records = CAPTURE_LOGGER.records.lock();
+        let summaries: Vec<_> = records
+            .iter()
+            .filter(|(_, message)| message.contains(" bytes (saved "))
+            .collect();
+        assert!(!summaries.is_empty(), "no summary logged: {records:?}");
+        assert!(summaries
+            .iter()
+            .all(|(level, _)| *level == log::Level::Info));
+        assert!(
+            summaries.iter().any(|(_, message)| *message == expected)
+                || summaries
+                    .iter()
+                    .any(|(_, m)| m.contains("more conversions")),
+            "{summaries:?}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_disallowed_input_format_is_not_decoded() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let img = image::RgbImage::from_fn(32, 32, |x, y| image::Rgb([x as u8, y as u8, 0]));
+        let jpeg = temp_dir.path().join("photo.jpg");
+        let tiff = temp_dir.path().join("scan.tiff");
+        // A TIFF named like a JPEG must not slip through by its extension either
+        let disguised = temp_dir.path().join("scan.jpg");
+        DynamicImage::ImageRgb8(img.clone()).save_with_format(&jpeg, ImageCrateFormat::Jpeg)?;
+        DynamicImage::ImageRgb8(img).save_with_format(&tiff, ImageCrateFormat::Tiff)?;
+        fs::copy(&tiff, &disguised)?;
+
+        let settings = HeicSettings {
+            allowed_input_formats: vec!["jpg".to_string(), "png".to_string()],
+            ..HeicSettings::default()
+        };
+        assert!(convert_to_heic_blocking(&jpeg, &settings).is_ok());
+        for path in [&tiff, &disguised] {
+            let err = convert_to_heic_blocking(path, &settings).unwrap_err();
+            assert!(
+                format!("{err:#}").contains("Tiff is not in heic_settings.allowed_input_formats"),
+                "unexpected error: {err:#}"
+            );
+        }
+        assert!(convert_to_heic_blocking(&tiff, &HeicSettings::default()).is_ok());
+
+        let detector =
+            crate::file_detector::FileDetector::new(vec![r".*\.(jpg|tiff)$".to_string()])?
+                .with_allowed_formats(&settings.allowed_input_formats);
+        assert!(detector.is_image_file(&jpeg));
+        assert!(!detector.is_image_file(&tiff));
+        assert!(!detector.is_image_file(&disguised));
+        assert_eq!(detector.detect_format(&tiff)?, None);
+        Ok(())
+    }
+
+    /// The per-pixel copy `fill_planes` replaced, kept as the reference output
+    fn fill_planes_per_pixel(rgb_img: &image::RgbImage, stride: usize, planes: [&mut [u8]; 3]) {
+        let (width, height) = rgb_img.dimensions();
+        let [plane_r, plane_g, plane_b] = planes;
+        for y in 0..height {
+            let row_start = (stride * y as usize).min(plane_r.len());
+            let row_end = (row_start + width as usize).min(plane_r.len());
+            for (x, pixel_idx) in (row_start..row_end).enumerate() {
+                let pixel = rgb_img.get_pixel(x as u32, y);
+                plane_r[pixel_idx] = pixel[0];
+                plane_g[pixel_idx] = pixel[1];
+                plane_b[pixel_idx] = pixel[2];
+            }
+        }
+    }
+
+    fn filled_planes(rgb_img: &image::RgbImage, stride: usize, reference: bool) -> Vec<Vec<u8>> {
+        let len = stride * rgb_img.height() as usize;
+        let (mut r, mut g, mut b) = (vec![7; len], vec![7; len], vec![7; len]);
+        if reference {
+            fill_planes_per_pixel(rgb_img, stride, [&mut r, &mut g, &mut b]);
+        } else {
+            let width = rgb_img.width() as usize;
+            fill_planes(rgb_img.as_raw(), width, stride, [&mut r, &mut g, &mut b]);
+        }
+        vec![r, g, b]
+    }
+
+    #[test]
+    fn test_jpeg_fallback_quality_mapping() {
+        assert_eq!(jpeg_fallback_quality(1), 50);
+        assert_eq!(jpeg_fallback_quality(30), 65);
+        assert_eq!(jpeg_fallback_quality(50), 75);
+        assert_eq!(jpeg_fallback_quality(100), 100);
+    }
+
+    #[test]
+    fn test_fill_planes_matches_per_pixel_copy() {
+        // Odd widths with padded strides, and one image large enough to fill in parallel
+        for (width, height, stride) in [(1, 1, 1), (37, 11, 64), (64, 64, 64), (1031, 1030, 1088)] {
+            let rgb_img = image::RgbImage::from_fn(width, height, |x, y| {
+                image::Rgb([x as u8, y as u8, (x ^ y).wrapping_mul(31) as u8])
+            });
+            assert_eq!(
+                filled_planes(&rgb_img, stride, false),
+                filled_planes(&rgb_img, stride, true),
+                "{width}x{height} with stride {stride}"
+            );
+        }
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with --release -- --ignored --nocapture"]
+    fn bench_fill_planes_24mp() {
+        let (width, height) = (6000, 4000);
+        let rgb_img = image::RgbImage::from_fn(width, height, |x, y| {
+            image::Rgb([x as u8, y as u8, (x + y) as u8])
+        });
+        let stride = width as usize;
+        let len = stride * height as usize;
+        let (mut r, mut g, mut b) = (vec![0; len], vec![0; len], vec![0; len]);
+
+        // Best of a few runs on already touched planes, so only the copy is timed
+        let mut time = |fill: &mut dyn FnMut([&mut [u8]; 3])| {
+            (0..5)
+                .map(|_| {
+                    let start = Instant::now();
+                    fill([&mut r, &mut g, &mut b]);
+                    start.elapsed()
+                })
+                .min()
+                .unwrap()
+        };
+        let per_pixel = time(&mut |planes| fill_planes_per_pixel(&rgb_img, stride, planes));
+        let sliced = time(&mut |planes| fill_planes(rgb_img.as_raw(), stride, stride, planes));
+
+        // Rows only spread over more than one rayon thread on multi-core machines
+        println!(
+            "24MP plane fill: per-pixel {per_pixel:?}, row slices {sliced:?} on {} threads",
+            rayon::current_num_threads()
+        );
+    }
 }
@@ -1,19 +1,23 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
 use fuse3::raw::Session;
 use fuse3::MountOptions;
-use log::info;
-use std::path::PathBuf;
+use log::{info, warn};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 mod cache;
 mod config;
+mod doctor;
 mod file_detector;
 mod filesystem;
 mod image_converter;
 mod mount_management;
 mod thread_pool;
+mod warm;
 
-use crate::config::Config;
+use crate::cache::ImageCache;
+use crate::config::{Config, SourcePath};
 use crate::filesystem::ImageFuseFS;
 
 #[derive(Parser)]
@@ -30,6 +34,14 @@ struct Args {
     )]
     mount: Option<PathBuf>,
 
+    #[arg(
+        short,
+        long = "source",
+        value_name = "NAME=PATH[:RECURSIVE]",
+        help = "Source path override, replacing the config source with the same name or adding it (repeatable)"
+    )]
+    sources: Vec<SourcePath>,
+
     #[arg(
         short,
         long,
@@ -48,6 +60,26 @@ struct Args {
 enum Commands {
     /// Create configuration directories and default config file
     Setup,
+    /// Show cumulative conversion savings recorded in the cache
+    Stats,
+    /// Check the runtime environment (FUSE, libheif, paths) and report problems
+    Doctor,
+    /// Validate the configuration file and exit
+    Validate,
+    /// Exit 0 if the mount point is a live FUSE mount, non-zero with a reason otherwise
+    Health,
+    /// Show the disk cache entry of a source file (or of a cache key) without a mount
+    CacheInfo {
+        /// Source image path, cached with the configured heic_settings, or a cache key
+        key_or_path: String,
+    },
+    /// Convert every source image into the disk cache ahead of mounting
+    Warm {
+        /// CSV record of each processed file; files unchanged since the manifest of an
+        /// earlier run are skipped
+        #[arg(long, value_name = "PATH")]
+        manifest: Option<PathBuf>,
+    },
 }
 
 fn setup() -> Result<()> {
@@ -78,29 +110,187 @@ fn setup() -> Result<()> {
     Ok(())
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let args = Args::parse();
+fn stats(config: &Config) -> Result<()> {
+    let cache_dir = config.get_cache_dir_from_config()?;
+    let stats = ImageCache::load_stats(&cache_dir)?;
+
+    println!("Cache directory: {}", cache_dir.display());
+    println!("Original bytes:  {}", stats.original_bytes);
+    println!("Converted bytes: {}", stats.converted_bytes);
+    println!(
+        "Saved:           {} bytes ({:.1}%)",
+        stats.saved_bytes(),
+        stats.savings_ratio() * 100.0
+    );
+
+    let sources = filesystem::load_source_status(&cache_dir)?;
+    if !sources.is_empty() {
+        println!("Sources:");
+        for (mount_name, status) in sources {
+            match status.error {
+                Some(error) => println!("  {mount_name}: degraded ({error})"),
+                None => println!("  {mount_name}: ok"),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn cache_info(config: &Config, key_or_path: &str) -> Result<()> {
+    let cache_dir = config.get_cache_dir_from_config()?;
+
+    let source = Path::new(key_or_path);
+    let key = if source.is_file() {
+        // Entries are keyed by the path as the mount sees it, i.e. under the configured source
+        let source = std::path::absolute(source)?;
+        let original_size = std::fs::metadata(&source)?.len();
+        let heic_settings = image_converter::settings_for_source(&source, &config.heic_settings);
+        let (key, _) =
+            cache::create_cache_key_and_context_for_path(&source, original_size, &heic_settings);
+        println!("Source:          {}", source.display());
+        key
+    } else {
+        key_or_path.to_string()
+    };
+    println!("Cache key:       {key}");
+
+    let Some(info) = ImageCache::entry_info(&cache_dir, &key)? else {
+        println!("Cache entry:     not cached");
+        return Ok(());
+    };
+    println!("Cache entry:     {}", info.path.display());
+    println!("Encrypted:       {}", info.encrypted);
+    println!("Pinned:          {}", info.pinned);
+    println!(
+        "Settings:        quality {}, speed {}, chroma {}",
+        info.quality, info.speed, info.chroma
+    );
+    println!("Payload size:    {} bytes", info.payload_size);
+    println!("Checksum:        {}", hex::encode(info.checksum));
+    Ok(())
+}
+
+fn warm(config: &Config, mount_point: &Path, manifest: Option<&Path>) -> Result<()> {
+    // Still single-threaded, warm_cache starts the cache and conversion threads
+    export_temp_dir(config)?;
+
+    let summary = warm::warm_cache(config, mount_point, manifest)?;
+    println!("Converted: {}", summary.converted);
+    println!("Skipped:   {}", summary.skipped);
+    println!("Failed:    {}", summary.failed);
+    if let Some(manifest) = manifest {
+        println!("Manifest:  {}", manifest.display());
+    }
+
+    if summary.failed > 0 {
+        anyhow::bail!("{} files failed to convert", summary.failed);
+    }
+    Ok(())
+}
+
+fn run_doctor(config: &Config, mount_point: &Path) -> Result<()> {
+    let results = doctor::run_checks(config, mount_point);
+    print!("{}", doctor::format_report(&results));
+
+    if doctor::has_failures(&results) {
+        anyhow::bail!("Some critical checks failed, see the hints above");
+    }
+
+    println!("\nAll critical checks passed");
+    Ok(())
+}
+
+fn validate(config_path: &Path) -> Result<()> {
+    // Config::load would create a default config, which is not what a check should do
+    if !config_path.exists() {
+        anyhow::bail!("Config file not found: {}", config_path.display());
+    }
+
+    Config::load(config_path)?;
+    println!("Configuration is valid: {}", config_path.display());
+    Ok(())
+}
+
+/// Logger filter resolved from the environment, flags and config
+#[derive(Debug, PartialEq)]
+struct LogFilter {
+    filter: String,
+    /// Level of the very chatty fuse3 crate, None when `RUST_LOG` controls it
+    fuse3_level: Option<log::LevelFilter>,
+}
+
+/// Resolve the log filter: an explicit `RUST_LOG` wins, else the `-v` count, else
+/// `logging.level` from the config, else "warn"
+fn resolve_log_filter(
+    rust_log: Option<&str>,
+    verbose: u8,
+    config_level: Option<&str>,
+) -> LogFilter {
+    if let Some(rust_log) = rust_log.filter(|value| !value.trim().is_empty()) {
+        return LogFilter {
+            filter: rust_log.to_string(),
+            fuse3_level: None,
+        };
+    }
 
-    let log_level = match args.verbose {
-        0 => "warn",
-        1 => "info",
-        2 => "debug",
-        _ => "trace",
+    let level = match verbose {
+        0 => config_level
+            .and_then(|level| level.parse().ok())
+            .unwrap_or(log::LevelFilter::Warn),
+        1 => log::LevelFilter::Info,
+        2 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
     };
 
-    let fuse3_level = if args.verbose >= 3 {
+    // fuse3 logs every request, only show it at trace level
+    let fuse3_level = if level == log::LevelFilter::Trace {
         log::LevelFilter::Debug
     } else {
         log::LevelFilter::Off
     };
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(log_level))
-        .filter_module("fuse3", fuse3_level)
-        .init();
 
-    match args.command {
-        Some(Commands::Setup) => return setup(),
-        None => {}
+    LogFilter {
+        filter: level.to_string().to_lowercase(),
+        fuse3_level: Some(fuse3_level),
+    }
+}
+
+fn init_logging(verbose: u8, config_level: Option<&str>) {
+    let rust_log = std::env::var("RUST_LOG").ok();
+    let log_filter = resolve_log_filter(rust_log.as_deref(), verbose, config_level);
+
+    let mut builder = env_logger::Builder::new();
+    builder.parse_filters(&log_filter.filter);
+    if let Some(fuse3_level) = log_filter.fuse3_level {
+        builder.filter_module("fuse3", fuse3_level);
+    }
+    builder.init();
+}
+
+/// Export `conversion.temp_dir` as TMPDIR for the conversion workers and libheif
+///
+/// Must run while the process is still single-threaded: setenv races any concurrent
+/// getenv, and the conversion workers, cache threads and the tokio runtime all read
+/// the environment.
+fn export_temp_dir(config: &Config) -> Result<()> {
+    if let Some(temp_dir) = &config.conversion.temp_dir {
+        image_converter::prepare_temp_dir(temp_dir)?;
+        info!("Using conversion temp dir: {temp_dir:?}");
+        std::env::set_var("TMPDIR", temp_dir);
+    }
+    Ok(())
+}
+
+// Not #[tokio::main]: the runtime is only built once the environment is final, see
+// export_temp_dir
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    // Commands that run before (or instead of) loading the config log without it
+    if let Some(Commands::Setup) = args.command {
+        init_logging(args.verbose, None);
+        return setup();
     }
 
     let config_path = match args.config {
@@ -108,22 +298,70 @@ async fn main() -> Result<()> {
         None => Config::get_default_config_path()?,
     };
 
-    info!("Loading configuration from: {config_path:?}");
-    let config = Config::load(&config_path)?;
+    if let Some(Commands::Validate) = args.command {
+        init_logging(args.verbose, None);
+        return validate(&config_path);
+    }
+
+    // The log level may come from the config, so its warnings are logged after loading
+    let (mut config, warnings) = Config::load_with_warnings(&config_path)?;
+    init_logging(args.verbose, Some(&config.logging.level));
+    image_converter::set_conversion_summary_interval(Duration::from_millis(
+        config.logging.conversion_summary_interval_ms,
+    ));
+    for warning in warnings {
+        warn!("{warning}");
+    }
+    info!("Loaded configuration from: {config_path:?}");
+
+    if !args.sources.is_empty() {
+        for source in args.sources {
+            info!(
+                "Source override: {} -> {:?}",
+                source.mount_name, source.path
+            );
+            config.apply_source_override(source);
+        }
+        config.validate().context("Invalid --source override")?;
+    }
 
     let mount_point = args.mount.unwrap_or(config.mount_point.clone());
 
-    mount_management::ensure_mount_point_accessible(&mount_point)?;
+    match args.command {
+        Some(Commands::Stats) => return stats(&config),
+        Some(Commands::CacheInfo { key_or_path }) => return cache_info(&config, &key_or_path),
+        Some(Commands::Doctor) => return run_doctor(&config, &mount_point),
+        Some(Commands::Warm { manifest }) => {
+            return warm(&config, &mount_point, manifest.as_deref())
+        }
+        Some(Commands::Health) => {
+            mount_management::check_health(&mount_point)?;
+            println!("healthy: {}", mount_point.display());
+            return Ok(());
+        }
+        Some(Commands::Setup) | Some(Commands::Validate) | None => {}
+    }
+
+    mount_management::ensure_mount_point_accessible(&mount_point, config.fuse.allow_nonempty)?;
+    export_temp_dir(&config)?;
 
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(mount(&config, mount_point))
+}
+
+async fn mount(config: &Config, mount_point: PathBuf) -> Result<()> {
     info!("Initializing FUSE filesystem");
-    let fs = ImageFuseFS::new(&config, mount_point.clone())?;
+    let fs = ImageFuseFS::new(config, mount_point.clone())?;
 
     let mut mount_options = MountOptions::default();
     mount_options
         .fs_name("fuse-img2heic")
         .allow_other(true)
         .default_permissions(true)
-        .read_only(true);
+        .read_only(true)
+        .nonempty(config.fuse.allow_nonempty);
 
     info!("Mounting filesystem at: {mount_point:?}");
 
@@ -141,3 +379,34 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_filter_precedence() {
+        // RUST_LOG wins over everything and also controls fuse3
+        let filter = resolve_log_filter(Some("fuse3=debug,info"), 3, Some("error"));
+        assert_eq!(filter.filter, "fuse3=debug,info");
+        assert_eq!(filter.fuse3_level, None);
+
+        // Then -v, ignoring the config
+        let filter = resolve_log_filter(None, 2, Some("error"));
+        assert_eq!(filter.filter, "debug");
+        assert_eq!(filter.fuse3_level, Some(log::LevelFilter::Off));
+        let filter = resolve_log_filter(Some(""), 3, Some("error"));
+        assert_eq!(filter.filter, "trace");
+        assert_eq!(filter.fuse3_level, Some(log::LevelFilter::Debug));
+
+        // Then logging.level
+        assert_eq!(resolve_log_filter(None, 0, Some("info")).filter, "info");
+        assert_eq!(
+            resolve_log_filter(None, 0, Some("trace")).fuse3_level,
+            Some(log::LevelFilter::Debug)
+        );
+
+        // Then warn
+        assert_eq!(resolve_log_filter(None, 0, None).filter, "warn");
+    }
+}
@@ -1,42 +1,168 @@
 use anyhow::Result;
-use log::{debug, info};
+use log::{debug, info, warn};
 use std::path::Path;
 
+/// Observed state of a mount point directory
+#[derive(Debug)]
+pub enum MountPointState {
+    /// The directory can be listed; mounting over existing entries hides them
+    Accessible {
+        empty: bool,
+    },
+    /// A FUSE mount whose daemon is gone ("Transport endpoint is not connected")
+    Stuck,
+    Missing,
+    Inaccessible(std::io::Error),
+}
+
+impl MountPointState {
+    fn from_read_dir<I: Iterator>(result: std::io::Result<I>) -> Self {
+        match result {
+            Ok(mut entries) => Self::Accessible {
+                empty: entries.next().is_none(),
+            },
+            Err(e) if e.raw_os_error() == Some(libc::ENOTCONN) => Self::Stuck,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Self::Missing,
+            Err(e) => Self::Inaccessible(e),
+        }
+    }
+}
+
+/// How to resolve a non-empty mount point, shared by startup and `doctor`
+pub const NONEMPTY_HINT: &str = "use an empty directory or set fuse.allow_nonempty";
+
+/// Check whether a mount point can be read, without trying to fix anything
+pub fn probe_mount_point(mount_point: &Path) -> MountPointState {
+    // Reading the directory is what catches stuck mounts
+    MountPointState::from_read_dir(std::fs::read_dir(mount_point))
+}
+
 /// Check if a mount point is accessible and attempt to unmount if stuck
-pub fn ensure_mount_point_accessible(mount_point: &Path) -> Result<()> {
+///
+/// A non-empty directory is refused unless `allow_nonempty` is set, since mounting
+/// over it hides real data and is almost always a mistake.
+pub fn ensure_mount_point_accessible(mount_point: &Path, allow_nonempty: bool) -> Result<()> {
     debug!("Checking mount point accessibility: {mount_point:?}");
 
-    // First check if we can read the directory - this will catch stuck mounts
-    match std::fs::read_dir(mount_point) {
-        Ok(_) => {
+    match probe_mount_point(mount_point) {
+        MountPointState::Accessible { empty: true } => {
             debug!("Mount point is accessible");
             Ok(())
         }
-        Err(e) => {
-            debug!("Failed to read mount point: {e:?}");
+        MountPointState::Accessible { empty: false } => check_nonempty(mount_point, allow_nonempty),
+        MountPointState::Stuck => {
+            info!("Mount point appears to be stuck from previous mount, attempting to unmount");
+            attempt_unmount(mount_point)?;
 
-            if e.raw_os_error() == Some(107) {
-                // Transport endpoint is not connected
-                info!("Mount point appears to be stuck from previous mount, attempting to unmount");
-                attempt_unmount(mount_point)?;
+            // After unmounting, ensure directory exists
+            if !mount_point.exists() {
+                info!("Creating mount point after unmount: {mount_point:?}");
+                std::fs::create_dir_all(mount_point)?;
+            }
 
-                // After unmounting, ensure directory exists
-                if !mount_point.exists() {
-                    info!("Creating mount point after unmount: {mount_point:?}");
-                    std::fs::create_dir_all(mount_point)?;
+            // The directory below the stale mount may hold files of its own
+            match probe_mount_point(mount_point) {
+                MountPointState::Accessible { empty: false } => {
+                    check_nonempty(mount_point, allow_nonempty)
                 }
-                return Ok(());
+                _ => Ok(()),
             }
+        }
+        MountPointState::Missing => {
+            info!("Creating mount point: {mount_point:?}");
+            std::fs::create_dir_all(mount_point)?;
+            Ok(())
+        }
+        MountPointState::Inaccessible(e) => {
+            debug!("Failed to read mount point: {e:?}");
+            Err(anyhow::anyhow!("Cannot access mount point: {e}"))
+        }
+    }
+}
 
-            if e.kind() == std::io::ErrorKind::NotFound {
-                info!("Creating mount point: {mount_point:?}");
-                std::fs::create_dir_all(mount_point)?;
-                return Ok(());
-            }
+fn check_nonempty(mount_point: &Path, allow_nonempty: bool) -> Result<()> {
+    if !allow_nonempty {
+        anyhow::bail!(
+            "Mount point {} is not empty, mounting would hide its contents; {NONEMPTY_HINT}",
+            mount_point.display()
+        );
+    }
+    warn!(
+        "Mount point {} is not empty, its contents are hidden while mounted",
+        mount_point.display()
+    );
+    Ok(())
+}
 
-            Err(anyhow::anyhow!("Cannot access mount point: {e}"))
+/// Check that a mount point is a live FUSE mount, for liveness probes
+///
+/// Returns a one-line reason when unhealthy: the mount point is stuck, missing,
+/// unreadable, or not a FUSE mount at all (e.g. the daemon never mounted it).
+pub fn check_health(mount_point: &Path) -> Result<()> {
+    match probe_mount_point(mount_point) {
+        MountPointState::Accessible { .. } => {}
+        MountPointState::Stuck => anyhow::bail!(
+            "{} is a stuck FUSE mount (transport endpoint is not connected)",
+            mount_point.display()
+        ),
+        MountPointState::Missing => anyhow::bail!("{} does not exist", mount_point.display()),
+        MountPointState::Inaccessible(e) => {
+            anyhow::bail!("{} is not readable: {e}", mount_point.display())
         }
     }
+
+    // mountinfo lists absolute targets with symlinks resolved, e.g. for "-m ./mnt"
+    let target = std::fs::canonicalize(mount_point)
+        .map_err(|e| anyhow::anyhow!("Cannot resolve {}: {e}", mount_point.display()))?;
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo")
+        .map_err(|e| anyhow::anyhow!("Cannot read /proc/self/mountinfo: {e}"))?;
+    match mount_fstype(&mountinfo, &target) {
+        Some(fstype) if fstype == "fuse" || fstype.starts_with("fuse.") => Ok(()),
+        Some(fstype) => anyhow::bail!(
+            "{} is a {fstype} mount, not a FUSE mount",
+            mount_point.display()
+        ),
+        None => anyhow::bail!("{} is not mounted", mount_point.display()),
+    }
+}
+
+/// Filesystem type of the mount at exactly `mount_point` in a mountinfo listing.
+/// The last entry wins, since later mounts stack on top of earlier ones.
+fn mount_fstype(mountinfo: &str, mount_point: &Path) -> Option<String> {
+    let mount_point = mount_point.to_str()?.trim_end_matches('/');
+    let mount_point = if mount_point.is_empty() {
+        "/"
+    } else {
+        mount_point
+    };
+
+    mountinfo.lines().rev().find_map(|line| {
+        // "<id> <parent> <dev> <root> <mount point> <options> [optional...] - <fstype> ..."
+        let (fields, fs_fields) = line.split_once(" - ")?;
+        let target = fields.split(' ').nth(4)?;
+        let fstype = fs_fields.split(' ').next()?;
+        (unescape_mountinfo(target) == mount_point).then(|| fstype.to_string())
+    })
+}
+
+/// Decode the octal escapes ("\040" for a space) used for paths in mountinfo
+fn unescape_mountinfo(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 3 < bytes.len() {
+            let escape = std::str::from_utf8(&bytes[i + 1..i + 4]).ok();
+            if let Some(byte) = escape.and_then(|octal| u8::from_str_radix(octal, 8).ok()) {
+                decoded.push(byte);
+                i += 4;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
 }
 
 /// Attempt to unmount a stuck filesystem
@@ -59,3 +185,105 @@ fn attempt_unmount(mount_point: &Path) -> Result<()> {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_probe_distinguishes_stuck_mounts() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        assert!(matches!(
+            probe_mount_point(temp_dir.path()),
+            MountPointState::Accessible { empty: true }
+        ));
+        assert!(matches!(
+            probe_mount_point(&temp_dir.path().join("missing")),
+            MountPointState::Missing
+        ));
+
+        // What read_dir returns on a FUSE mount whose daemon died
+        let stuck = MountPointState::from_read_dir::<std::iter::Empty<()>>(Err(
+            std::io::Error::from_raw_os_error(libc::ENOTCONN),
+        ));
+        assert!(matches!(stuck, MountPointState::Stuck));
+        Ok(())
+    }
+
+    #[test]
+    fn test_nonempty_mount_point_is_refused() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+
+        // Empty directory: fine
+        ensure_mount_point_accessible(temp_dir.path(), false)?;
+
+        // Real data: refused unless explicitly allowed
+        std::fs::write(temp_dir.path().join("notes.txt"), b"data")?;
+        assert!(matches!(
+            probe_mount_point(temp_dir.path()),
+            MountPointState::Accessible { empty: false }
+        ));
+        let err = ensure_mount_point_accessible(temp_dir.path(), false)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("allow_nonempty"), "{err}");
+        ensure_mount_point_accessible(temp_dir.path(), true)?;
+
+        // A stale FUSE mount is never mistaken for a non-empty directory
+        let stuck = MountPointState::from_read_dir::<std::iter::Empty<()>>(Err(
+            std::io::Error::from_raw_os_error(libc::ENOTCONN),
+        ));
+        assert!(matches!(stuck, MountPointState::Stuck));
+        Ok(())
+    }
+
+    #[test]
+    fn test_health_rejects_plain_directory() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let err = check_health(temp_dir.path()).unwrap_err().to_string();
+        assert!(
+            err.contains("not mounted") || err.contains("not a FUSE mount"),
+            "{err}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_health_resolves_relative_and_symlinked_paths() -> Result<()> {
+        // /proc is mounted everywhere these tests run, and is not a FUSE mount
+        let depth = std::env::current_dir()?.components().count();
+        let relative = std::path::PathBuf::from("../".repeat(depth)).join("proc");
+        assert!(relative.is_relative());
+        let temp_dir = TempDir::new()?;
+        let symlink = temp_dir.path().join("proc");
+        std::os::unix::fs::symlink("/proc", &symlink)?;
+
+        for mount_point in [relative, symlink] {
+            let err = check_health(&mount_point).unwrap_err().to_string();
+            assert!(err.contains("is a proc mount"), "{mount_point:?}: {err}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_mount_fstype_from_mountinfo() {
+        let mountinfo = "\
+22 1 0:21 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+40 22 0:35 / /tmp/fuse-img2heic rw,nosuid,nodev shared:20 - fuse.fuse-img2heic fuse-img2heic rw,user_id=1000
+41 22 0:36 / /media/my\\040photos rw shared:21 - fuse fuse rw
+";
+        assert_eq!(
+            mount_fstype(mountinfo, Path::new("/tmp/fuse-img2heic/")).as_deref(),
+            Some("fuse.fuse-img2heic")
+        );
+        assert_eq!(
+            mount_fstype(mountinfo, Path::new("/media/my photos")).as_deref(),
+            Some("fuse")
+        );
+        assert_eq!(
+            mount_fstype(mountinfo, Path::new("/")).as_deref(),
+            Some("ext4")
+        );
+        assert_eq!(mount_fstype(mountinfo, Path::new("/home")), None);
+    }
+}
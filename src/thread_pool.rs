@@ -1,13 +1,15 @@
 use anyhow::Result;
-use crossbeam::channel::{self, Sender};
+use crossbeam::channel::{self, Receiver, Sender};
 use dashmap::DashSet;
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
 use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::cache::{create_cache_key_and_context_for_path, ImageCache};
+use crate::cache::{create_cache_key_and_context_for_source, ImageCache};
 use crate::config::HeicSettings;
 
 pub struct ConversionJob {
@@ -18,16 +20,24 @@ pub struct ConversionJob {
 
 pub struct ConversionThreadPool {
     sender: Option<Sender<ConversionJob>>,
+    /// Separate queue for prefetch jobs, only served when no read is waiting
+    prefetch_sender: Option<Sender<ConversionJob>>,
     workers: Vec<thread::JoinHandle<()>>,
     cache: Arc<ImageCache>,
     in_flight: Arc<DashSet<PathBuf>>,
+    /// Set once the drain timeout expires; workers then discard queued jobs
+    stop: Arc<AtomicBool>,
+    drain_timeout: Duration,
 }
 
 impl ConversionThreadPool {
-    pub fn new(num_workers: usize, cache: Arc<ImageCache>) -> Self {
+    pub fn new(num_workers: usize, cache: Arc<ImageCache>, drain_timeout: Duration) -> Self {
         let (sender, receiver) = channel::unbounded::<ConversionJob>();
         let receiver = Arc::new(receiver);
+        let (prefetch_sender, prefetch_receiver) = channel::unbounded::<ConversionJob>();
+        let prefetch_receiver = Arc::new(prefetch_receiver);
         let in_flight: Arc<DashSet<PathBuf>> = Arc::new(DashSet::new());
+        let stop = Arc::new(AtomicBool::new(false));
 
         info!("Starting {num_workers} conversion worker threads");
 
@@ -35,15 +45,31 @@ impl ConversionThreadPool {
 
         for id in 0..num_workers {
             let receiver = Arc::clone(&receiver);
+            let prefetch_receiver = Arc::clone(&prefetch_receiver);
             let cache = Arc::clone(&cache);
             let in_flight = Arc::clone(&in_flight);
+            let stop = Arc::clone(&stop);
 
             let handle = thread::spawn(move || {
                 trace!("Worker {id} started");
 
-                while let Ok(job) = receiver.recv() {
+                while let Some(job) = next_job(&receiver, &prefetch_receiver) {
+                    if stop.load(Ordering::Relaxed) {
+                        // Dropping the job drops its result sender, which wakes up
+                        // any caller blocked in convert_image_blocking
+                        trace!("Worker {} cancelling job for: {:?}", id, job.input_path);
+                        in_flight.remove(&job.input_path);
+                        continue;
+                    }
+
                     debug!("Worker {} processing job for: {:?}", id, job.input_path);
 
+                    // Stat before converting, so a source replaced during the conversion
+                    // does not get the old result cached under its new identity
+                    let (cache_key, context) = create_cache_key_and_context_for_source(
+                        &job.input_path,
+                        &job.heic_settings,
+                    );
                     let result = crate::image_converter::convert_to_heic_blocking(
                         &job.input_path,
                         &job.heic_settings,
@@ -62,15 +88,9 @@ impl ConversionThreadPool {
                             );
 
                             // Always cache the result
-                            let original_size = std::fs::metadata(&job.input_path)
-                                .map(|m| m.len())
-                                .unwrap_or(0);
-                            let (cache_key, context) = create_cache_key_and_context_for_path(
-                                &job.input_path,
-                                original_size,
-                                &job.heic_settings,
-                            );
-                            if let Err(e) = cache.put_with_context(cache_key, data.clone(), &context) {
+                            if let Err(e) =
+                                cache.put_with_context(cache_key, data.clone(), &context)
+                            {
                                 debug!("Worker {id} failed to cache result: {e}");
                             }
 
@@ -99,9 +119,12 @@ impl ConversionThreadPool {
 
         Self {
             sender: Some(sender),
+            prefetch_sender: Some(prefetch_sender),
             workers,
             cache,
             in_flight,
+            stop,
+            drain_timeout,
         }
     }
 
@@ -141,12 +164,8 @@ impl ConversionThreadPool {
         }
 
         // Check if already cached
-        let original_size = std::fs::metadata(&input_path).map(|m| m.len()).unwrap_or(0);
-        let (cache_key, context) = create_cache_key_and_context_for_path(
-            &input_path,
-            original_size,
-            &heic_settings,
-        );
+        let (cache_key, context) =
+            create_cache_key_and_context_for_source(&input_path, &heic_settings);
         if self.cache.get_with_context(&cache_key, &context).is_some() {
             return; // Already cached
         }
@@ -160,7 +179,10 @@ impl ConversionThreadPool {
             result_sender: None, // No one waiting, just cache it
         };
 
-        let _ = self.submit_job(job); // Ignore errors for prefetch
+        // Ignore errors for prefetch
+        if let Some(prefetch_sender) = &self.prefetch_sender {
+            let _ = prefetch_sender.send(job);
+        }
     }
 }
 
@@ -168,16 +190,142 @@ impl Drop for ConversionThreadPool {
     fn drop(&mut self) {
         info!("Shutting down conversion thread pool");
 
-        // Close the sender to signal workers to stop
+        // Close the channels; workers keep going until the queues are empty
         drop(self.sender.take());
+        drop(self.prefetch_sender.take());
+
+        if !wait_for_workers(&self.workers, self.drain_timeout) {
+            warn!(
+                "Conversions still running after {:?}, cancelling queued jobs",
+                self.drain_timeout
+            );
+            self.stop.store(true, Ordering::Relaxed);
+
+            // A conversion that is already running cannot be interrupted, give it
+            // one more timeout to finish before leaving the worker behind
+            if !wait_for_workers(&self.workers, self.drain_timeout) {
+                let stuck = self.workers.iter().filter(|w| !w.is_finished()).count();
+                warn!("Detaching {stuck} conversion workers that did not stop in time");
+                self.workers.retain(|w| w.is_finished());
+            }
+        }
 
-        // Wait for all workers to finish
         while let Some(worker) = self.workers.pop() {
             if let Err(e) = worker.join() {
                 error!("Worker thread panicked: {e:?}");
             }
         }
 
+        crate::image_converter::flush_conversion_summary();
         info!("All conversion workers shut down");
     }
 }
+
+/// Next job for a worker, preferring reads someone is waiting on over prefetches.
+/// Returns None once both queues are closed and empty.
+fn next_job(
+    foreground: &Receiver<ConversionJob>,
+    prefetch: &Receiver<ConversionJob>,
+) -> Option<ConversionJob> {
+    if let Ok(job) = foreground.try_recv() {
+        return Some(job);
+    }
+    channel::select! {
+        recv(foreground) -> job => job.ok().or_else(|| prefetch.recv().ok()),
+        recv(prefetch) -> job => job.ok().or_else(|| foreground.recv().ok()),
+    }
+}
+
+/// Wait until every worker has exited or the timeout expires, returns true if all exited
+fn wait_for_workers(workers: &[thread::JoinHandle<()>], timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if workers.iter().all(|w| w.is_finished()) {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CacheSettings;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_reads_are_served_before_queued_prefetches() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let write_image = |name: &str| -> Result<PathBuf> {
+            let path = temp_dir.path().join(name);
+            image::RgbImage::from_fn(384, 384, |x, y| image::Rgb([x as u8, y as u8, 90]))
+                .save(&path)?;
+            Ok(path)
+        };
+
+        let cache = ImageCache::new(temp_dir.path().join("cache"), &CacheSettings::default())?;
+        let pool = ConversionThreadPool::new(1, cache, Duration::from_millis(50));
+
+        for i in 0..8 {
+            pool.prefetch(
+                write_image(&format!("next_{i}.png"))?,
+                HeicSettings::default(),
+            );
+        }
+        let current = write_image("current.png")?;
+        pool.convert_image_blocking(current, HeicSettings::default())?;
+
+        // At most the prefetch that was already running finished before the read
+        assert!(
+            pool.in_flight.len() >= 6,
+            "{} prefetches left",
+            pool.in_flight.len()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_drop_with_queued_jobs_is_bounded() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let image_path = temp_dir.path().join("photo.png");
+        image::RgbImage::from_pixel(512, 512, image::Rgb([10, 120, 200])).save(&image_path)?;
+
+        let cache = ImageCache::new(temp_dir.path().join("cache"), &CacheSettings::default())?;
+        let pool = ConversionThreadPool::new(1, cache, Duration::from_millis(50));
+
+        let receivers: Vec<_> = (0..50)
+            .map(|_| {
+                let (result_sender, result_receiver) = mpsc::channel();
+                pool.submit_job(ConversionJob {
+                    input_path: image_path.clone(),
+                    heic_settings: HeicSettings::default(),
+                    result_sender: Some(result_sender),
+                })
+                .map(|()| result_receiver)
+            })
+            .collect::<Result<_>>()?;
+
+        let start = Instant::now();
+        drop(pool);
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "shutdown took {:?}",
+            start.elapsed()
+        );
+
+        // Every caller gets an answer, cancelled jobs report a closed channel
+        let mut cancelled = 0;
+        for receiver in receivers {
+            match receiver.recv_timeout(Duration::from_secs(1)) {
+                Ok(result) => assert!(result.is_ok()),
+                Err(mpsc::RecvTimeoutError::Disconnected) => cancelled += 1,
+                Err(mpsc::RecvTimeoutError::Timeout) => panic!("caller left waiting"),
+            }
+        }
+        assert!(cancelled > 0);
+        Ok(())
+    }
+}
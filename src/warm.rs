@@ -0,0 +1,447 @@
+use anyhow::{Context, Result};
+use libheif_rs::CompressionFormat;
+use log::{debug, info, warn};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+use crate::cache::{create_cache_key_and_context_for_source, ImageCache};
+use crate::config::{Config, HeicSettings, SourcePath};
+use crate::file_detector::FileDetector;
+use crate::image_converter;
+use crate::thread_pool::{ConversionJob, ConversionThreadPool};
+
+const MANIFEST_HEADER: [&str; 6] = [
+    "source",
+    "source_sha256",
+    "output_size",
+    "settings",
+    "cache_key",
+    "status",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WarmStatus {
+    Converted,
+    /// Unchanged since the manifest of an earlier run recorded it
+    Skipped,
+    Failed,
+}
+
+impl WarmStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Converted => "converted",
+            Self::Skipped => "skipped",
+            Self::Failed => "failed",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "converted" => Some(Self::Converted),
+            "skipped" => Some(Self::Skipped),
+            "failed" => Some(Self::Failed),
+            _ => None,
+        }
+    }
+}
+
+/// One manifest row: a source file converted with one settings block
+#[derive(Debug, Clone, PartialEq)]
+pub struct ManifestEntry {
+    pub source: PathBuf,
+    /// SHA-256 of the source file content
+    pub source_hash: String,
+    pub output_size: u64,
+    /// Readable summary of the settings; the cache key covers all of them
+    pub settings: String,
+    pub cache_key: String,
+    pub status: WarmStatus,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct WarmSummary {
+    pub converted: usize,
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+/// Convert every image the mount would serve converted into the disk cache
+///
+/// With a manifest, files whose source hash and cache key (and so settings) match a
+/// non-failed row of the previous manifest are skipped, and the manifest is rewritten
+/// with one row per processed file.
+pub fn warm_cache(
+    config: &Config,
+    mount_point: &Path,
+    manifest_path: Option<&Path>,
+) -> Result<WarmSummary> {
+    let mut config = config.clone();
+    image_converter::apply_encoder_fallback(
+        &mut config,
+        image_converter::check_encoder_available(CompressionFormat::Hevc),
+    )?;
+
+    let previous = match manifest_path {
+        Some(path) if path.exists() => read_manifest(path)?,
+        _ => Vec::new(),
+    };
+    let previous: HashMap<(PathBuf, String), ManifestEntry> = previous
+        .into_iter()
+        .filter(|entry| entry.status != WarmStatus::Failed)
+        .map(|entry| ((entry.source.clone(), entry.cache_key.clone()), entry))
+        .collect();
+
+    let cache = ImageCache::new(config.get_cache_dir_from_config()?, &config.cache)?;
+    let workers = num_cpus::get();
+    let pool = ConversionThreadPool::new(
+        workers,
+        Arc::clone(&cache),
+        Duration::from_secs(config.conversion.drain_timeout_secs),
+    );
+    let settings_blocks = served_settings(&config);
+
+    // Rows in discovery order; about one job per worker is in flight at a time, so
+    // converted payloads are dropped as soon as their size is recorded
+    let mut entries = Vec::new();
+    let mut in_flight = VecDeque::with_capacity(workers);
+    for source_path in &config.source_paths {
+        for path in converted_sources(&config, source_path, mount_point)? {
+            let source_hash = match fs::read(&path) {
+                Ok(content) => hex::encode(Sha256::digest(&content)),
+                Err(e) => {
+                    warn!("Failed to read {path:?}: {e}");
+                    continue;
+                }
+            };
+
+            for heic_settings in &settings_blocks {
+                let heic_settings = &image_converter::settings_for_source(&path, heic_settings);
+                let (cache_key, context) =
+                    create_cache_key_and_context_for_source(&path, heic_settings);
+                let mut entry = ManifestEntry {
+                    source: path.clone(),
+                    source_hash: source_hash.clone(),
+                    output_size: 0,
+                    settings: settings_summary(heic_settings),
+                    cache_key,
+                    status: WarmStatus::Skipped,
+                };
+
+                // The manifest only says what was converted, the entry may have been
+                // evicted or the cache cleared since
+                if let Some(done) = previous.get(&(entry.source.clone(), entry.cache_key.clone())) {
+                    if done.source_hash == entry.source_hash
+                        && cache
+                            .get_checksum_with_context(&entry.cache_key, &context)
+                            .is_some()
+                    {
+                        entry.output_size = done.output_size;
+                        entries.push(entry);
+                        continue;
+                    }
+                }
+
+                if in_flight.len() >= workers {
+                    let (index, result_receiver) = in_flight.pop_front().unwrap();
+                    record_result(&mut entries[index], result_receiver);
+                }
+                let (result_sender, result_receiver) = mpsc::channel();
+                pool.submit_job(ConversionJob {
+                    input_path: path.clone(),
+                    heic_settings: heic_settings.clone(),
+                    result_sender: Some(result_sender),
+                })?;
+                in_flight.push_back((entries.len(), result_receiver));
+                entries.push(entry);
+            }
+        }
+    }
+    for (index, result_receiver) in in_flight {
+        record_result(&mut entries[index], result_receiver);
+    }
+
+    let mut summary = WarmSummary::default();
+    for entry in &entries {
+        match entry.status {
+            WarmStatus::Converted => summary.converted += 1,
+            WarmStatus::Skipped => summary.skipped += 1,
+            WarmStatus::Failed => summary.failed += 1,
+        }
+    }
+    info!(
+        "Warmed cache: {} converted, {} skipped, {} failed",
+        summary.converted, summary.skipped, summary.failed
+    );
+
+    if let Some(path) = manifest_path {
+        write_manifest(path, &entries)?;
+    }
+    Ok(summary)
+}
+
+/// Wait for the conversion of `entry` and record its outcome, keeping only the size
+fn record_result(entry: &mut ManifestEntry, result_receiver: mpsc::Receiver<Result<Vec<u8>>>) {
+    match result_receiver
+        .recv()
+        .map_err(|_| anyhow::anyhow!("Conversion job was cancelled"))
+        .and_then(|result| result)
+    {
+        Ok(data) => {
+            entry.output_size = data.len() as u64;
+            entry.status = WarmStatus::Converted;
+        }
+        Err(e) => {
+            warn!("Conversion failed for {:?}: {e:#}", entry.source);
+            entry.status = WarmStatus::Failed;
+        }
+    }
+}
+
+/// Queue a background conversion of every source image matching
+/// `cache.pinned_patterns`, so pinned entries are cached (and spared by eviction)
+/// from startup on. Returns the number of files queued.
+pub fn prefetch_pinned(
+    config: &Config,
+    mount_point: &Path,
+    cache: &ImageCache,
+    pool: &ConversionThreadPool,
+) -> Result<usize> {
+    let settings_blocks = served_settings(config);
+    let mut queued = 0;
+    for source_path in &config.source_paths {
+        for path in converted_sources(config, source_path, mount_point)? {
+            if !cache.is_pinned_path(&path.to_string_lossy()) {
+                continue;
+            }
+            for heic_settings in &settings_blocks {
+                let heic_settings = image_converter::settings_for_source(&path, heic_settings);
+                // Already cached files are only recorded as pinned
+                pool.prefetch(path.clone(), heic_settings);
+            }
+            queued += 1;
+        }
+    }
+    info!("Pinned {queued} files in the cache");
+    Ok(queued)
+}
+
+/// Settings blocks the mount serves every converted file with; variants replace the
+/// plain name in listings, so only their settings are served
+fn served_settings(config: &Config) -> Vec<HeicSettings> {
+    if config.variants.is_empty() {
+        vec![config.heic_settings.clone()]
+    } else {
+        config
+            .variants
+            .iter()
+            .map(|variant| variant.heic_settings.clone())
+            .collect()
+    }
+}
+
+/// Images of a source the mount serves converted, in discovery order
+fn converted_sources(
+    config: &Config,
+    source_path: &SourcePath,
+    mount_point: &Path,
+) -> Result<Vec<PathBuf>> {
+    let detector = FileDetector::new(config.filename_patterns.clone())?
+        .with_min_convert_bytes(config.heic_settings.min_convert_bytes)
+        .with_allowed_formats(&config.heic_settings.allowed_input_formats);
+
+    Ok(detector
+        .discover_images(source_path, &[mount_point])
+        .into_iter()
+        .filter(|path| {
+            let size = fs::metadata(path).map_or(0, |m| m.len());
+            let converted = size > 0
+                && !detector.is_below_convert_threshold(size)
+                && image_converter::is_convertible_format(path);
+            if !converted {
+                debug!("Not converted by the mount, skipping: {path:?}");
+            }
+            converted
+        })
+        .collect())
+}
+
+fn settings_summary(settings: &HeicSettings) -> String {
+    let mut summary = format!(
+        "quality={} speed={} chroma={}",
+        settings.quality, settings.speed, settings.chroma
+    );
+    if let Some(max_resolution) = &settings.max_resolution {
+        let _ = write!(summary, " max_resolution={max_resolution}");
+    }
+    if settings.is_lossless() {
+        summary.push_str(" lossless");
+    }
+    if let Some(fallback) = settings.active_fallback {
+        let _ = write!(summary, " format={}", fallback.extension());
+    }
+    summary
+}
+
+/// Write the manifest as CSV with a header row, through a temporary file
+fn write_manifest(path: &Path, entries: &[ManifestEntry]) -> Result<()> {
+    let mut content = MANIFEST_HEADER.join(",");
+    content.push('\n');
+    for entry in entries {
+        let fields = [
+            entry.source.to_string_lossy().to_string(),
+            entry.source_hash.clone(),
+            entry.output_size.to_string(),
+            entry.settings.clone(),
+            entry.cache_key.clone(),
+            entry.status.as_str().to_string(),
+        ];
+        let fields: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+        content.push_str(&fields.join(","));
+        content.push('\n');
+    }
+
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, content)
+        .with_context(|| format!("Failed to write manifest {tmp_path:?}"))?;
+    fs::rename(&tmp_path, path).with_context(|| format!("Failed to write manifest {path:?}"))?;
+    Ok(())
+}
+
+fn read_manifest(path: &Path) -> Result<Vec<ManifestEntry>> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("Failed to read manifest {path:?}"))?;
+    let mut rows = parse_csv(&content).into_iter();
+    if rows.next().is_none_or(|header| header != MANIFEST_HEADER) {
+        anyhow::bail!("{path:?} is not a conversion manifest (unexpected header)");
+    }
+
+    rows.enumerate()
+        .map(|(index, row)| {
+            let parsed = match row.as_slice() {
+                [source, source_hash, output_size, settings, cache_key, status] => {
+                    output_size.parse().ok().zip(WarmStatus::parse(status)).map(
+                        |(output_size, status)| ManifestEntry {
+                            source: PathBuf::from(source),
+                            source_hash: source_hash.clone(),
+                            output_size,
+                            settings: settings.clone(),
+                            cache_key: cache_key.clone(),
+                            status,
+                        },
+                    )
+                }
+                _ => None,
+            };
+            parsed.ok_or_else(|| anyhow::anyhow!("Invalid row {} in manifest {path:?}", index + 2))
+        })
+        .collect()
+}
+
+/// Quote a CSV field if it contains a separator, quote or line break
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Split CSV content into rows of fields, honoring quoted fields
+fn parse_csv(content: &str) -> Vec<Vec<String>> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = content.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => row.push(std::mem::take(&mut field)),
+            '\n' if !in_quotes => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            }
+            '\r' if !in_quotes => {}
+            c => field.push(c),
+        }
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CacheSettings;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_second_run_skips_unchanged_files() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let source_dir = temp_dir.path().join("source");
+        fs::create_dir_all(source_dir.join("album, 2024"))?;
+        let write_image = |name: &str, blue: u8| -> Result<()> {
+            image::RgbImage::from_fn(64, 64, |x, y| image::Rgb([x as u8, y as u8, blue]))
+                .save(source_dir.join(name))?;
+            Ok(())
+        };
+        write_image("first.png", 10)?;
+        write_image("album, 2024/second.png", 20)?;
+
+        let config = Config {
+            source_paths: vec![SourcePath {
+                path: source_dir.clone(),
+                recursive: true,
+                mount_name: "pictures".to_string(),
+                exclude_dirs: Vec::new(),
+                flatten: false,
+            }],
+            cache: CacheSettings {
+                cache_dir: Some(temp_dir.path().join("cache")),
+                ..CacheSettings::default()
+            },
+            ..Config::default()
+        };
+        let mount_point = temp_dir.path().join("mnt");
+        let manifest = temp_dir.path().join("manifest.csv");
+
+        let first = warm_cache(&config, &mount_point, Some(&manifest))?;
+        assert_eq!((first.converted, first.skipped, first.failed), (2, 0, 0));
+        let entries = read_manifest(&manifest)?;
+        assert_eq!(entries.len(), 2);
+        assert!(entries.iter().all(|entry| entry.output_size > 0));
+
+        let second = warm_cache(&config, &mount_point, Some(&manifest))?;
+        assert_eq!((second.converted, second.skipped, second.failed), (0, 2, 0));
+        // Skipped rows keep what the conversion recorded, so a third run skips too
+        let mut rerun = read_manifest(&manifest)?;
+        for entry in &mut rerun {
+            assert_eq!(entry.status, WarmStatus::Skipped);
+            entry.status = WarmStatus::Converted;
+        }
+        assert_eq!(rerun, entries);
+
+        write_image("first.png", 200)?;
+        let third = warm_cache(&config, &mount_point, Some(&manifest))?;
+        assert_eq!((third.converted, third.skipped, third.failed), (1, 1, 0));
+
+        fs::remove_dir_all(temp_dir.path().join("cache"))?;
+        let fourth = warm_cache(&config, &mount_point, Some(&manifest))?;
+        assert_eq!((fourth.converted, fourth.skipped, fourth.failed), (2, 0, 0));
+        Ok(())
+    }
+}